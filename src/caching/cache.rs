@@ -1,69 +1,299 @@
 //! Local on-disk cache.
 
-use crate::config::MapProvider;
+use crate::config::{MapProvider, TileFormat};
+use crate::map::mbtiles::Mbtiles;
 use crate::map::tiles::TileIndex;
-use crate::tracks::schema::DetailedActivity;
-use anyhow::Context;
+use crate::tracks::schema::{DetailedActivity, StreamSet, SummaryActivity, Token};
+use crate::ui::util::decode_image;
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use fs4::{FileExt, TryLockError};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::task::spawn_blocking;
 
+/// Number of tiles to write per transaction when importing a directory cache.
+const IMPORT_BATCH_SIZE: usize = 1000;
+/// Age after which a temporary file is considered left over by an interrupted
+/// write, rather than being written by another instance.
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(3600);
+/// Minimum interval between two updates of the access time of a tile in an
+/// MBTiles cache.
+const MBTILES_TOUCH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Storage backend for the cached map tiles.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileCacheBackend {
+    /// One `{z}-{x}-{y}.{extension}` file per tile, in a folder per map
+    /// provider.
+    Directory,
+    /// One MBTiles (SQLite) database per map provider.
+    Mbtiles,
+}
+
+/// Access to the cache directory, enforced with an advisory lock between
+/// ridemap instances.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheAccess {
+    /// Shared with other instances, to read and write tiles and activities.
+    Shared,
+    /// Exclusive, to delete or migrate tiles in bulk.
+    Exclusive,
+}
+
+/// Permissions of a file written in the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileMode {
+    /// Default permissions, subject to the umask.
+    Default,
+    /// Only readable and writable by the owner, for secrets.
+    Private,
+}
+
+/// Parameters of the tile cache.
+#[derive(Clone, Copy, Debug)]
+pub struct TileCacheParams {
+    /// Storage backend.
+    pub backend: TileCacheBackend,
+    /// Age after which cached tiles are stale, and should be revalidated with
+    /// their provider.
+    pub max_age: Duration,
+    /// Maximum total size of the cached tiles in bytes, beyond which the least
+    /// recently used tiles are pruned.
+    pub max_size: Option<u64>,
+}
+
+/// Freshness information stored alongside each cached tile.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TileMetadata {
+    /// Time at which the tile was fetched or last revalidated, in seconds
+    /// since the Unix epoch.
+    pub fetched_at: u64,
+    /// `ETag` header of the response that served the tile.
+    pub etag: Option<String>,
+    /// `Last-Modified` header of the response that served the tile.
+    pub last_modified: Option<String>,
+}
+
+impl TileMetadata {
+    /// Creates metadata for a tile fetched now.
+    pub fn fetched_now(etag: Option<String>, last_modified: Option<String>) -> Self {
+        Self {
+            fetched_at: unix_time(),
+            etag,
+            last_modified,
+        }
+    }
+
+    /// Returns the time elapsed since the tile was fetched or last
+    /// revalidated.
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(unix_time().saturating_sub(self.fetched_at))
+    }
+}
+
+/// Usage information of a cached map tile.
+#[derive(Clone, Copy, Debug)]
+pub struct TileUsage {
+    /// Position of the tile on the world map.
+    pub index: TileIndex,
+    /// Last access time of the tile, in seconds since the Unix epoch (zero if
+    /// unknown).
+    pub accessed_at: u64,
+    /// Size of the tile data, in bytes.
+    pub size: u64,
+}
+
+/// Statistics about the cached tiles of a map provider.
+#[derive(Debug, Default)]
+pub struct TileStats {
+    /// Number of tiles at each zoom level.
+    pub count_by_zoom: BTreeMap<u32, usize>,
+    /// Total size of the tile data, in bytes.
+    pub data_size: u64,
+    /// Size of the tile storage on disk, in bytes, including the freshness
+    /// information.
+    pub disk_size: u64,
+}
+
+/// Statistics about the cached Strava activities.
+#[derive(Debug)]
+pub struct ActivityStats {
+    /// Number of activities.
+    pub count: usize,
+    /// Size of the activities on disk, in bytes.
+    pub disk_size: u64,
+}
+
+/// Number and size of the tiles deleted when pruning the cache.
+#[derive(Debug, Default)]
+pub struct PruneStats {
+    /// Number of deleted tiles.
+    pub count: usize,
+    /// Total size of the deleted tiles, in bytes.
+    pub size: u64,
+}
+
+/// Map tile read from the cache.
+pub struct CachedTile {
+    /// Image format of the tile.
+    pub format: TileFormat,
+    /// Raw bytes of the tile.
+    pub data: Box<[u8]>,
+    /// Freshness information of the tile.
+    pub metadata: TileMetadata,
+    /// Whether the tile is older than the maximum age.
+    pub stale: bool,
+}
+
+/// Storage of the cached map tiles.
+enum TileStore {
+    /// One file per tile, in a folder per map provider.
+    Directory,
+    /// One MBTiles database per map provider, indexed by cache folder.
+    Mbtiles(HashMap<String, Mbtiles>),
+}
+
+/// Cached map tiles of all the map providers, shared with the background task
+/// pruning the tile cache.
+struct TileStorage {
+    /// Root directory of the cache.
+    cache_root: PathBuf,
+    /// Storage of the map tiles.
+    tile_store: TileStore,
+    /// Cache folders of the map providers.
+    cache_folders: Vec<String>,
+}
+
 /// Handle to the on-disk cache.
 pub struct Cache {
     /// Root directory.
     cache_root: PathBuf,
-    /// Folder name for the current map provider.
-    map_provider_folder: String,
+    /// Storage of the map tiles.
+    tiles: Arc<TileStorage>,
+    /// Age after which cached tiles are stale.
+    max_age: Duration,
+    /// Maximum total size of the cached tiles in bytes.
+    max_size: Option<u64>,
+    /// Number of tile bytes written since the cache was last pruned.
+    written: AtomicU64,
+    /// Whether the tile cache is being pruned in the background.
+    pruning: Arc<AtomicBool>,
+    /// Lock file of the cache directory, holding an advisory lock for the
+    /// lifetime of this handle.
+    _lock: File,
 }
 
 impl Cache {
+    /// Fraction of the maximum size to write between two prunings of the
+    /// tile cache.
+    const PRUNE_INTERVAL_RATIO: u64 = 10;
+
     /// Initializes the cache at the given root directory for the given map
-    /// provider.
-    pub fn new(cache_directory: &str, map_provider: &MapProvider) -> anyhow::Result<Self> {
+    /// providers.
+    ///
+    /// This fails if another ridemap instance holds an incompatible lock on
+    /// the cache directory.
+    pub fn new(
+        cache_directory: &str,
+        map_providers: &[MapProvider],
+        params: TileCacheParams,
+        access: CacheAccess,
+    ) -> anyhow::Result<Self> {
         let cache_root = PathBuf::from(cache_directory);
-        let map_provider_folder = map_provider.cache_folder.clone();
         fs::create_dir_all(cache_root.join("strava/activities"))
             .context("Failed to create the strava/activities cache")?;
-        fs::create_dir_all(cache_root.join(format!("tiles/{map_provider_folder}"))).with_context(
-            || format!("Failed to create the tile cache for provider: {map_provider_folder}"),
-        )?;
+        fs::create_dir_all(cache_root.join("strava/streams"))
+            .context("Failed to create the strava/streams cache")?;
+        let lock = lock_cache_root(&cache_root, access)?;
+        let tile_store = match params.backend {
+            TileCacheBackend::Directory => {
+                for map_provider in map_providers {
+                    let map_provider_folder = &map_provider.cache_folder;
+                    fs::create_dir_all(cache_root.join(format!("tiles/{map_provider_folder}")))
+                        .with_context(|| {
+                            format!(
+                                "Failed to create the tile cache for provider: {map_provider_folder}"
+                            )
+                        })?;
+                }
+                TileStore::Directory
+            }
+            TileCacheBackend::Mbtiles => {
+                let mut databases = HashMap::new();
+                for map_provider in map_providers {
+                    let map_provider_folder = &map_provider.cache_folder;
+                    let path = cache_root.join(format!("tiles/{map_provider_folder}.mbtiles"));
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent).with_context(|| {
+                            format!(
+                                "Failed to create the tile cache for provider: {map_provider_folder}"
+                            )
+                        })?;
+                    }
+                    let mbtiles = Mbtiles::create(&path, map_provider_folder, map_provider.format)
+                        .with_context(|| {
+                            format!(
+                            "Failed to create the tile cache for provider: {map_provider_folder}"
+                        )
+                        })?;
+                    databases.insert(map_provider_folder.clone(), mbtiles);
+                }
+                TileStore::Mbtiles(databases)
+            }
+        };
         Ok(Self {
+            tiles: Arc::new(TileStorage {
+                cache_root: cache_root.clone(),
+                tile_store,
+                cache_folders: map_providers
+                    .iter()
+                    .map(|map_provider| map_provider.cache_folder.clone())
+                    .collect(),
+            }),
             cache_root,
-            map_provider_folder,
+            max_age: params.max_age,
+            max_size: params.max_size,
+            written: AtomicU64::new(0),
+            pruning: Arc::new(AtomicBool::new(false)),
+            _lock: lock,
         })
     }
 
     /// Writes the given Strava activity.
     pub fn set_activity(&self, id: u64, activity: &DetailedActivity) -> anyhow::Result<()> {
-        let file = File::create(self.activity_path(id)).with_context(|| {
-            format!("Failed to create file for Strava activity (id = {id}) in cache")
-        })?;
-        let writer = BufWriter::new(file);
-        serde_json::to_writer(writer, activity)
-            .with_context(|| format!("Failed to serialize Strava activity (id = {id}) in cache"))
+        write_atomically(&self.activity_path(id), FileMode::Default, |writer| {
+            serde_json::to_writer(writer, activity).with_context(|| {
+                format!("Failed to serialize Strava activity (id = {id}) in cache")
+            })
+        })
+        .with_context(|| format!("Failed to write file for Strava activity (id = {id}) in cache"))
     }
 
     /// Reads the given Strava activity.
     pub async fn get_activity(&self, id: u64) -> anyhow::Result<DetailedActivity> {
         let path = self.activity_path(id);
 
-        spawn_blocking(move || -> anyhow::Result<DetailedActivity> {
-            let path = &path;
-            let file = File::open(path).with_context(|| {
-                format!(
-                    "Failed to open file for Strava activity (id = {id}) from cache: {}",
-                    path.display()
-                )
-            })?;
-            let reader = BufReader::new(file);
-            serde_json::from_reader(reader).with_context(|| {
-                format!(
-                    "Failed to parse file for Strava activity (id = {id}) from cache: {}",
-                    path.display()
-                )
-            })
+        spawn_blocking(move || {
+            let result = read_activity(&path, id);
+            if let Err(e) = &result {
+                // Delete the corrupt activity, so that it can be fetched again.
+                if e.downcast_ref::<serde_json::Error>().is_some() {
+                    warn!("Deleting corrupt Strava activity (id = {id}) from cache: {e:?}");
+                    if let Err(e) = fs::remove_file(&path) {
+                        error!("Couldn't delete Strava activity (id = {id}) from cache: {e:?}");
+                    }
+                }
+            }
+            result
         })
         .await
         .with_context(|| {
@@ -71,38 +301,1185 @@ impl Cache {
         })?
     }
 
-    /// Reads the given map tile.
-    pub fn get_tile(&self, index: &TileIndex) -> anyhow::Result<Box<[u8]>> {
-        let mut file = File::open(self.tile_path(index))
-            .with_context(|| format!("Failed to open file for tile: {index:?}"))?;
+    /// Writes the streams of the given Strava activity.
+    pub fn set_activity_streams(&self, id: u64, streams: &StreamSet) -> anyhow::Result<()> {
+        write_atomically(&self.streams_path(id), FileMode::Default, |writer| {
+            serde_json::to_writer(writer, streams).with_context(|| {
+                format!("Failed to serialize streams of Strava activity (id = {id}) in cache")
+            })
+        })
+        .with_context(|| {
+            format!("Failed to write file for streams of Strava activity (id = {id}) in cache")
+        })
+    }
+
+    /// Reads the streams of the given Strava activity.
+    pub async fn get_activity_streams(&self, id: u64) -> anyhow::Result<StreamSet> {
+        let path = self.streams_path(id);
+
+        spawn_blocking(move || {
+            let result = read_streams(&path, id);
+            if let Err(e) = &result {
+                // Delete the corrupt streams, so that they can be fetched again.
+                if e.downcast_ref::<serde_json::Error>().is_some() {
+                    warn!("Deleting corrupt streams of Strava activity (id = {id}) from cache: {e:?}");
+                    if let Err(e) = fs::remove_file(&path) {
+                        error!("Couldn't delete streams of Strava activity (id = {id}) from cache: {e:?}");
+                    }
+                }
+            }
+            result
+        })
+        .await
+        .with_context(|| {
+            format!("Failed to join background task to load streams of Strava activity (id = {id}) from cache")
+        })?
+    }
+
+    /// Writes the local index of the known Strava activities.
+    pub fn set_activity_index(&self, activities: &[SummaryActivity]) -> anyhow::Result<()> {
+        write_atomically(&self.activity_index_path(), FileMode::Default, |writer| {
+            Ok(serde_json::to_writer(writer, activities)?)
+        })
+        .context("Failed to write file for Strava activity index in cache")
+    }
+
+    /// Reads the local index of the known Strava activities, if any.
+    pub fn get_activity_index(&self) -> anyhow::Result<Option<Vec<SummaryActivity>>> {
+        let path = self.activity_index_path();
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to open file for Strava activity index: {}",
+                        path.display()
+                    )
+                })
+            }
+        };
+        let activities = serde_json::from_reader(BufReader::new(file)).with_context(|| {
+            format!(
+                "Failed to parse file for Strava activity index: {}",
+                path.display()
+            )
+        })?;
+        Ok(Some(activities))
+    }
+
+    /// Writes the OAuth token of the given Strava application, readable only by
+    /// the current user.
+    pub fn set_strava_token(&self, client_id: &str, token: &Token) -> anyhow::Result<()> {
+        write_atomically(
+            &self.strava_token_path(client_id),
+            FileMode::Private,
+            |writer| Ok(serde_json::to_writer(writer, token)?),
+        )
+        .context("Failed to write file for Strava token in cache")
+    }
+
+    /// Reads the OAuth token of the given Strava application, if any.
+    pub fn get_strava_token(&self, client_id: &str) -> anyhow::Result<Option<Token>> {
+        let path = self.strava_token_path(client_id);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to open file for Strava token: {}", path.display())
+                })
+            }
+        };
+        let token = serde_json::from_reader(BufReader::new(file)).with_context(|| {
+            format!("Failed to parse file for Strava token: {}", path.display())
+        })?;
+        Ok(Some(token))
+    }
+
+    /// Lists the IDs of the cached Strava activities, from the most recent to
+    /// the oldest.
+    pub fn list_activities(&self) -> anyhow::Result<Vec<u64>> {
+        let folder = self.activities_path();
+        let mut ids = fs::read_dir(&folder)
+            .with_context(|| {
+                format!(
+                    "Failed to list Strava activities in cache: {}",
+                    folder.display()
+                )
+            })?
+            .filter_map(|entry| parse_activity_path(&entry.ok()?.path()))
+            .collect::<Vec<_>>();
+        // Strava assigns increasing IDs to new activities.
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(ids)
+    }
+
+    /// Reads the given map tile of the given provider, with its freshness
+    /// information, and marks it as recently used.
+    ///
+    /// In a directory cache, the tile is looked up under the extension of each
+    /// supported format, as a provider may serve tiles in different formats.
+    /// Tiles without metadata (e.g. cached by a previous version) are stale.
+    pub async fn get_tile(
+        &self,
+        map_provider: &MapProvider,
+        index: &TileIndex,
+    ) -> anyhow::Result<CachedTile> {
+        let tiles = self.tiles.clone();
+        let folder = map_provider.cache_folder.clone();
+        let index = *index;
+        let max_age = self.max_age;
+
+        spawn_blocking(move || tiles.get_tile(&folder, &index, max_age))
+            .await
+            .with_context(|| {
+                format!("Failed to join background task to read tile {index:?} from cache")
+            })?
+    }
+
+    /// Writes the given map tile of the given provider, encoded in the given
+    /// format, with its freshness information.
+    ///
+    /// The cache is pruned in the background once enough tiles were written
+    /// since the last pruning.
+    pub async fn set_tile(
+        &self,
+        map_provider: &MapProvider,
+        index: &TileIndex,
+        format: TileFormat,
+        tile: &[u8],
+        metadata: &TileMetadata,
+    ) -> anyhow::Result<()> {
+        let tiles = self.tiles.clone();
+        let folder = map_provider.cache_folder.clone();
+        let index = *index;
+        let size = tile.len() as u64;
+        let tile = tile.to_vec();
+        let metadata = metadata.clone();
+
+        spawn_blocking(move || tiles.set_tile(&folder, &index, format, &tile, &metadata))
+            .await
+            .with_context(|| {
+                format!("Failed to join background task to write tile {index:?} to cache")
+            })??;
+
+        if let Some(max_size) = self.max_size {
+            let written = self.written.fetch_add(size, Ordering::Relaxed);
+            if written >= max_size / Self::PRUNE_INTERVAL_RATIO {
+                self.written.store(0, Ordering::Relaxed);
+                self.prune_tiles_in_background(max_size);
+            }
+        }
+        Ok(())
+    }
+
+    /// Prunes the tile cache to the maximum size, if any, on a blocking
+    /// background task. This must be called within a Tokio runtime.
+    pub fn spawn_prune_tiles(&self) {
+        if let Some(max_size) = self.max_size {
+            self.prune_tiles_in_background(max_size);
+        }
+    }
+
+    /// Prunes the tile cache to the given maximum size on a blocking
+    /// background task, unless a pruning is already in progress.
+    fn prune_tiles_in_background(&self, max_size: u64) {
+        if self.pruning.swap(true, Ordering::AcqRel) {
+            debug!("The tile cache is already being pruned");
+            return;
+        }
+        let tiles = self.tiles.clone();
+        let pruning = self.pruning.clone();
+        spawn_blocking(move || {
+            if let Err(e) = tiles.prune(max_size) {
+                error!("Couldn't prune the tile cache: {e:?}");
+            }
+            pruning.store(false, Ordering::Release);
+        });
+    }
+
+    /// Updates the freshness information of the given map tile of the given
+    /// provider, e.g. after the provider confirmed that the tile is unchanged.
+    pub async fn set_tile_metadata(
+        &self,
+        map_provider: &MapProvider,
+        index: &TileIndex,
+        metadata: &TileMetadata,
+    ) -> anyhow::Result<()> {
+        let tiles = self.tiles.clone();
+        let folder = map_provider.cache_folder.clone();
+        let index = *index;
+        let metadata = metadata.clone();
+
+        spawn_blocking(move || tiles.set_tile_metadata(&folder, &index, &metadata))
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to join background task to write metadata of tile {index:?} to cache"
+                )
+            })?
+    }
+
+    /// Deletes the least recently used tiles until the total size of the tile
+    /// cache fits within the maximum size, if any.
+    pub fn prune_tiles(&self) -> anyhow::Result<PruneStats> {
+        match self.max_size {
+            Some(max_size) => self.tiles.prune(max_size),
+            None => Ok(PruneStats::default()),
+        }
+    }
+
+    /// Deletes the cached tiles of the given provider that match the given
+    /// predicate, called with the index and freshness information of each
+    /// tile.
+    pub fn prune_tiles_matching(
+        &self,
+        map_provider: &MapProvider,
+        predicate: impl Fn(&TileIndex, &TileMetadata) -> bool,
+    ) -> anyhow::Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        let mut indices = Vec::new();
+        for tile in self.list_tiles(map_provider)? {
+            if predicate(
+                &tile.index,
+                &self
+                    .tiles
+                    .get_tile_metadata(&map_provider.cache_folder, &tile.index)?,
+            ) {
+                stats.count += 1;
+                stats.size += tile.size;
+                indices.push(tile.index);
+            }
+        }
+        self.delete_tiles(map_provider, indices)?;
+        Ok(stats)
+    }
+
+    /// Lists the cached tiles of the given provider.
+    pub fn list_tiles(&self, map_provider: &MapProvider) -> anyhow::Result<Vec<TileUsage>> {
+        self.tiles.list_folder_tiles(&map_provider.cache_folder)
+    }
+
+    /// Deletes the given tiles of the given provider, with their freshness
+    /// information.
+    pub fn delete_tiles(
+        &self,
+        map_provider: &MapProvider,
+        indices: Vec<TileIndex>,
+    ) -> anyhow::Result<()> {
+        self.tiles
+            .delete_folder_tiles(&map_provider.cache_folder, indices)
+    }
+
+    /// Returns statistics about the cached tiles of the given provider.
+    pub fn tile_stats(&self, map_provider: &MapProvider) -> anyhow::Result<TileStats> {
+        let mut stats = TileStats::default();
+        for tile in self.list_tiles(map_provider)? {
+            *stats.count_by_zoom.entry(tile.index.z).or_default() += 1;
+            stats.data_size += tile.size;
+        }
+        let folder = &map_provider.cache_folder;
+        stats.disk_size = match self.tiles.tile_store {
+            TileStore::Mbtiles(_) => {
+                let path = self.tiles.mbtiles_path(folder);
+                fs::metadata(&path)
+                    .with_context(|| format!("Failed to read metadata of: {}", path.display()))?
+                    .len()
+            }
+            TileStore::Directory => directory_size(&self.tiles.tile_folder_path(folder))?,
+        };
+        Ok(stats)
+    }
+
+    /// Returns statistics about the cached Strava activities.
+    pub fn activity_stats(&self) -> anyhow::Result<ActivityStats> {
+        Ok(ActivityStats {
+            count: self.list_activities()?.len(),
+            disk_size: directory_size(&self.activities_path())?
+                + directory_size(&self.streams_folder_path())?,
+        })
+    }
+
+    /// Checks that the cached tiles of the given provider can be decoded, and
+    /// that their freshness information can be parsed. Returns the corrupt
+    /// tiles, with a description of the problem.
+    pub fn verify_tiles(
+        &self,
+        map_provider: &MapProvider,
+    ) -> anyhow::Result<Vec<(TileIndex, anyhow::Error)>> {
+        let mut corrupt = Vec::new();
+        for tile in self.list_tiles(map_provider)? {
+            if let Err(e) = self.verify_tile(map_provider, &tile.index) {
+                debug!("Tile {:?} is corrupt: {e:?}", tile.index);
+                corrupt.push((tile.index, e));
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// Checks that the given tile of the given provider can be decoded, and
+    /// that its freshness information can be parsed.
+    ///
+    /// Unlike [`Self::get_tile()`], this doesn't mark the tile as recently
+    /// used.
+    fn verify_tile(&self, map_provider: &MapProvider, index: &TileIndex) -> anyhow::Result<()> {
+        let (format, data) = match self.tiles.tile_store {
+            TileStore::Mbtiles(_) => {
+                let mbtiles = self.mbtiles(map_provider)?;
+                mbtiles.get_tile_metadata(index)?;
+                mbtiles.get_tile(index)?
+            }
+            TileStore::Directory => {
+                let path = self
+                    .tiles
+                    .tile_metadata_path(&map_provider.cache_folder, index);
+                match fs::read(&path) {
+                    Ok(bytes) => {
+                        serde_json::from_slice::<TileMetadata>(&bytes).with_context(|| {
+                            format!("Failed to parse metadata file: {}", path.display())
+                        })?;
+                    }
+                    // Tiles cached by a previous version may not have metadata.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("Failed to read metadata file: {}", path.display())
+                        })
+                    }
+                }
+                let (format, mut file) = self
+                    .tiles
+                    .open_tile_file(&map_provider.cache_folder, index)?;
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)
+                    .with_context(|| format!("Failed to read file for tile: {index:?}"))?;
+                (format, buf.into_boxed_slice())
+            }
+        };
+        decode_image(&data, format).with_context(|| format!("Failed to decode {format:?} data"))?;
+        Ok(())
+    }
+
+    /// Checks that the cached Strava activities can be parsed. Returns the
+    /// corrupt activities, with a description of the problem.
+    pub fn verify_activities(&self) -> anyhow::Result<Vec<(u64, anyhow::Error)>> {
+        let mut corrupt = Vec::new();
+        for id in self.list_activities()? {
+            if let Err(e) = read_activity(&self.activity_path(id), id) {
+                debug!("Strava activity {id} is corrupt: {e:?}");
+                corrupt.push((id, e));
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// Checks that the cached streams of the Strava activities can be parsed.
+    /// Returns the activities with corrupt streams, with a description of the
+    /// problem.
+    pub fn verify_activity_streams(&self) -> anyhow::Result<Vec<(u64, anyhow::Error)>> {
+        let folder = self.streams_folder_path();
+        let ids = fs::read_dir(&folder)
+            .with_context(|| {
+                format!(
+                    "Failed to list streams of Strava activities in cache: {}",
+                    folder.display()
+                )
+            })?
+            .filter_map(|entry| parse_activity_path(&entry.ok()?.path()));
+        let mut corrupt = Vec::new();
+        for id in ids {
+            if let Err(e) = read_streams(&self.streams_path(id), id) {
+                debug!("Streams of Strava activity {id} are corrupt: {e:?}");
+                corrupt.push((id, e));
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// Checks that the local index of the Strava activities, if any, can be
+    /// parsed. Returns a description of the problem if it's corrupt.
+    pub fn verify_activity_index(&self) -> Option<anyhow::Error> {
+        self.get_activity_index().err()
+    }
+
+    /// Lists the temporary files left in the cache by interrupted writes.
+    pub fn list_stale_temp_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut folders = vec![
+            self.cache_root.join("strava"),
+            self.activities_path(),
+            self.streams_folder_path(),
+        ];
+        if let TileStore::Directory = self.tiles.tile_store {
+            for folder in &self.tiles.cache_folders {
+                folders.push(self.tiles.tile_folder_path(folder));
+            }
+        }
+        let mut paths = Vec::new();
+        for folder in folders {
+            paths.extend(stale_temp_files(&folder)?);
+        }
+        Ok(paths)
+    }
+
+    /// Deletes the given temporary files left in the cache.
+    pub fn delete_temp_files(&self, paths: &[PathBuf]) -> anyhow::Result<()> {
+        for path in paths {
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to delete temporary file: {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the streams of the given Strava activity.
+    pub fn delete_activity_streams(&self, id: u64) -> anyhow::Result<()> {
+        fs::remove_file(self.streams_path(id)).with_context(|| {
+            format!("Failed to delete file for streams of Strava activity (id = {id}) from cache")
+        })
+    }
+
+    /// Deletes the local index of the Strava activities, so that the next
+    /// sync lists all the activities again.
+    pub fn delete_activity_index(&self) -> anyhow::Result<()> {
+        fs::remove_file(self.activity_index_path())
+            .context("Failed to delete file for Strava activity index from cache")
+    }
+
+    /// Deletes the given Strava activity, and its streams if any.
+    pub fn delete_activity(&self, id: u64) -> anyhow::Result<()> {
+        fs::remove_file(self.activity_path(id)).with_context(|| {
+            format!("Failed to delete file for Strava activity (id = {id}) from cache")
+        })?;
+        match fs::remove_file(self.streams_path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).with_context(|| {
+                format!(
+                    "Failed to delete file for streams of Strava activity (id = {id}) from cache"
+                )
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Imports the tiles of the given provider from a directory cache into the
+    /// MBTiles cache, optionally deleting the imported files. Returns the
+    /// number of imported tiles.
+    pub fn import_directory(
+        &self,
+        map_provider: &MapProvider,
+        delete: bool,
+    ) -> anyhow::Result<usize> {
+        let mbtiles = self.mbtiles(map_provider)?;
+        let folder = self
+            .cache_root
+            .join(format!("tiles/{}", map_provider.cache_folder));
+        if !folder.is_dir() {
+            debug!("No directory cache to import at: {}", folder.display());
+            return Ok(0);
+        }
+
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&folder)
+            .with_context(|| format!("Failed to list directory cache: {}", folder.display()))?
+        {
+            let path = entry
+                .with_context(|| format!("Failed to list directory cache: {}", folder.display()))?
+                .path();
+            match parse_tile_path(&path) {
+                Some(index) => paths.push((index, path)),
+                // Metadata files are imported alongside their tile.
+                None if path
+                    .extension()
+                    .is_some_and(|extension| extension == "json") => {}
+                None => warn!("Skipping unknown file in tile cache: {}", path.display()),
+            }
+        }
+
+        let mut count = 0;
+        for batch in paths.chunks(IMPORT_BATCH_SIZE) {
+            let tiles = batch
+                .iter()
+                .map(|(index, path)| {
+                    let tile = fs::read(path)
+                        .with_context(|| format!("Failed to read tile file: {}", path.display()))?;
+                    let metadata: TileMetadata = fs::read(path.with_extension("json"))
+                        .ok()
+                        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                        .unwrap_or_default();
+                    Ok((*index, tile, metadata))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            mbtiles.set_tiles(
+                tiles
+                    .iter()
+                    .map(|(index, tile, metadata)| (*index, tile.as_slice(), metadata)),
+                unix_time(),
+            )?;
+            count += tiles.len();
+            debug!("Imported {count}/{} tiles", paths.len());
+
+            if delete {
+                for (_, path) in batch {
+                    fs::remove_file(path).with_context(|| {
+                        format!("Failed to delete imported tile file: {}", path.display())
+                    })?;
+                    let _ = fs::remove_file(path.with_extension("json"));
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Returns the MBTiles database caching the tiles of the given provider.
+    fn mbtiles(&self, map_provider: &MapProvider) -> anyhow::Result<&Mbtiles> {
+        self.tiles.folder_mbtiles(&map_provider.cache_folder)
+    }
+
+    /// Computes the path of the folder containing the Strava activities.
+    fn activities_path(&self) -> PathBuf {
+        self.cache_root.join("strava/activities")
+    }
+
+    /// Computes the path of the folder containing the streams of the Strava
+    /// activities.
+    fn streams_folder_path(&self) -> PathBuf {
+        self.cache_root.join("strava/streams")
+    }
+
+    /// Computes the path of the streams of the given activity.
+    fn streams_path(&self, id: u64) -> PathBuf {
+        self.streams_folder_path().join(format!("{id}.json"))
+    }
+
+    /// Computes the path of the local index of the Strava activities.
+    fn activity_index_path(&self) -> PathBuf {
+        self.cache_root.join("strava/index.json")
+    }
+
+    /// Computes the path of the OAuth token of the given Strava application.
+    fn strava_token_path(&self, client_id: &str) -> PathBuf {
+        self.cache_root
+            .join(format!("strava/token-{client_id}.json"))
+    }
+
+    /// Computes the path associated to the given activity.
+    fn activity_path(&self, id: u64) -> PathBuf {
+        self.activities_path().join(format!("{id}.json"))
+    }
+}
+
+impl TileStorage {
+    /// Reads the given map tile of the given cache folder, with its freshness
+    /// information, and marks it as recently used.
+    ///
+    /// Tiles older than the given maximum age are stale.
+    fn get_tile(
+        &self,
+        folder: &str,
+        index: &TileIndex,
+        max_age: Duration,
+    ) -> anyhow::Result<CachedTile> {
+        let now = unix_time();
+        let (format, data, metadata) = match self.tile_store {
+            TileStore::Mbtiles(_) => {
+                let mbtiles = self.folder_mbtiles(folder)?;
+                let (format, data) = mbtiles.get_tile(index)?;
+                let (metadata, accessed_at) = mbtiles.get_tile_access(index)?.unwrap_or_default();
+                // Writing the access time on every read would contend with the
+                // other readers and writers of the database, while pruning
+                // doesn't need it to be precise.
+                if now.saturating_sub(accessed_at) >= MBTILES_TOUCH_INTERVAL.as_secs() {
+                    if let Err(e) = mbtiles.touch_tile(index, now) {
+                        warn!("Couldn't update the access time of tile {index:?}: {e:?}");
+                    }
+                }
+                (format, data, metadata)
+            }
+            TileStore::Directory => {
+                let (format, data) = self.get_tile_file(folder, index)?;
+                (format, data, self.get_tile_metadata(folder, index)?)
+            }
+        };
+        let stale = now.saturating_sub(metadata.fetched_at) >= max_age.as_secs();
+        Ok(CachedTile {
+            format,
+            data,
+            metadata,
+            stale,
+        })
+    }
+
+    /// Reads the freshness information of the given map tile of the given
+    /// cache folder, without marking the tile as recently used.
+    ///
+    /// Tiles without metadata (e.g. cached by a previous version) get a
+    /// default one, which is stale.
+    fn get_tile_metadata(&self, folder: &str, index: &TileIndex) -> anyhow::Result<TileMetadata> {
+        match self.tile_store {
+            TileStore::Mbtiles(_) => Ok(self
+                .folder_mbtiles(folder)?
+                .get_tile_metadata(index)?
+                .unwrap_or_default()),
+            TileStore::Directory => Ok(fs::read(self.tile_metadata_path(folder, index))
+                .ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                .unwrap_or_default()),
+        }
+    }
+
+    /// Reads the file of the given map tile in a directory cache, and marks
+    /// it as recently used by updating its modification time.
+    fn get_tile_file(
+        &self,
+        folder: &str,
+        index: &TileIndex,
+    ) -> anyhow::Result<(TileFormat, Box<[u8]>)> {
+        let (format, mut file) = self.open_tile_file(folder, index)?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)
             .with_context(|| format!("Failed to read file for tile: {index:?}"))?;
-        Ok(buf.into_boxed_slice())
+        if let Err(e) = file.set_modified(SystemTime::now()) {
+            warn!("Couldn't update the access time of tile {index:?}: {e:?}");
+        }
+        Ok((format, buf.into_boxed_slice()))
     }
 
-    /// Writes the given map tile.
-    pub fn set_tile(&self, index: &TileIndex, tile: &[u8]) -> anyhow::Result<()> {
-        let mut file = File::create(self.tile_path(index))
-            .with_context(|| format!("Failed to create file for tile: {index:?}"))?;
-        file.write_all(tile)
-            .with_context(|| format!("Failed to write file for tile: {index:?}"))?;
+    /// Opens the file of the given map tile in a directory cache, under the
+    /// extension of any supported format.
+    fn open_tile_file(
+        &self,
+        folder: &str,
+        index: &TileIndex,
+    ) -> anyhow::Result<(TileFormat, File)> {
+        TileFormat::ALL
+            .iter()
+            .find_map(|&format| {
+                File::open(self.tile_path(folder, index, format))
+                    .ok()
+                    .map(|file| (format, file))
+            })
+            .with_context(|| format!("Failed to open file for tile: {index:?}"))
+    }
+
+    /// Writes the given map tile of the given cache folder, encoded in the
+    /// given format, with its freshness information.
+    fn set_tile(
+        &self,
+        folder: &str,
+        index: &TileIndex,
+        format: TileFormat,
+        tile: &[u8],
+        metadata: &TileMetadata,
+    ) -> anyhow::Result<()> {
+        match self.tile_store {
+            TileStore::Mbtiles(_) => {
+                self.folder_mbtiles(folder)?
+                    .set_tiles([(*index, tile, metadata)], unix_time())?;
+            }
+            TileStore::Directory => {
+                write_atomically(
+                    &self.tile_path(folder, index, format),
+                    FileMode::Default,
+                    |writer| Ok(writer.write_all(tile)?),
+                )
+                .with_context(|| format!("Failed to write file for tile: {index:?}"))?;
+                // A previous file in another format would otherwise shadow
+                // the new one when reading the tile.
+                for other in TileFormat::ALL.into_iter().filter(|&f| f != format) {
+                    let path = self.tile_path(folder, index, other);
+                    match fs::remove_file(&path) {
+                        Ok(()) => debug!("Deleted previous {other:?} file for tile {index:?}"),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                        Err(e) => {
+                            return Err(e).with_context(|| {
+                                format!("Failed to delete tile file: {}", path.display())
+                            })
+                        }
+                    }
+                }
+                self.set_tile_metadata_file(folder, index, metadata)?;
+            }
+        }
         Ok(())
     }
 
-    /// Computes the path associated to the given activity.
-    fn activity_path(&self, id: u64) -> PathBuf {
-        self.cache_root.join(format!("strava/activities/{id}.json"))
+    /// Updates the freshness information of the given map tile of the given
+    /// cache folder.
+    fn set_tile_metadata(
+        &self,
+        folder: &str,
+        index: &TileIndex,
+        metadata: &TileMetadata,
+    ) -> anyhow::Result<()> {
+        match self.tile_store {
+            TileStore::Mbtiles(_) => {
+                self.folder_mbtiles(folder)?
+                    .set_tile_metadata(index, metadata, unix_time())
+            }
+            TileStore::Directory => self.set_tile_metadata_file(folder, index, metadata),
+        }
+    }
+
+    /// Writes the metadata file of the given map tile in a directory cache.
+    fn set_tile_metadata_file(
+        &self,
+        folder: &str,
+        index: &TileIndex,
+        metadata: &TileMetadata,
+    ) -> anyhow::Result<()> {
+        write_atomically(
+            &self.tile_metadata_path(folder, index),
+            FileMode::Default,
+            |writer| Ok(serde_json::to_writer(writer, metadata)?),
+        )
+        .with_context(|| format!("Failed to write metadata file for tile: {index:?}"))
+    }
+
+    /// Deletes the least recently used tiles until the total size of the tile
+    /// cache fits within the given maximum size.
+    fn prune(&self, max_size: u64) -> anyhow::Result<PruneStats> {
+        if let TileStore::Directory = self.tile_store {
+            for folder in &self.cache_folders {
+                for path in stale_temp_files(&self.tile_folder_path(folder))? {
+                    debug!("Deleting temporary file: {}", path.display());
+                    if let Err(e) = fs::remove_file(&path) {
+                        warn!("Couldn't delete temporary file {}: {e:?}", path.display());
+                    }
+                }
+            }
+        }
+
+        let mut usage = Vec::new();
+        for folder in &self.cache_folders {
+            for tile in self.list_folder_tiles(folder)? {
+                usage.push((tile.accessed_at, tile.size, (folder, tile.index)));
+            }
+        }
+
+        let pruned = lru_to_prune(&mut usage, max_size);
+        let mut pruned_by_folder: HashMap<&String, Vec<TileIndex>> = HashMap::new();
+        for (_, _, (folder, index)) in pruned {
+            pruned_by_folder.entry(folder).or_default().push(*index);
+        }
+        for (folder, indices) in pruned_by_folder {
+            self.delete_folder_tiles(folder, indices)?;
+        }
+        let stats = PruneStats {
+            count: pruned.len(),
+            size: pruned.iter().map(|(_, size, _)| size).sum(),
+        };
+
+        if stats.count != 0 {
+            info!(
+                "Pruned {} tiles ({} bytes) from the tile cache",
+                stats.count, stats.size
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Lists the cached tiles of the given cache folder.
+    fn list_folder_tiles(&self, folder: &str) -> anyhow::Result<Vec<TileUsage>> {
+        match &self.tile_store {
+            TileStore::Mbtiles(_) => self.folder_mbtiles(folder)?.tile_usage(),
+            TileStore::Directory => {
+                let folder = self.tile_folder_path(folder);
+                let mut tiles = Vec::new();
+                for entry in fs::read_dir(&folder).with_context(|| {
+                    format!("Failed to list directory cache: {}", folder.display())
+                })? {
+                    let path = entry
+                        .with_context(|| {
+                            format!("Failed to list directory cache: {}", folder.display())
+                        })?
+                        .path();
+                    let Some(index) = parse_tile_path(&path) else {
+                        continue;
+                    };
+                    let file_metadata = fs::metadata(&path).with_context(|| {
+                        format!("Failed to read metadata of tile file: {}", path.display())
+                    })?;
+                    let accessed_at = file_metadata
+                        .modified()
+                        .ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |duration| duration.as_secs());
+                    tiles.push(TileUsage {
+                        index,
+                        accessed_at,
+                        size: file_metadata.len(),
+                    });
+                }
+                Ok(tiles)
+            }
+        }
     }
 
-    /// Computes the path associated to the given map tile.
-    fn tile_path(&self, index: &TileIndex) -> PathBuf {
+    /// Deletes the given tiles of the given cache folder, with their freshness
+    /// information.
+    fn delete_folder_tiles(&self, folder: &str, indices: Vec<TileIndex>) -> anyhow::Result<()> {
+        match &self.tile_store {
+            TileStore::Mbtiles(_) => self.folder_mbtiles(folder)?.delete_tiles(indices),
+            TileStore::Directory => {
+                for index in &indices {
+                    for format in TileFormat::ALL {
+                        let path = self.tile_path(folder, index, format);
+                        match fs::remove_file(&path) {
+                            Ok(()) => (),
+                            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                            Err(e) => {
+                                return Err(e).with_context(|| {
+                                    format!("Failed to delete tile file: {}", path.display())
+                                })
+                            }
+                        }
+                    }
+                    // Tiles cached by a previous version may not have metadata.
+                    let _ = fs::remove_file(self.tile_metadata_path(folder, index));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the MBTiles database caching the tiles of the given cache
+    /// folder.
+    fn folder_mbtiles(&self, folder: &str) -> anyhow::Result<&Mbtiles> {
+        match &self.tile_store {
+            TileStore::Mbtiles(databases) => databases
+                .get(folder)
+                .with_context(|| format!("No MBTiles cache for provider: {folder}")),
+            TileStore::Directory => bail!("The tile cache isn't backed by MBTiles"),
+        }
+    }
+
+    /// Computes the path of the metadata file associated to the given map tile
+    /// in the given cache folder, in a directory cache.
+    fn tile_metadata_path(&self, folder: &str, index: &TileIndex) -> PathBuf {
         self.cache_root.join(format!(
-            "tiles/{provider}/{z}-{x}-{y}.png",
-            provider = self.map_provider_folder,
+            "tiles/{folder}/{z}-{x}-{y}.json",
             z = index.z,
             x = index.x,
-            y = index.y
+            y = index.y,
         ))
     }
+
+    /// Computes the path of the directory cache of the given cache folder.
+    fn tile_folder_path(&self, folder: &str) -> PathBuf {
+        self.cache_root.join(format!("tiles/{folder}"))
+    }
+
+    /// Computes the path of the MBTiles cache of the given cache folder.
+    fn mbtiles_path(&self, folder: &str) -> PathBuf {
+        self.cache_root.join(format!("tiles/{folder}.mbtiles"))
+    }
+
+    /// Computes the path associated to the given map tile in the given cache
+    /// folder and in the given format, in a directory cache.
+    fn tile_path(&self, folder: &str, index: &TileIndex, format: TileFormat) -> PathBuf {
+        self.cache_root.join(format!(
+            "tiles/{folder}/{z}-{x}-{y}.{extension}",
+            z = index.z,
+            x = index.x,
+            y = index.y,
+            extension = format.extension(),
+        ))
+    }
+}
+
+/// Lists the temporary files of [`write_atomically()`] in the given folder
+/// that are old enough to have been left over by an interrupted write.
+fn stale_temp_files(folder: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to list folder: {}", folder.display()))
+        }
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to list folder: {}", folder.display()))?
+            .path();
+        if path.extension().is_none_or(|extension| extension != "tmp") {
+            continue;
+        }
+        let stale = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age >= STALE_TEMP_FILE_AGE);
+        if stale {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Opens the lock file of the given cache directory, and acquires an advisory
+/// lock on it for the given access.
+fn lock_cache_root(cache_root: &Path, access: CacheAccess) -> anyhow::Result<File> {
+    let path = cache_root.join("lock");
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Failed to open the cache lock file: {}", path.display()))?;
+    // The trait methods are named explicitly, as newer versions of the
+    // standard library provide methods with the same names.
+    let result = match access {
+        CacheAccess::Shared => FileExt::try_lock_shared(&file),
+        CacheAccess::Exclusive => FileExt::try_lock(&file),
+    };
+    match result {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => bail!(
+            "The cache directory is locked by another ridemap instance: {}",
+            cache_root.display()
+        ),
+        Err(TryLockError::Error(e)) => Err(e)
+            .with_context(|| format!("Failed to lock the cache directory: {}", path.display())),
+    }
+}
+
+/// Writes a file at the given path with the given function, atomically
+/// replacing any existing file.
+///
+/// The data is first written to a temporary file in the same directory, which
+/// is then renamed, so that concurrent readers and crashes never observe a
+/// partially written file. The file is created with the given permissions.
+fn write_atomically(
+    path: &Path,
+    mode: FileMode,
+    write: impl FnOnce(&mut BufWriter<File>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    /// Counter to give a unique name to each temporary file of this process.
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut tmp_name = path.file_name().context("Invalid file name")?.to_owned();
+    tmp_name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if mode == FileMode::Private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let result = options
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create file: {}", tmp_path.display()))
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer
+                .flush()
+                .and_then(|()| writer.get_ref().sync_all())
+                .with_context(|| format!("Failed to write file: {}", tmp_path.display()))
+        })
+        .and_then(|()| {
+            fs::rename(&tmp_path, path).with_context(|| {
+                format!(
+                    "Failed to rename {} to {}",
+                    tmp_path.display(),
+                    path.display()
+                )
+            })
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Reads the given Strava activity from the given file.
+fn read_activity(path: &Path, id: u64) -> anyhow::Result<DetailedActivity> {
+    let file = File::open(path).with_context(|| {
+        format!(
+            "Failed to open file for Strava activity (id = {id}) from cache: {}",
+            path.display()
+        )
+    })?;
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader).with_context(|| {
+        format!(
+            "Failed to parse file for Strava activity (id = {id}) from cache: {}",
+            path.display()
+        )
+    })
+}
+
+/// Reads the streams of the given Strava activity from the given file.
+fn read_streams(path: &Path, id: u64) -> anyhow::Result<StreamSet> {
+    let file = File::open(path).with_context(|| {
+        format!(
+            "Failed to open file for streams of Strava activity (id = {id}) from cache: {}",
+            path.display()
+        )
+    })?;
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader).with_context(|| {
+        format!(
+            "Failed to parse file for streams of Strava activity (id = {id}) from cache: {}",
+            path.display()
+        )
+    })
+}
+
+/// Returns the total size of the files in the given directory, in bytes.
+fn directory_size(path: &Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)
+        .with_context(|| format!("Failed to list directory: {}", path.display()))?
+    {
+        let metadata = entry
+            .and_then(|entry| entry.metadata())
+            .with_context(|| format!("Failed to list directory: {}", path.display()))?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Sorts the given `(accessed_at, size, key)` usage of the tile cache from
+/// least to most recently used, and returns the tiles to prune so that the
+/// remaining ones fit within the given maximum size.
+fn lru_to_prune<T>(usage: &mut [(u64, u64, T)], max_size: u64) -> &[(u64, u64, T)] {
+    usage.sort_by_key(|(accessed_at, _, _)| *accessed_at);
+    let mut total: u64 = usage.iter().map(|(_, size, _)| size).sum();
+    let mut count = 0;
+    for (_, size, _) in usage.iter() {
+        if total <= max_size {
+            break;
+        }
+        total -= size;
+        count += 1;
+    }
+    &usage[..count]
+}
+
+/// Parses the index of a tile from the path of its file in a directory cache,
+/// i.e. `{z}-{x}-{y}.{extension}`.
+fn parse_tile_path(path: &Path) -> Option<TileIndex> {
+    let extension = path.extension()?.to_str()?;
+    if !TileFormat::ALL
+        .iter()
+        .any(|format| format.extension() == extension)
+    {
+        return None;
+    }
+    let mut parts = path.file_stem()?.to_str()?.split('-');
+    let z = parts.next()?.parse().ok()?;
+    let x = parts.next()?.parse().ok()?;
+    let y = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || z >= 32 || x >> z != 0 || y >> z != 0 {
+        return None;
+    }
+    Some(TileIndex { z, x, y })
+}
+
+/// Parses the ID of a Strava activity from the path of its file in the cache,
+/// i.e. `{id}.json`.
+fn parse_activity_path(path: &Path) -> Option<u64> {
+    if path.extension()? != "json" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_tile_path_valid() {
+        assert_eq!(
+            parse_tile_path(Path::new("tiles/osm/12-2048-1361.png")),
+            Some(TileIndex {
+                z: 12,
+                x: 2048,
+                y: 1361
+            })
+        );
+        assert_eq!(
+            parse_tile_path(Path::new("0-0-0.webp")),
+            Some(TileIndex { z: 0, x: 0, y: 0 })
+        );
+    }
+
+    #[test]
+    fn set_tile_replaces_other_formats() {
+        let directory =
+            std::env::temp_dir().join(format!("ridemap-test-formats-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("tiles/test")).unwrap();
+        let tiles = TileStorage {
+            cache_root: directory.clone(),
+            tile_store: TileStore::Directory,
+            cache_folders: vec!["test".to_owned()],
+        };
+        let index = TileIndex { z: 1, x: 0, y: 1 };
+        let metadata = TileMetadata::default();
+
+        tiles
+            .set_tile("test", &index, TileFormat::Png, b"png", &metadata)
+            .unwrap();
+        tiles
+            .set_tile("test", &index, TileFormat::Jpeg, b"jpeg", &metadata)
+            .unwrap();
+        let (format, data) = tiles.get_tile_file("test", &index).unwrap();
+        assert_eq!(format, TileFormat::Jpeg);
+        assert_eq!(&*data, b"jpeg");
+        assert!(!tiles.tile_path("test", &index, TileFormat::Png).exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn lru_to_prune_oldest() {
+        let mut usage = [(30, 100, 'c'), (10, 100, 'a'), (20, 50, 'b'), (40, 10, 'd')];
+        assert_eq!(lru_to_prune(&mut usage, 1000), []);
+        assert_eq!(lru_to_prune(&mut usage, 160), [(10, 100, 'a')]);
+        assert_eq!(
+            lru_to_prune(&mut usage, 150),
+            [(10, 100, 'a'), (20, 50, 'b')]
+        );
+        assert_eq!(lru_to_prune(&mut usage, 0).len(), 4);
+    }
+
+    #[test]
+    fn parse_activity_path_valid() {
+        assert_eq!(
+            parse_activity_path(Path::new("strava/activities/1234567.json")),
+            Some(1234567)
+        );
+        assert_eq!(parse_activity_path(Path::new("1234567.json.tmp")), None);
+        assert_eq!(parse_activity_path(Path::new("activity.json")), None);
+    }
+
+    #[test]
+    fn parse_tile_path_invalid() {
+        assert_eq!(parse_tile_path(Path::new("12-2048-1361.txt")), None);
+        assert_eq!(parse_tile_path(Path::new("12-2048.png")), None);
+        assert_eq!(parse_tile_path(Path::new("1-2-0.png")), None);
+        assert_eq!(parse_tile_path(Path::new("1-0-0-0.png")), None);
+    }
 }
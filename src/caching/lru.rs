@@ -98,6 +98,22 @@ where
         }
     }
 
+    /// Replaces the value for the given key, if the store contains it, making
+    /// it the most recently used.
+    ///
+    /// Returns whether the value was replaced.
+    pub fn replace(&mut self, k: &K, value: V) -> bool {
+        let generation = self.next_generation();
+        match self.map.get_mut(k) {
+            Some(item) => {
+                item.generation.set(generation);
+                item.value = value;
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Evicts an item from the store, following:
     /// 1. the given priority predicate,
     /// 2. among items of equal priority, the least recently used item is
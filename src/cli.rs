@@ -1,7 +1,10 @@
 //! Command-line interface.
 
-use crate::config::MapProvider;
-use crate::tracks::schema::ActivityType;
+use crate::caching::cache::TileCacheBackend;
+use crate::config::MapProviders;
+use crate::map::prefetch::BoundingBox;
+use crate::tracks::date::parse_date_or_date_time;
+use crate::tracks::schema::{ActivityType, SportType};
 use crate::tracks::strava::StravaConfig;
 use clap::{Parser, Subcommand};
 
@@ -12,18 +15,33 @@ use clap::{Parser, Subcommand};
 #[command(author)]
 #[command(about = "Map your rides", long_about = None)]
 pub struct Cli {
-    /// Sub-command to load tracks.
+    /// Sub-command to load tracks, to prefetch tiles or to manage the cache.
     #[command(subcommand)]
-    pub track_params: Option<TrackParams>,
+    pub command: Option<Command>,
 
-    /// JSON file containing the map provider configuration.
-    #[arg(long = "map-config", value_parser = clap::value_parser!(MapProvider))]
-    pub map_provider: MapProvider,
+    /// JSON file containing the map provider configuration, either a single
+    /// provider or a list of providers to switch between.
+    #[arg(long = "map-config", value_parser = clap::value_parser!(MapProviders))]
+    pub map_providers: MapProviders,
 
     /// Path of the cache directory.
     #[arg(long, short = 'c')]
     pub cache_directory: Option<String>,
 
+    /// Storage backend for the map tiles in the cache directory.
+    #[arg(long, value_enum, default_value_t = TileCacheBackend::Directory)]
+    pub tile_cache: TileCacheBackend,
+
+    /// Number of days after which cached map tiles are revalidated with their
+    /// provider.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+    pub tile_max_age_days: u32,
+
+    /// Maximum total size of the cached map tiles, in megabytes. The least
+    /// recently used tiles are pruned beyond this size.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_tile_cache_mb: Option<u64>,
+
     /// Refresh UI only when graphics change, instead of on each frame.
     #[arg(long)]
     pub lazy_ui_refresh: bool,
@@ -36,6 +54,11 @@ pub struct Cli {
     #[arg(long)]
     pub background_ui_thread: bool,
 
+    /// Never access the network: map tiles and Strava activities are only read
+    /// from local sources and the cache directory.
+    #[arg(long)]
+    pub offline: bool,
+
     /// Maximum number of requests to send in parallel to a server.
     #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=100))]
     pub parallel_requests: u32,
@@ -50,11 +73,150 @@ pub struct Cli {
     pub max_tile_level: i32,
 }
 
+/// Sub-command to run.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Load tracks and display them on the map.
+    #[command(flatten)]
+    Tracks(TrackParams),
+
+    /// Download the map tiles of an area into the cache, for offline use.
+    Prefetch(PrefetchParams),
+
+    /// Manage the cache directory.
+    #[command(subcommand)]
+    Cache(CacheCommand),
+}
+
+/// Parameters to prefetch map tiles.
+#[derive(Parser, Debug)]
+pub struct PrefetchParams {
+    /// Area to prefetch.
+    #[command(subcommand)]
+    pub area: PrefetchArea,
+
+    /// Minimum zoom level to prefetch.
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=20))]
+    pub min_zoom: u32,
+
+    /// Maximum zoom level to prefetch, by default the --max-tile-level.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=20))]
+    pub max_zoom: Option<u32>,
+
+    /// Map layers to prefetch, as indices in the map provider configuration.
+    /// By default, all the providers without a local source are prefetched.
+    #[arg(long, value_delimiter = ',')]
+    pub layers: Vec<usize>,
+
+    /// Maximum number of tiles to prefetch. The prefetching is aborted before
+    /// starting if the area contains more tiles.
+    #[arg(long, default_value_t = 10000)]
+    pub max_tiles: usize,
+
+    /// Only print the number of tiles to prefetch.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Area to prefetch.
+#[derive(Subcommand, Debug)]
+pub enum PrefetchArea {
+    /// Rectangle between the given coordinates.
+    Bbox {
+        /// Coordinates of the rectangle, in degrees, in the
+        /// `min_lon,min_lat,max_lon,max_lat` format.
+        #[arg(allow_negative_numbers = true)]
+        bbox: BoundingBox,
+    },
+
+    /// Corridor around the given tracks.
+    Corridor {
+        /// Distance from the tracks to prefetch, in meters.
+        #[arg(long, default_value_t = 1000.0)]
+        buffer_meters: f64,
+
+        /// Tracks to prefetch around.
+        #[command(subcommand)]
+        tracks: TrackParams,
+    },
+
+    /// Rectangle containing all the given tracks.
+    Extent {
+        /// Tracks to prefetch.
+        #[command(subcommand)]
+        tracks: TrackParams,
+    },
+}
+
+/// Sub-command to manage the cache directory.
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Import the tiles of a directory cache into the MBTiles cache.
+    Migrate(MigrateParams),
+
+    /// Print the number of cached tiles by zoom level and the size of the
+    /// cache.
+    Stats,
+
+    /// Delete tiles from the cache.
+    Prune(PruneParams),
+
+    /// Detect corrupt tiles and Strava activities in the cache.
+    Verify(VerifyParams),
+}
+
+/// Parameters to migrate a directory cache.
+#[derive(Parser, Debug)]
+pub struct MigrateParams {
+    /// Delete the tile files once imported.
+    #[arg(long)]
+    pub delete: bool,
+}
+
+/// Parameters to prune the cache.
+///
+/// Tiles matching all the given `--older-than-days`, `--min-zoom`,
+/// `--max-zoom` and `--bbox` criteria are deleted. Then, the least recently
+/// used tiles are deleted until the cache fits within `--max-size-mb`.
+#[derive(Parser, Debug)]
+pub struct PruneParams {
+    /// Delete the tiles fetched (or last revalidated) more than this number of
+    /// days ago.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub older_than_days: Option<u32>,
+
+    /// Delete the tiles at this zoom level or above.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=31))]
+    pub min_zoom: Option<u32>,
+
+    /// Delete the tiles at this zoom level or below.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=31))]
+    pub max_zoom: Option<u32>,
+
+    /// Delete the tiles intersecting this rectangle, in degrees, in the
+    /// `min_lon,min_lat,max_lon,max_lat` format.
+    #[arg(long, allow_hyphen_values = true)]
+    pub bbox: Option<BoundingBox>,
+
+    /// Delete the least recently used tiles until the total size of the tiles
+    /// fits within this limit, in megabytes.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_size_mb: Option<u64>,
+}
+
+/// Parameters to verify the cache.
+#[derive(Parser, Debug)]
+pub struct VerifyParams {
+    /// Delete the corrupt entries, so that they are fetched again when needed.
+    #[arg(long)]
+    pub delete: bool,
+}
+
 /// Parameters to load tracks.
 #[derive(Subcommand, Debug)]
 pub enum TrackParams {
     /// Fetch activities from Strava.
-    Strava(StravaParams),
+    Strava(Box<StravaParams>),
 
     /// Fetch activities from GPX file(s).
     Gpx(GpxParams),
@@ -78,17 +240,60 @@ pub struct StravaParams {
     #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..=200))]
     pub activities_per_page: u32,
 
-    /// Number of activity pages to list.
+    /// Number of activity pages to list, when there is no local index of the
+    /// activities in the cache yet.
     #[arg(long, default_value_t = 12, value_parser = clap::value_parser!(u32).range(1..=100))]
     pub activity_pages: u32,
 
+    /// List the whole history of activities, paging until the end rather than
+    /// only listing the activities newer than the local index (or the
+    /// `--activity-pages` most recent pages without an index).
+    #[arg(long)]
+    pub backfill: bool,
+
     /// Total number of activities to show on the map.
     #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u32).range(0..=10000))]
     pub activity_count: u32,
 
-    /// Activity(ies) to display.
-    #[arg(long, value_delimiter = ',', value_enum)]
+    /// Fetch the streams of the activities (time, altitude, heart rate, power
+    /// and speed at each point), to color the tracks by these attributes. This
+    /// doubles the number of requests to Strava.
+    #[arg(long = "streams")]
+    pub fetch_streams: bool,
+
+    /// Also display the routes of the athlete, on top of the activities.
+    #[arg(long)]
+    pub routes: bool,
+
+    /// Also display the segments starred by the athlete, on top of the
+    /// activities and routes, and log the athlete's efforts on them.
+    #[arg(long)]
+    pub starred_segments: bool,
+
+    /// Activity type(s) to display (e.g. Ride, Run, Hike).
+    #[arg(long, value_delimiter = ',')]
     pub activity_types: Vec<ActivityType>,
+
+    /// Sport type(s) to display, more specific than activity types (e.g.
+    /// GravelRide, MountainBikeRide, TrailRun).
+    #[arg(long, value_delimiter = ',')]
+    pub sport_types: Vec<SportType>,
+
+    /// Only display the activities started on or after this UTC date (e.g.
+    /// 2024-01-01 or 2024-01-01T08:00:00Z).
+    #[arg(long, value_parser = parse_timestamp)]
+    pub after: Option<i64>,
+
+    /// Only display the activities started before this UTC date (e.g.
+    /// 2025-01-01 or 2025-01-01T08:00:00Z).
+    #[arg(long, value_parser = parse_timestamp)]
+    pub before: Option<i64>,
+
+    /// Gear (bike(s) or shoes) to display the activities of, by ID (e.g.
+    /// b1234567) or by name as in the athlete profile (only by ID in offline
+    /// mode).
+    #[arg(long, value_delimiter = ',')]
+    pub gear: Vec<String>,
 }
 
 /// Parameters to load GPX files.
@@ -106,3 +311,9 @@ pub struct GeoJsonParams {
     #[arg(long = "file", short = 'f', required = true, value_delimiter = ',')]
     pub files: Vec<String>,
 }
+
+/// Parses a date or date and time into a Unix timestamp, for the command-line
+/// arguments.
+fn parse_timestamp(date: &str) -> Result<i64, String> {
+    parse_date_or_date_time(date).map_err(|e| e.to_string())
+}
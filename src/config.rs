@@ -1,12 +1,16 @@
 //! Configuration utilities.
 
-use anyhow::Context;
+use crate::map::tiles::TileIndex;
+use crate::map::url_template::UrlTemplate;
+use anyhow::{bail, Context};
 use clap::builder;
 use clap::error::ErrorKind;
+use image::ImageFormat;
 use serde::Deserialize;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Path to the font to use when displaying text on the UI.
 pub const FONT_PATH: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSansMono-Bold.ttf";
@@ -14,28 +18,225 @@ pub const FONT_PATH: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSansMono-Bol
 /// Description of a web service providing tiles.
 #[derive(Clone, Debug, Deserialize)]
 pub struct MapProvider {
+    /// Template of the URL of each tile, e.g.
+    /// `https://{s}.example.com/{z}/{x}/{y}{r}.png`. See [`UrlTemplate`] for
+    /// the supported placeholders.
+    ///
+    /// Takes precedence over the `server` and `extension` fields.
+    #[serde(default)]
+    pub url: Option<UrlTemplate>,
+    /// Subdomains to rotate between for the `{s}` placeholder of the `url`.
+    #[serde(default = "MapProvider::default_subdomains")]
+    pub subdomains: Vec<String>,
+    /// Whether to request high-resolution tiles, replacing the `{r}`
+    /// placeholder of the `url` by `@2x`.
+    #[serde(default)]
+    pub retina: bool,
     /// Address of the HTTPS tile server, including the domain name and any
     /// sub-directories.
-    pub server: String,
+    ///
+    /// Tiles are requested at `https://{server}/{z}/{x}/{y}{extension}`. This
+    /// is ignored if a `url` template is set.
+    #[serde(default)]
+    pub server: Option<String>,
+    /// Local source of pre-rendered tiles, read instead of requesting tiles
+    /// over HTTP. Takes precedence over the `url` and `server` fields.
+    ///
+    /// Tiles read from a local source aren't copied to the cache.
+    #[serde(default)]
+    pub local: Option<LocalSource>,
     /// Local sub-folder (relative to the root `--cache-directory`) where tiles
     /// for this provider should be cached.
     pub cache_folder: String,
-    /// File extension to append to each tile request.
+    /// File extension to append to each tile request, when using the `server`
+    /// field.
     ///
     /// A simple example is `.png`. Additionally, some services may require an
     /// access token parameter, provide higher-resolution tiles under a `@2x`
     /// suffix, etc.
-    ///
-    /// Note: the current implementation only supports tiles in PNG format.
+    #[serde(default)]
     pub extension: String,
+    /// Image format of the tiles served by this provider.
+    ///
+    /// If unset, the format is detected from the `Content-Type` header of each
+    /// response, or from the first bytes of the image.
+    #[serde(default)]
+    pub format: Option<TileFormat>,
+    /// Whether this provider serves an overlay (e.g. hillshading or contour
+    /// lines) drawn on top of the base map, rather than a base map.
+    #[serde(default)]
+    pub overlay: bool,
+    /// Opacity at which to draw the tiles, between 0.0 and 1.0.
+    #[serde(default = "MapProvider::default_opacity")]
+    pub opacity: f32,
     /// Referer HTTP header to attach to each tile request.
     pub referer: Option<String>,
     /// User-agent HTTP header to attach to each tile request.
     pub user_agent: Option<String>,
+    /// Maximum number of tile requests to send per second to this provider.
+    /// Unlimited if unset.
+    #[serde(default)]
+    pub max_requests_per_second: Option<f32>,
+}
+
+/// Local source of pre-rendered map tiles.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LocalSource {
+    /// Directory tree where each tile is stored at `{z}/{x}/{y}.{extension}`,
+    /// with the extension of any supported format.
+    Directory {
+        /// Root directory of the tree.
+        path: PathBuf,
+    },
+    /// [MBTiles](https://github.com/mapbox/mbtiles-spec) SQLite database of
+    /// raster tiles.
+    Mbtiles {
+        /// Path to the database file.
+        path: PathBuf,
+    },
+    /// [PMTiles](https://github.com/protomaps/PMTiles) (version 3) archive of
+    /// raster tiles.
+    Pmtiles {
+        /// Path to the archive file.
+        path: PathBuf,
+    },
+}
+
+/// Image format of a map tile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileFormat {
+    /// Portable Network Graphics.
+    Png,
+    /// JPEG, typically used by satellite and aerial imagery providers.
+    Jpeg,
+    /// WebP.
+    Webp,
+}
+
+impl TileFormat {
+    /// All the supported tile formats.
+    pub const ALL: [TileFormat; 3] = [TileFormat::Png, TileFormat::Jpeg, TileFormat::Webp];
+
+    /// Returns the file extension used to store tiles of this format, without
+    /// the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TileFormat::Png => "png",
+            TileFormat::Jpeg => "jpg",
+            TileFormat::Webp => "webp",
+        }
+    }
+
+    /// Returns the corresponding format of the [`image`] crate.
+    pub fn image_format(&self) -> ImageFormat {
+        match self {
+            TileFormat::Png => ImageFormat::Png,
+            TileFormat::Jpeg => ImageFormat::Jpeg,
+            TileFormat::Webp => ImageFormat::WebP,
+        }
+    }
+
+    /// Parses a tile format from the value of a `Content-Type` HTTP header,
+    /// ignoring any parameters.
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        let essence = mime_type.split(';').next()?.trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "image/png" => Some(TileFormat::Png),
+            "image/jpeg" | "image/jpg" => Some(TileFormat::Jpeg),
+            "image/webp" => Some(TileFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// Detects the tile format from the magic bytes at the start of the given
+    /// image data.
+    pub fn from_magic_bytes(bytes: &[u8]) -> Option<Self> {
+        match image::guess_format(bytes) {
+            Ok(ImageFormat::Png) => Some(TileFormat::Png),
+            Ok(ImageFormat::Jpeg) => Some(TileFormat::Jpeg),
+            Ok(ImageFormat::WebP) => Some(TileFormat::Webp),
+            _ => None,
+        }
+    }
 }
 
 impl MapProvider {
-    /// Reads a map provider configuration from the given JSON file.
+    /// Suffix replacing the `{r}` placeholder when retina tiles are enabled.
+    const RETINA_SUFFIX: &'static str = "@2x";
+
+    /// Default subdomains for the `{s}` placeholder.
+    fn default_subdomains() -> Vec<String> {
+        vec!["a".to_owned(), "b".to_owned(), "c".to_owned()]
+    }
+
+    /// Default opacity of the tiles.
+    fn default_opacity() -> f32 {
+        1.0
+    }
+
+    /// Returns the URL to request the given tile from.
+    pub fn tile_url(&self, index: &TileIndex) -> String {
+        match &self.url {
+            Some(url) => {
+                let retina = if self.retina { Self::RETINA_SUFFIX } else { "" };
+                url.expand(index, &self.subdomains, retina)
+            }
+            None => format!(
+                "https://{server}/{z}/{x}/{y}{extension}",
+                server = self.server.as_deref().unwrap_or_default(),
+                z = index.z,
+                x = index.x,
+                y = index.y,
+                extension = self.extension
+            ),
+        }
+    }
+
+    /// Checks that the configuration describes a usable tile server or local
+    /// source.
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.opacity) {
+            bail!(
+                "The `opacity` must be between 0.0 and 1.0, found: {}",
+                self.opacity
+            );
+        }
+        if let Some(rps) = self.max_requests_per_second {
+            if !(rps > 0.0 && rps.is_finite()) {
+                bail!("The `max_requests_per_second` must be positive, found: {rps}");
+            }
+        }
+        if self.local.is_some() {
+            return Ok(());
+        }
+        match (&self.url, &self.server) {
+            (None, None) => {
+                bail!("Either a `url` template, a `server` or a `local` source must be configured")
+            }
+            (Some(url), _) => {
+                if url.has_subdomain() && self.subdomains.is_empty() {
+                    bail!("The `url` template contains {{s}} but no `subdomains` are configured");
+                }
+                Ok(())
+            }
+            (None, Some(_)) => Ok(()),
+        }
+    }
+}
+
+/// List of map providers, between which the UI can switch at runtime.
+#[derive(Clone, Debug)]
+pub struct MapProviders {
+    /// Configured providers. The first base map is displayed initially, and
+    /// overlays are drawn on top of the base map in order.
+    pub providers: Vec<MapProvider>,
+}
+
+impl MapProviders {
+    /// Reads a map provider configuration from the given JSON file, which
+    /// contains either a single provider or a list of providers.
     fn read_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let path = path.as_ref();
         let file = File::open(path).with_context(|| {
@@ -45,24 +246,61 @@ impl MapProvider {
             )
         })?;
         let reader = BufReader::new(file);
-        let provider: Self = serde_json::from_reader(reader).with_context(|| {
+        let value: serde_json::Value = serde_json::from_reader(reader).with_context(|| {
+            format!(
+                "Failed to parse map provider configuration from: {}",
+                path.display()
+            )
+        })?;
+        let providers: Vec<MapProvider> = if value.is_array() {
+            serde_json::from_value(value)
+        } else {
+            serde_json::from_value(value).map(|provider| vec![provider])
+        }
+        .with_context(|| {
             format!(
                 "Failed to parse map provider configuration from: {}",
                 path.display()
             )
         })?;
 
-        Ok(provider)
+        let map_providers = Self { providers };
+        map_providers.validate().with_context(|| {
+            format!("Invalid map provider configuration in: {}", path.display())
+        })?;
+
+        Ok(map_providers)
+    }
+
+    /// Checks that the list contains a base map and that each provider is
+    /// usable and cached in its own folder.
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.providers.iter().all(|provider| provider.overlay) {
+            bail!("At least one map provider must be a base map (i.e. not an overlay)");
+        }
+        let mut cache_folders = HashSet::new();
+        for (i, provider) in self.providers.iter().enumerate() {
+            provider
+                .validate()
+                .with_context(|| format!("Invalid map provider #{i}"))?;
+            if !cache_folders.insert(provider.cache_folder.as_str()) {
+                bail!(
+                    "Map provider #{i} uses the same `cache_folder` as another provider: {}",
+                    provider.cache_folder
+                );
+            }
+        }
+        Ok(())
     }
 }
 
-/// Helper struct to parse a [`MapProvider`] configuration directly from a Clap
+/// Helper struct to parse a [`MapProviders`] configuration directly from a Clap
 /// argument.
 #[derive(Clone)]
 pub struct MapProviderParser;
 
 impl builder::TypedValueParser for MapProviderParser {
-    type Value = MapProvider;
+    type Value = MapProviders;
 
     fn parse_ref(
         &self,
@@ -70,7 +308,7 @@ impl builder::TypedValueParser for MapProviderParser {
         arg: Option<&clap::Arg>,
         value: &std::ffi::OsStr,
     ) -> Result<Self::Value, clap::Error> {
-        MapProvider::read_from_file(value).map_err(|e| {
+        MapProviders::read_from_file(value).map_err(|e| {
             let arg_str = arg.map(|a| a.to_string());
             // TODO: use clap::builder::StyledStr once it supports coloring the arguments.
             let msg = format!(
@@ -87,10 +325,49 @@ impl builder::TypedValueParser for MapProviderParser {
     }
 }
 
-impl builder::ValueParserFactory for MapProvider {
+impl builder::ValueParserFactory for MapProviders {
     type Parser = MapProviderParser;
 
     fn value_parser() -> Self::Parser {
         MapProviderParser
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tile_format_from_mime_type() {
+        assert_eq!(
+            TileFormat::from_mime_type("image/png"),
+            Some(TileFormat::Png)
+        );
+        assert_eq!(
+            TileFormat::from_mime_type("image/JPEG; charset=binary"),
+            Some(TileFormat::Jpeg)
+        );
+        assert_eq!(
+            TileFormat::from_mime_type("image/webp"),
+            Some(TileFormat::Webp)
+        );
+        assert_eq!(TileFormat::from_mime_type("text/html"), None);
+    }
+
+    #[test]
+    fn tile_format_from_magic_bytes() {
+        assert_eq!(
+            TileFormat::from_magic_bytes(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some(TileFormat::Png)
+        );
+        assert_eq!(
+            TileFormat::from_magic_bytes(b"\xff\xd8\xff\xe0\0\x10JFIF\0"),
+            Some(TileFormat::Jpeg)
+        );
+        assert_eq!(
+            TileFormat::from_magic_bytes(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(TileFormat::Webp)
+        );
+        assert_eq!(TileFormat::from_magic_bytes(b"<html></html>"), None);
+    }
+}
@@ -10,42 +10,93 @@ mod map;
 mod tracks;
 mod ui;
 
-use anyhow::Context;
-use caching::cache::Cache;
+use anyhow::{bail, Context};
+use caching::cache::{Cache, CacheAccess, TileCacheBackend, TileCacheParams};
 use clap::Parser;
-use cli::{Cli, StravaParams, TrackParams};
+use cli::{
+    CacheCommand, Cli, Command, PrefetchArea, PrefetchParams, PruneParams, StravaParams,
+    TrackParams,
+};
 use config::MapProvider;
 use futures::channel::oneshot;
 use futures::future::FutureExt;
-use futures::{future, join, select, StreamExt};
+use futures::{future, join, select, stream, StreamExt};
 use log::{debug, error, info, warn};
+use map::prefetch::{prefetch_tiles, Area};
+use map::tile_box::TileBox;
 use map::tile_channel::{tile_channel, TileRequestReceiver};
 use map::tiles::Tiles;
+use std::collections::BTreeSet;
+use std::fmt::Display;
 use std::sync::mpsc::{channel, Sender};
 use std::thread;
+use std::time::Duration;
 use tokio::runtime::Runtime;
-use tracks::strava::StravaClient;
+use tracks::polyline::Point;
+use tracks::schema::{ActivityType, DetailedAthlete, SportType, SummaryGear};
+use tracks::strava::{self, ActivityFilter, StravaClient};
 use tracks::{geojson, gpx};
 use ui::window::Window;
-use ui::UiMessage;
+use ui::{LayerParams, TileParams, UiMessage};
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let Cli {
-        track_params,
-        map_provider,
+        command,
+        map_providers,
         cache_directory,
+        tile_cache,
+        tile_max_age_days,
+        max_tile_cache_mb,
         lazy_ui_refresh,
         speculative_tile_load,
         background_ui_thread,
+        offline,
         parallel_requests,
         max_pixels_per_tile,
         max_tile_level,
     } = Cli::parse();
 
+    let tile_cache_params = TileCacheParams {
+        backend: tile_cache,
+        max_age: Duration::from_secs(tile_max_age_days as u64 * 24 * 3600),
+        max_size: max_tile_cache_mb.map(|mb| mb * 1024 * 1024),
+    };
+
+    let track_params = match command {
+        None => None,
+        Some(Command::Tracks(track_params)) => Some(track_params),
+        Some(Command::Prefetch(_)) if offline => {
+            bail!("Tiles cannot be prefetched in offline mode");
+        }
+        Some(Command::Prefetch(prefetch_params)) => {
+            return run_prefetch_command(
+                prefetch_params,
+                cache_directory.as_deref(),
+                &map_providers.providers,
+                tile_cache_params,
+                max_tile_level as u32,
+                parallel_requests as usize,
+            );
+        }
+        Some(Command::Cache(cache_command)) => {
+            return run_cache_command(
+                cache_command,
+                cache_directory.as_deref(),
+                &map_providers.providers,
+                tile_cache_params,
+            );
+        }
+    };
+
     let cache: Option<Cache> = match &cache_directory {
-        Some(dir) => match Cache::new(dir, &map_provider) {
+        Some(dir) => match Cache::new(
+            dir,
+            &map_providers.providers,
+            tile_cache_params,
+            CacheAccess::Shared,
+        ) {
             Ok(c) => Some(c),
             Err(e) => {
                 error!("Couldn't create cache: {e:?}");
@@ -57,11 +108,27 @@ fn main() -> anyhow::Result<()> {
             None
         }
     };
+    if offline && cache.is_none() {
+        warn!("Offline mode without a cache: only the local map sources are available.");
+    }
 
     // Separate threads for GUI and network.
     let (cancel_tx, cancel_rx) = oneshot::channel();
     let (ui_tx, ui_rx) = channel();
     let (tiles_tx, tiles_rx) = tile_channel();
+    let tile_params = TileParams {
+        layers: map_providers
+            .providers
+            .iter()
+            .map(|provider| LayerParams {
+                overlay: provider.overlay,
+                opacity: provider.opacity,
+            })
+            .collect(),
+        speculative_tile_load,
+        max_pixels_per_tile: max_pixels_per_tile as usize,
+        max_tile_level,
+    };
 
     let network = thread::spawn(move || {
         // Create the Tokio runtime.
@@ -75,8 +142,9 @@ fn main() -> anyhow::Result<()> {
                     ui_tx,
                     tiles_rx,
                     cache.as_ref(),
-                    &map_provider,
+                    &map_providers.providers,
                     track_params.as_ref(),
+                    offline,
                     parallel_requests as usize,
                 ).fuse() => res,
             )
@@ -93,30 +161,14 @@ fn main() -> anyhow::Result<()> {
         // really, absolutely need to create an EventLoop on a different thread, please
         // use the `EventLoopExtUnix::new_any_thread` function.'
         let ui = thread::spawn(move || {
-            match Window::ui_loop(
-                ui_rx,
-                cancel_tx,
-                tiles_tx,
-                lazy_ui_refresh,
-                speculative_tile_load,
-                max_pixels_per_tile as usize,
-                max_tile_level,
-            ) {
+            match Window::ui_loop(ui_rx, cancel_tx, tiles_tx, lazy_ui_refresh, tile_params) {
                 Ok(()) => info!("End of UI thread"),
                 Err(e) => error!("Failed to run UI thread: {e:?}"),
             }
         });
         ui.join().unwrap();
     } else {
-        match Window::ui_loop(
-            ui_rx,
-            cancel_tx,
-            tiles_tx,
-            lazy_ui_refresh,
-            speculative_tile_load,
-            max_pixels_per_tile as usize,
-            max_tile_level,
-        ) {
+        match Window::ui_loop(ui_rx, cancel_tx, tiles_tx, lazy_ui_refresh, tile_params) {
             Ok(()) => info!("End of UI thread"),
             Err(e) => error!("Failed to run UI thread: {e:?}"),
         }
@@ -127,27 +179,430 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Runs the given sub-command to manage the cache directory.
+fn run_cache_command(
+    cache_command: CacheCommand,
+    cache_directory: Option<&str>,
+    map_providers: &[MapProvider],
+    tile_cache_params: TileCacheParams,
+) -> anyhow::Result<()> {
+    let cache_directory =
+        cache_directory.context("A cache must be configured with --cache-directory")?;
+    match cache_command {
+        CacheCommand::Migrate(migrate_params) => {
+            let cache = Cache::new(
+                cache_directory,
+                map_providers,
+                TileCacheParams {
+                    backend: TileCacheBackend::Mbtiles,
+                    ..tile_cache_params
+                },
+                CacheAccess::Exclusive,
+            )
+            .context("Failed to create the MBTiles cache")?;
+            for map_provider in map_providers {
+                let count = cache
+                    .import_directory(map_provider, migrate_params.delete)
+                    .with_context(|| {
+                        format!(
+                            "Failed to import the directory cache of provider: {}",
+                            map_provider.cache_folder
+                        )
+                    })?;
+                println!(
+                    "Imported {count} tiles into the MBTiles cache of provider: {}",
+                    map_provider.cache_folder
+                );
+            }
+            Ok(())
+        }
+        CacheCommand::Stats => {
+            let cache = Cache::new(
+                cache_directory,
+                map_providers,
+                tile_cache_params,
+                CacheAccess::Shared,
+            )
+            .context("Failed to open the cache")?;
+            for map_provider in map_providers {
+                let stats = cache.tile_stats(map_provider).with_context(|| {
+                    format!(
+                        "Failed to read the tile cache of provider: {}",
+                        map_provider.cache_folder
+                    )
+                })?;
+                println!(
+                    "Provider {}: {} tiles, {} of data, {} on disk",
+                    map_provider.cache_folder,
+                    stats.count_by_zoom.values().sum::<usize>(),
+                    format_size(stats.data_size),
+                    format_size(stats.disk_size)
+                );
+                for (z, count) in &stats.count_by_zoom {
+                    println!("  Zoom level {z:2}: {count} tiles");
+                }
+            }
+            let stats = cache
+                .activity_stats()
+                .context("Failed to read the Strava activity cache")?;
+            println!(
+                "Strava activities: {}, {} on disk",
+                stats.count,
+                format_size(stats.disk_size)
+            );
+            Ok(())
+        }
+        CacheCommand::Prune(prune_params) => run_prune_command(
+            prune_params,
+            cache_directory,
+            map_providers,
+            tile_cache_params,
+        ),
+        CacheCommand::Verify(verify_params) => {
+            // Deleting corrupt entries mustn't race with other instances writing
+            // them again.
+            let access = if verify_params.delete {
+                CacheAccess::Exclusive
+            } else {
+                CacheAccess::Shared
+            };
+            let cache = Cache::new(cache_directory, map_providers, tile_cache_params, access)
+                .context("Failed to open the cache")?;
+            let mut corrupt_count = 0;
+            for map_provider in map_providers {
+                let corrupt = cache.verify_tiles(map_provider).with_context(|| {
+                    format!(
+                        "Failed to verify the tile cache of provider: {}",
+                        map_provider.cache_folder
+                    )
+                })?;
+                for (index, e) in &corrupt {
+                    println!(
+                        "Corrupt tile {index:?} of provider {}: {e:#}",
+                        map_provider.cache_folder
+                    );
+                }
+                corrupt_count += corrupt.len();
+                if verify_params.delete && !corrupt.is_empty() {
+                    cache.delete_tiles(
+                        map_provider,
+                        corrupt.into_iter().map(|(index, _)| index).collect(),
+                    )?;
+                }
+            }
+
+            let corrupt = cache
+                .verify_activities()
+                .context("Failed to verify the Strava activity cache")?;
+            for (id, e) in &corrupt {
+                println!("Corrupt Strava activity {id}: {e:#}");
+            }
+            corrupt_count += corrupt.len();
+            if verify_params.delete {
+                for (id, _) in corrupt {
+                    cache.delete_activity(id)?;
+                }
+            }
+
+            let corrupt = cache
+                .verify_activity_streams()
+                .context("Failed to verify the Strava activity streams cache")?;
+            for (id, e) in &corrupt {
+                println!("Corrupt streams of Strava activity {id}: {e:#}");
+            }
+            corrupt_count += corrupt.len();
+            if verify_params.delete {
+                for (id, _) in corrupt {
+                    cache.delete_activity_streams(id)?;
+                }
+            }
+
+            if let Some(e) = cache.verify_activity_index() {
+                println!("Corrupt Strava activity index: {e:#}");
+                corrupt_count += 1;
+                if verify_params.delete {
+                    cache.delete_activity_index()?;
+                }
+            }
+
+            let temp_files = cache
+                .list_stale_temp_files()
+                .context("Failed to list the temporary files of the cache")?;
+            for path in &temp_files {
+                println!("Leftover temporary file: {}", path.display());
+            }
+            corrupt_count += temp_files.len();
+            if verify_params.delete {
+                cache.delete_temp_files(&temp_files)?;
+            }
+
+            if corrupt_count == 0 {
+                println!("No corrupt entries found");
+            } else if verify_params.delete {
+                println!("Deleted {corrupt_count} corrupt entries");
+            } else {
+                bail!("Found {corrupt_count} corrupt entries, run the command again with --delete to delete them");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Runs the sub-command to delete tiles from the cache directory.
+fn run_prune_command(
+    params: PruneParams,
+    cache_directory: &str,
+    map_providers: &[MapProvider],
+    tile_cache_params: TileCacheParams,
+) -> anyhow::Result<()> {
+    let PruneParams {
+        older_than_days,
+        min_zoom,
+        max_zoom,
+        bbox,
+        max_size_mb,
+    } = params;
+    let selective =
+        older_than_days.is_some() || min_zoom.is_some() || max_zoom.is_some() || bbox.is_some();
+    if !selective && max_size_mb.is_none() {
+        bail!("No tiles to prune: please pass at least one of --older-than-days, --min-zoom, --max-zoom, --bbox or --max-size-mb");
+    }
+    if let (Some(min_zoom), Some(max_zoom)) = (min_zoom, max_zoom) {
+        if min_zoom > max_zoom {
+            bail!("The minimum zoom level ({min_zoom}) must be at most the maximum zoom level ({max_zoom})");
+        }
+    }
+
+    let cache = Cache::new(
+        cache_directory,
+        map_providers,
+        TileCacheParams {
+            max_size: max_size_mb.map(|mb| mb * 1024 * 1024),
+            ..tile_cache_params
+        },
+        CacheAccess::Exclusive,
+    )
+    .context("Failed to open the cache")?;
+
+    if selective {
+        let max_age = older_than_days.map(|days| Duration::from_secs(days as u64 * 24 * 3600));
+        let corners = bbox.map(|bbox| bbox.mercator_corners());
+        for map_provider in map_providers {
+            let stats = cache
+                .prune_tiles_matching(map_provider, |index, metadata| {
+                    max_age.is_none_or(|max_age| metadata.age() > max_age)
+                        && min_zoom.is_none_or(|min_zoom| index.z >= min_zoom)
+                        && max_zoom.is_none_or(|max_zoom| index.z <= max_zoom)
+                        && corners.is_none_or(|(min, max)| {
+                            TileBox::covering(index.z, min, max).contains(index)
+                        })
+                })
+                .with_context(|| {
+                    format!(
+                        "Failed to prune the tile cache of provider: {}",
+                        map_provider.cache_folder
+                    )
+                })?;
+            println!(
+                "Deleted {} tiles ({}) of provider: {}",
+                stats.count,
+                format_size(stats.size),
+                map_provider.cache_folder
+            );
+        }
+    }
+
+    if max_size_mb.is_some() {
+        let stats = cache
+            .prune_tiles()
+            .context("Failed to prune the tile cache")?;
+        println!(
+            "Deleted {} least recently used tiles ({})",
+            stats.count,
+            format_size(stats.size)
+        );
+    }
+    Ok(())
+}
+
+/// Formats the given size in bytes as megabytes, for display.
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Runs the sub-command to prefetch the map tiles of an area into the cache.
+fn run_prefetch_command(
+    params: PrefetchParams,
+    cache_directory: Option<&str>,
+    map_providers: &[MapProvider],
+    tile_cache_params: TileCacheParams,
+    max_tile_level: u32,
+    parallel_requests: usize,
+) -> anyhow::Result<()> {
+    let cache_directory =
+        cache_directory.context("A cache must be configured with --cache-directory")?;
+    let cache = Cache::new(
+        cache_directory,
+        map_providers,
+        tile_cache_params,
+        CacheAccess::Shared,
+    )
+    .context("Failed to create the cache")?;
+
+    let layers: Vec<usize> = if params.layers.is_empty() {
+        (0..map_providers.len())
+            .filter(|&layer| map_providers[layer].local.is_none())
+            .collect()
+    } else {
+        params.layers.clone()
+    };
+    for &layer in &layers {
+        let map_provider = map_providers
+            .get(layer)
+            .with_context(|| format!("Invalid map layer #{layer}"))?;
+        if map_provider.local.is_some() {
+            bail!("Map layer #{layer} has a local source, its tiles can't be prefetched");
+        }
+    }
+
+    let min_zoom = params.min_zoom;
+    let max_zoom = params.max_zoom.unwrap_or(max_tile_level);
+    if min_zoom > max_zoom {
+        bail!("The minimum zoom level ({min_zoom}) is larger than the maximum ({max_zoom})");
+    }
+
+    let client = reqwest::Client::new();
+    let rt = Runtime::new().context("Failed to create the Tokio runtime")?;
+    rt.block_on(async {
+        let area = match &params.area {
+            PrefetchArea::Bbox { bbox } => Area::from_bbox(bbox),
+            PrefetchArea::Corridor {
+                buffer_meters,
+                tracks,
+            } => Area::Corridor {
+                tracks: load_track_points(Some(&cache), &client, tracks, parallel_requests)
+                    .await?,
+                buffer_meters: *buffer_meters,
+            },
+            PrefetchArea::Extent { tracks } => Area::extent(
+                &load_track_points(Some(&cache), &client, tracks, parallel_requests).await?,
+            )
+            .context("No track points to prefetch around")?,
+        };
+
+        let mut count = 0;
+        for z in min_zoom..=max_zoom {
+            let tiles = area.tile_count(z);
+            println!("Zoom level {z}: {tiles} tiles");
+            count += tiles;
+        }
+        let total = count * layers.len();
+        println!(
+            "{total} tiles to prefetch ({count} per map layer, for {} map layers)",
+            layers.len()
+        );
+        if params.dry_run {
+            return Ok(());
+        }
+        if total > params.max_tiles {
+            bail!(
+                "Too many tiles to prefetch ({total} > {}), please reduce the area or the zoom levels, or raise the --max-tiles limit",
+                params.max_tiles
+            );
+        }
+
+        let mut indices = BTreeSet::new();
+        for z in min_zoom..=max_zoom {
+            indices.extend(area.tile_indices(z));
+        }
+
+        // The tiles are only written to the cache, not displayed.
+        let (ui_tx, _ui_rx) = channel();
+        let tiles = Tiles::new(map_providers, Some(&cache), &client, &ui_tx, false)?;
+        let stats = prefetch_tiles(&tiles, &layers, &indices, parallel_requests).await;
+        println!(
+            "Downloaded {} tiles, {} were already cached, {} are missing on the server, {} failed",
+            stats.downloaded, stats.cached, stats.missing, stats.failed
+        );
+        if stats.failed > 0 {
+            bail!("Failed to prefetch {} tiles, please run the command again to retry them", stats.failed);
+        }
+        Ok(())
+    })
+}
+
+/// Asynchronous function loading the given tracks, returning their points in
+/// Mercator coordinates.
+async fn load_track_points(
+    cache: Option<&Cache>,
+    client: &reqwest::Client,
+    track_params: &TrackParams,
+    parallel_requests: usize,
+) -> anyhow::Result<Vec<Vec<Point<f64>>>> {
+    let (ui_tx, ui_rx) = channel();
+    fetch_tracks(
+        &ui_tx,
+        cache,
+        client,
+        Some(track_params),
+        false,
+        parallel_requests,
+    )
+    .await?;
+    let tracks: Vec<_> = ui_rx
+        .try_iter()
+        .filter_map(|msg| match msg {
+            UiMessage::Activity { points, .. } => Some(points),
+            _ => None,
+        })
+        .collect();
+    println!("Loaded {} tracks", tracks.len());
+    Ok(tracks)
+}
+
 /// Asynchronous loop fetching data from the network (tiles, tracks) and sending
 /// it to the UI thread via a channel.
 ///
+/// In offline mode, the data is only read from local sources and the cache.
+///
 /// This is invoked with a Tokio runtime in a background thread by the main
 /// function.
 async fn tokio_loop(
     ui_tx: Sender<UiMessage>,
     tiles_rx: TileRequestReceiver,
     cache: Option<&Cache>,
-    map_provider: &MapProvider,
+    map_providers: &[MapProvider],
     track_params: Option<&TrackParams>,
+    offline: bool,
     parallel_requests: usize,
 ) -> anyhow::Result<()> {
     let client = reqwest::Client::new();
 
-    let tiles = Tiles::new(map_provider, cache, &client, &ui_tx);
+    // Enforce the maximum size of the tile cache without delaying the start.
+    if let Some(cache) = cache {
+        cache.spawn_prune_tiles();
+    }
 
-    let (a, b) = join!(
-        tiles.query_loop(tiles_rx, parallel_requests),
-        fetch_tracks(&ui_tx, cache, &client, track_params, parallel_requests)
-    );
+    let tiles = Tiles::new(map_providers, cache, &client, &ui_tx, offline)?;
+
+    let (a, b) = join!(tiles.query_loop(tiles_rx, parallel_requests), async {
+        let result = fetch_tracks(
+            &ui_tx,
+            cache,
+            &client,
+            track_params,
+            offline,
+            parallel_requests,
+        )
+        .await;
+        if let Err(e) = &result {
+            error!("Failed to fetch tracks: {e:?}");
+            ui_tx.send(UiMessage::TrackError {
+                error: format!("{e:#}"),
+            })?;
+        }
+        result
+    });
 
     match (a, b) {
         (Ok(()), Ok(())) => Ok(()),
@@ -158,15 +613,41 @@ async fn tokio_loop(
 
 /// Asynchronous function fetching tracks based on the given parameters, and
 /// sending them to the UI thread.
+///
+/// In offline mode, Strava activities are only read from the cache.
 async fn fetch_tracks(
     ui_tx: &Sender<UiMessage>,
     cache: Option<&Cache>,
     client: &reqwest::Client,
     track_params: Option<&TrackParams>,
+    offline: bool,
     parallel_requests: usize,
 ) -> anyhow::Result<()> {
     match track_params {
         None => Ok(()),
+        Some(TrackParams::Strava(strava_params)) if offline => {
+            let cache = cache.context("Offline mode requires a cache to read Strava activities")?;
+            if strava_params.routes || strava_params.starred_segments {
+                warn!("Strava routes and segments aren't available in offline mode");
+            }
+            // The athlete's gear isn't available to resolve names offline.
+            if let Some(name) = strava_params
+                .gear
+                .iter()
+                .find(|id_or_name| !SummaryGear::is_id(id_or_name))
+            {
+                bail!("Gear {name:?} can't be selected by name in offline mode, please select it by ID (e.g. b1234567) instead");
+            }
+            strava::get_cached_activities_parallel(
+                ui_tx,
+                cache,
+                &activity_filter(strava_params),
+                strava_params.activity_count as usize,
+                parallel_requests,
+            )
+            .await
+            .context("Failed to read Strava activities from the cache")
+        }
         Some(TrackParams::Strava(strava_params)) => {
             fetch_strava_activities(ui_tx, cache, client, strava_params, parallel_requests).await
         }
@@ -179,6 +660,65 @@ async fn fetch_tracks(
     }
 }
 
+/// Returns the filter selecting the Strava activities to display, based on the
+/// given parameters.
+///
+/// The gear is selected by ID, use [`resolve_gear`] to select it by name.
+fn activity_filter(strava_params: &StravaParams) -> ActivityFilter {
+    warn_unknown_types(
+        "activity type",
+        &strava_params.activity_types,
+        ActivityType::KNOWN,
+    );
+    warn_unknown_types("sport type", &strava_params.sport_types, SportType::KNOWN);
+    ActivityFilter {
+        activity_types: strava_params.activity_types.clone(),
+        sport_types: strava_params.sport_types.clone(),
+        after: strava_params.after,
+        before: strava_params.before,
+        gear_ids: strava_params.gear.clone(),
+    }
+}
+
+/// Warns about the given Strava activity or sport types that are unknown to
+/// this version of Ridemap (e.g. because of a typo), as filtering by them
+/// likely hides all the activities.
+fn warn_unknown_types<T: PartialEq + Display>(kind: &str, types: &[T], known: &[T]) {
+    for unknown in types.iter().filter(|t| !known.contains(t)) {
+        let unknown = unknown.to_string();
+        match known
+            .iter()
+            .find(|t| t.to_string().eq_ignore_ascii_case(&unknown))
+        {
+            Some(suggestion) => warn!("Unknown {kind} {unknown:?}, did you mean \"{suggestion}\"?"),
+            None => warn!(
+                "Unknown {kind} {unknown:?}, only activities of this exact type will be displayed"
+            ),
+        }
+    }
+}
+
+/// Resolves the given gear IDs or names into the IDs of the gear of the given
+/// athlete.
+fn resolve_gear(athlete: &DetailedAthlete, gear: &[String]) -> anyhow::Result<Vec<String>> {
+    gear.iter()
+        .map(|id_or_name| match athlete.find_gear(id_or_name) {
+            Some(found) => {
+                debug!("Selected gear {id_or_name:?} = {}", found.id);
+                Ok(found.id.clone())
+            }
+            None => {
+                let available = athlete
+                    .gear()
+                    .map(|g| format!("{} ({})", g.id, g.name))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                bail!("Unknown gear {id_or_name:?}, available gear: {available}")
+            }
+        })
+        .collect()
+}
+
 /// Asynchronous function fetching Strava activities based on the given
 /// parameters, and sending them to the UI thread.
 async fn fetch_strava_activities(
@@ -204,26 +744,63 @@ async fn fetch_strava_activities(
         .context("Failed to get Strava athlete information")?;
     debug!("Athlete = {athlete:#?}");
 
+    let mut filter = activity_filter(strava_params);
+    filter.gear_ids = resolve_gear(&athlete, &strava_params.gear)?;
+
     // List activities.
-    let activity_stream = strava.get_activity_list(
-        strava_params.activities_per_page as usize,
-        strava_params.activity_pages as usize,
-        parallel_requests,
-    );
+    let activity_list = strava
+        .sync_activity_index(
+            strava_params.activities_per_page as usize,
+            strava_params.activity_pages as usize,
+            strava_params.backfill,
+            &filter,
+            parallel_requests,
+        )
+        .await
+        .context("Failed to list Strava activities")?;
 
-    let activities = activity_stream
+    let activities = stream::iter(activity_list)
         .filter(|a| {
-            future::ready(
-                strava_params.activity_types.is_empty()
-                    || strava_params.activity_types.contains(&a.r#type),
-            )
+            future::ready(filter.matches(
+                &a.r#type,
+                &a.sport_type(),
+                Some(&a.start_date),
+                a.gear_id.as_deref(),
+            ))
         })
         .take(strava_params.activity_count as usize);
 
     strava
-        .get_detailed_activities_parallel(ui_tx, activities, parallel_requests)
+        .get_detailed_activities_parallel(
+            ui_tx,
+            activities,
+            strava_params.fetch_streams,
+            parallel_requests,
+        )
         .await
         .context("Failed to fetch Strava activities")?;
 
+    if strava_params.routes {
+        strava
+            .send_routes(
+                ui_tx,
+                athlete.id,
+                strava_params.activities_per_page as usize,
+            )
+            .await
+            .context("Failed to fetch Strava routes")?;
+    }
+    if strava_params.starred_segments {
+        strava
+            .send_starred_segments(
+                ui_tx,
+                strava_params.activities_per_page as usize,
+                parallel_requests,
+            )
+            .await
+            .context("Failed to fetch Strava starred segments")?;
+    }
+    strava.log_rate_limit_budget();
+
     Ok(())
 }
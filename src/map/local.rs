@@ -0,0 +1,69 @@
+//! Local sources of pre-rendered tiles, for offline use.
+
+use super::mbtiles::Mbtiles;
+use super::pmtiles::Pmtiles;
+use super::tiles::TileIndex;
+use crate::config::{LocalSource, TileFormat};
+use anyhow::{bail, Context};
+use std::fs;
+use std::path::PathBuf;
+use tokio::task::spawn_blocking;
+
+/// Handle to an opened [`LocalSource`].
+#[derive(Clone)]
+pub enum LocalTiles {
+    /// Directory tree of `{z}/{x}/{y}.{extension}` files.
+    Directory(PathBuf),
+    /// MBTiles database.
+    Mbtiles(Mbtiles),
+    /// PMTiles archive.
+    Pmtiles(Pmtiles),
+}
+
+impl LocalTiles {
+    /// Opens the given local source.
+    pub fn open(source: &LocalSource) -> anyhow::Result<Self> {
+        Ok(match source {
+            LocalSource::Directory { path } => {
+                if !path.is_dir() {
+                    bail!("Local tile directory not found: {}", path.display());
+                }
+                LocalTiles::Directory(path.clone())
+            }
+            LocalSource::Mbtiles { path } => LocalTiles::Mbtiles(Mbtiles::open(path)?),
+            LocalSource::Pmtiles { path } => LocalTiles::Pmtiles(Pmtiles::open(path)?),
+        })
+    }
+
+    /// Reads the given tile, returning its format and bytes.
+    pub async fn get_tile(&self, index: &TileIndex) -> anyhow::Result<(TileFormat, Box<[u8]>)> {
+        let local_tiles = self.clone();
+        let index = *index;
+        spawn_blocking(move || match &local_tiles {
+            LocalTiles::Directory(root) => TileFormat::ALL
+                .iter()
+                .find_map(|&format| {
+                    let path = root.join(format!(
+                        "{z}/{x}/{y}.{extension}",
+                        z = index.z,
+                        x = index.x,
+                        y = index.y,
+                        extension = format.extension(),
+                    ));
+                    fs::read(path)
+                        .ok()
+                        .map(|bytes| (format, bytes.into_boxed_slice()))
+                })
+                .with_context(|| {
+                    format!(
+                        "Tile {index:?} not found in local directory: {}",
+                        root.display()
+                    )
+                }),
+            LocalTiles::Mbtiles(mbtiles) => mbtiles.get_tile(&index),
+            LocalTiles::Pmtiles(pmtiles) => pmtiles.get_tile(&index),
+        })
+        .await
+        .with_context(|| format!("Failed to join background task to read local tile {index:?}"))?
+    }
+}
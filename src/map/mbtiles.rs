@@ -0,0 +1,410 @@
+//! Reader and writer for [MBTiles](https://github.com/mapbox/mbtiles-spec)
+//! SQLite databases of raster tiles.
+
+use super::tiles::TileIndex;
+use crate::caching::cache::{TileMetadata, TileUsage};
+use crate::config::TileFormat;
+use anyhow::{bail, Context};
+use log::warn;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Maximum duration to wait for other processes to release a lock on a
+/// writable database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handle to an MBTiles database.
+#[derive(Clone)]
+pub struct Mbtiles {
+    /// Read-only connection to the database.
+    connection: Arc<Mutex<Connection>>,
+    /// Image format declared in the metadata, if any.
+    format: Option<TileFormat>,
+}
+
+impl Mbtiles {
+    /// Opens the MBTiles database at the given path in read-only mode.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open MBTiles database: {}", path.display()))?;
+        let format: Option<String> = connection
+            .query_row(
+                "SELECT value FROM metadata WHERE name = 'format'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .with_context(|| format!("Failed to read MBTiles metadata: {}", path.display()))?;
+        let format = match format.as_deref() {
+            None => None,
+            Some("png") => Some(TileFormat::Png),
+            Some("jpg") | Some("jpeg") => Some(TileFormat::Jpeg),
+            Some("webp") => Some(TileFormat::Webp),
+            Some(format) => bail!(
+                "Unsupported MBTiles tile format `{format}` in: {}",
+                path.display()
+            ),
+        };
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            format,
+        })
+    }
+
+    /// Opens the MBTiles database at the given path in read-write mode,
+    /// creating it with the given name and tile format (if known) if it
+    /// doesn't exist.
+    ///
+    /// If the tile format isn't known, it's recorded in the metadata from the
+    /// first written tile. The `minzoom` and `maxzoom` metadata are also
+    /// updated as tiles are written.
+    ///
+    /// Besides the standard tables, the database contains a `tile_cache`
+    /// table storing the [`TileMetadata`] and last access time of each tile.
+    ///
+    /// The database can be shared with other processes, in which case writes
+    /// wait for the other processes' transactions to complete.
+    pub fn create(path: &Path, name: &str, format: Option<TileFormat>) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open MBTiles database: {}", path.display()))?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .with_context(|| format!("Failed to configure MBTiles database: {}", path.display()))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS metadata (name TEXT NOT NULL PRIMARY KEY, value TEXT);
+                CREATE TABLE IF NOT EXISTS tiles (
+                    zoom_level INTEGER NOT NULL,
+                    tile_column INTEGER NOT NULL,
+                    tile_row INTEGER NOT NULL,
+                    tile_data BLOB NOT NULL,
+                    PRIMARY KEY (zoom_level, tile_column, tile_row)
+                );
+                CREATE TABLE IF NOT EXISTS tile_cache (
+                    zoom_level INTEGER NOT NULL,
+                    tile_column INTEGER NOT NULL,
+                    tile_row INTEGER NOT NULL,
+                    fetched_at INTEGER NOT NULL,
+                    accessed_at INTEGER NOT NULL,
+                    etag TEXT,
+                    last_modified TEXT,
+                    PRIMARY KEY (zoom_level, tile_column, tile_row)
+                );",
+            )
+            .with_context(|| format!("Failed to create MBTiles schema: {}", path.display()))?;
+        connection
+            .execute(
+                "INSERT OR IGNORE INTO metadata (name, value) VALUES ('name', ?1)",
+                [name],
+            )
+            .with_context(|| format!("Failed to write MBTiles metadata: {}", path.display()))?;
+        if let Some(format) = format {
+            connection
+                .execute(
+                    "INSERT OR IGNORE INTO metadata (name, value) VALUES ('format', ?1)",
+                    [format.extension()],
+                )
+                .with_context(|| format!("Failed to write MBTiles metadata: {}", path.display()))?;
+        }
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            format,
+        })
+    }
+
+    /// Reads the given tile, returning its format and bytes.
+    ///
+    /// The format is detected from the image data, falling back to the format
+    /// declared in the metadata.
+    pub fn get_tile(&self, index: &TileIndex) -> anyhow::Result<(TileFormat, Box<[u8]>)> {
+        let data: Vec<u8> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                (index.z, index.x, tms_row(index)),
+                |row| row.get(0),
+            )
+            .optional()
+            .with_context(|| format!("Failed to query tile {index:?} from MBTiles database"))?
+            .with_context(|| format!("Tile {index:?} not found in MBTiles database"))?;
+        let format = TileFormat::from_magic_bytes(&data)
+            .or(self.format)
+            .with_context(|| format!("Failed to detect the image format of tile {index:?}"))?;
+        Ok((format, data.into_boxed_slice()))
+    }
+
+    /// Reads the cache metadata of the given tile, if any.
+    pub fn get_tile_metadata(&self, index: &TileIndex) -> anyhow::Result<Option<TileMetadata>> {
+        Ok(self
+            .get_tile_access(index)?
+            .map(|(metadata, _accessed_at)| metadata))
+    }
+
+    /// Reads the cache metadata of the given tile with its last access time
+    /// (in seconds since the Unix epoch), if any.
+    pub fn get_tile_access(
+        &self,
+        index: &TileIndex,
+    ) -> anyhow::Result<Option<(TileMetadata, u64)>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT fetched_at, etag, last_modified, accessed_at FROM tile_cache WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                (index.z, index.x, tms_row(index)),
+                |row| {
+                    let metadata = TileMetadata {
+                        fetched_at: row.get(0)?,
+                        etag: row.get(1)?,
+                        last_modified: row.get(2)?,
+                    };
+                    Ok((metadata, row.get(3)?))
+                },
+            )
+            .optional()
+            .with_context(|| format!("Failed to query metadata of tile {index:?} from MBTiles database"))
+    }
+
+    /// Writes the cache metadata of the given tile, marking it as accessed at
+    /// the given time (in seconds since the Unix epoch).
+    pub fn set_tile_metadata(
+        &self,
+        index: &TileIndex,
+        metadata: &TileMetadata,
+        accessed_at: u64,
+    ) -> anyhow::Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO tile_cache (zoom_level, tile_column, tile_row, fetched_at, accessed_at, etag, last_modified) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                (
+                    index.z,
+                    index.x,
+                    tms_row(index),
+                    metadata.fetched_at,
+                    accessed_at,
+                    &metadata.etag,
+                    &metadata.last_modified,
+                ),
+            )
+            .with_context(|| format!("Failed to write metadata of tile {index:?} to MBTiles database"))?;
+        Ok(())
+    }
+
+    /// Marks the given tile as accessed at the given time (in seconds since
+    /// the Unix epoch).
+    pub fn touch_tile(&self, index: &TileIndex, accessed_at: u64) -> anyhow::Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE tile_cache SET accessed_at = ?4 WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                (index.z, index.x, tms_row(index), accessed_at),
+            )
+            .with_context(|| format!("Failed to update access time of tile {index:?} in MBTiles database"))?;
+        Ok(())
+    }
+
+    /// Lists all the tiles, with their last access time (zero if unknown) and
+    /// size in bytes.
+    pub fn tile_usage(&self) -> anyhow::Result<Vec<TileUsage>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT tiles.zoom_level, tiles.tile_column, tiles.tile_row, COALESCE(tile_cache.accessed_at, 0), length(tiles.tile_data)
+                FROM tiles LEFT JOIN tile_cache USING (zoom_level, tile_column, tile_row)",
+            )
+            .context("Failed to prepare MBTiles query")?;
+        let rows = statement
+            .query_map([], |row| {
+                let z: u32 = row.get(0)?;
+                let row_index: u32 = row.get(2)?;
+                // Rows outside of their zoom level are skipped.
+                let Some(y) = 1u32
+                    .checked_shl(z)
+                    .and_then(|count| (count - 1).checked_sub(row_index))
+                else {
+                    warn!("Skipping invalid MBTiles tile at row {row_index} of zoom level {z}");
+                    return Ok(None);
+                };
+                let index = TileIndex {
+                    z,
+                    x: row.get(1)?,
+                    y,
+                };
+                Ok(Some(TileUsage {
+                    index,
+                    accessed_at: row.get(3)?,
+                    size: row.get(4)?,
+                }))
+            })
+            .context("Failed to list tiles of MBTiles database")?;
+        let tiles: Vec<Option<TileUsage>> = rows
+            .collect::<Result<_, _>>()
+            .context("Failed to list tiles of MBTiles database")?;
+        Ok(tiles.into_iter().flatten().collect())
+    }
+
+    /// Deletes the given tiles and their cache metadata, in a single
+    /// transaction.
+    pub fn delete_tiles(&self, indices: impl IntoIterator<Item = TileIndex>) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction()
+            .context("Failed to start MBTiles transaction")?;
+        for index in indices {
+            for table in ["tiles", "tile_cache"] {
+                transaction
+                    .prepare_cached(&format!(
+                        "DELETE FROM {table} WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3"
+                    ))
+                    .context("Failed to prepare MBTiles deletion")?
+                    .execute((index.z, index.x, tms_row(&index)))
+                    .with_context(|| format!("Failed to delete tile {index:?} from MBTiles database"))?;
+            }
+        }
+        transaction
+            .commit()
+            .context("Failed to commit MBTiles transaction")
+    }
+
+    /// Writes the given tiles with their cache metadata, replacing any
+    /// existing ones, in a single transaction. The tiles are marked as
+    /// accessed at the given time (in seconds since the Unix epoch).
+    pub fn set_tiles<'a>(
+        &self,
+        tiles: impl IntoIterator<Item = (TileIndex, &'a [u8], &'a TileMetadata)>,
+        accessed_at: u64,
+    ) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction()
+            .context("Failed to start MBTiles transaction")?;
+        {
+            let mut statement = transaction
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+                )
+                .context("Failed to prepare MBTiles insertion")?;
+            let mut metadata_statement = transaction
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO tile_cache (zoom_level, tile_column, tile_row, fetched_at, accessed_at, etag, last_modified) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                )
+                .context("Failed to prepare MBTiles insertion")?;
+            let mut format = None;
+            let mut zoom_range: Option<(u32, u32)> = None;
+            for (index, tile, metadata) in tiles {
+                format = format.or_else(|| TileFormat::from_magic_bytes(tile));
+                zoom_range = Some(zoom_range.map_or((index.z, index.z), |(min, max)| {
+                    (min.min(index.z), max.max(index.z))
+                }));
+                statement
+                    .execute((index.z, index.x, tms_row(&index), tile))
+                    .with_context(|| {
+                        format!("Failed to write tile {index:?} to MBTiles database")
+                    })?;
+                metadata_statement
+                    .execute((
+                        index.z,
+                        index.x,
+                        tms_row(&index),
+                        metadata.fetched_at,
+                        accessed_at,
+                        &metadata.etag,
+                        &metadata.last_modified,
+                    ))
+                    .with_context(|| {
+                        format!("Failed to write metadata of tile {index:?} to MBTiles database")
+                    })?;
+            }
+
+            if let Some(format) = format {
+                transaction
+                    .execute(
+                        "INSERT OR IGNORE INTO metadata (name, value) VALUES ('format', ?1)",
+                        [format.extension()],
+                    )
+                    .context("Failed to write MBTiles metadata")?;
+            }
+            if let Some((min_zoom, max_zoom)) = zoom_range {
+                transaction
+                    .execute(
+                        "INSERT INTO metadata (name, value) VALUES ('minzoom', ?1), ('maxzoom', ?2)
+                        ON CONFLICT (name) DO UPDATE SET value = CASE name
+                            WHEN 'minzoom' THEN MIN(CAST(value AS INTEGER), CAST(excluded.value AS INTEGER))
+                            ELSE MAX(CAST(value AS INTEGER), CAST(excluded.value AS INTEGER))
+                        END",
+                        (min_zoom, max_zoom),
+                    )
+                    .context("Failed to write MBTiles metadata")?;
+            }
+        }
+        transaction
+            .commit()
+            .context("Failed to commit MBTiles transaction")
+    }
+}
+
+/// Returns the row of the given tile in the TMS scheme followed by MBTiles,
+/// with rows counted from the South.
+fn tms_row(index: &TileIndex) -> u32 {
+    (1u32 << index.z) - 1 - index.y
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mbtiles_metadata() {
+        let path = std::env::temp_dir().join(format!(
+            "ridemap-test-metadata-{}.mbtiles",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let metadata = |mbtiles: &Mbtiles, name: &str| -> Option<String> {
+            mbtiles
+                .connection
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT value FROM metadata WHERE name = ?1",
+                    [name],
+                    |row| row.get(0),
+                )
+                .optional()
+                .unwrap()
+        };
+
+        // The format is recorded from the first written tile, and the zoom
+        // range grows with the written tiles.
+        let mbtiles = Mbtiles::create(&path, "test", None).unwrap();
+        assert_eq!(metadata(&mbtiles, "format"), None);
+        let png: &[u8] = b"\x89PNG\r\n\x1a\n";
+        let tile_metadata = TileMetadata::default();
+        mbtiles
+            .set_tiles(
+                [
+                    (TileIndex { z: 3, x: 1, y: 2 }, png, &tile_metadata),
+                    (TileIndex { z: 5, x: 4, y: 8 }, png, &tile_metadata),
+                ],
+                0,
+            )
+            .unwrap();
+        mbtiles
+            .set_tiles([(TileIndex { z: 4, x: 1, y: 2 }, png, &tile_metadata)], 0)
+            .unwrap();
+        assert_eq!(metadata(&mbtiles, "format").as_deref(), Some("png"));
+        assert_eq!(metadata(&mbtiles, "minzoom").as_deref(), Some("3"));
+        assert_eq!(metadata(&mbtiles, "maxzoom").as_deref(), Some("5"));
+        drop(mbtiles);
+
+        assert_eq!(Mbtiles::open(&path).unwrap().format, Some(TileFormat::Png));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
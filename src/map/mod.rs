@@ -1,5 +1,11 @@
 //! Module to manage tiles for a world map.
 
+pub mod local;
+pub mod mbtiles;
+pub mod pmtiles;
+pub mod prefetch;
+pub mod rate_limit;
 pub mod tile_box;
 pub mod tile_channel;
 pub mod tiles;
+pub mod url_template;
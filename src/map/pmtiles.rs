@@ -0,0 +1,346 @@
+//! Reader for [PMTiles](https://github.com/protomaps/PMTiles) (version 3)
+//! archives of raster tiles.
+
+use super::tiles::TileIndex;
+use crate::config::TileFormat;
+use anyhow::{bail, Context};
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Length of the fixed-size header at the start of the archive.
+const HEADER_LENGTH: usize = 127;
+/// Maximum depth of leaf directories, as mandated by the specification.
+const MAX_DIRECTORY_DEPTH: usize = 4;
+
+/// Compression scheme of the directories or tiles of an archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    /// No compression.
+    None,
+    /// Gzip compression.
+    Gzip,
+}
+
+impl Compression {
+    /// Parses the compression byte of the header.
+    fn from_byte(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            1 => Ok(Compression::None),
+            2 => Ok(Compression::Gzip),
+            _ => bail!("Unsupported PMTiles compression: {byte}"),
+        }
+    }
+
+    /// Decompresses the given data.
+    fn decompress(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let mut buf = Vec::new();
+                GzDecoder::new(data.as_slice())
+                    .read_to_end(&mut buf)
+                    .context("Failed to decompress gzip data")?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+/// Entry of a PMTiles directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Entry {
+    /// First tile ID covered by this entry.
+    tile_id: u64,
+    /// Offset of the data, relative to the tile data section for tiles or to
+    /// the leaf directories section for leaf directories.
+    offset: u64,
+    /// Length of the data.
+    length: u64,
+    /// Number of consecutive tile IDs sharing the same data, or zero if this
+    /// entry points to a leaf directory.
+    run_length: u64,
+}
+
+/// Handle to a PMTiles archive.
+#[derive(Clone)]
+pub struct Pmtiles {
+    /// Open archive file.
+    file: Arc<Mutex<File>>,
+    /// Entries of the root directory.
+    root: Arc<[Entry]>,
+    /// Offset of the leaf directories section.
+    leaf_directories_offset: u64,
+    /// Offset of the tile data section.
+    tile_data_offset: u64,
+    /// Compression of the directories.
+    internal_compression: Compression,
+    /// Compression of the tiles.
+    tile_compression: Compression,
+    /// Image format of the tiles.
+    format: TileFormat,
+}
+
+impl Pmtiles {
+    /// Opens the PMTiles archive at the given path, reading its header and
+    /// root directory.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::open(path)
+            .with_context(|| format!("Failed to open PMTiles archive: {}", path.display()))?;
+        let mut header = [0; HEADER_LENGTH];
+        file.read_exact(&mut header)
+            .with_context(|| format!("Failed to read PMTiles header: {}", path.display()))?;
+
+        if &header[..7] != b"PMTiles" {
+            bail!("Invalid PMTiles magic number in: {}", path.display());
+        }
+        if header[7] != 3 {
+            bail!(
+                "Unsupported PMTiles version {} in: {}",
+                header[7],
+                path.display()
+            );
+        }
+
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+        let root_offset = read_u64(8);
+        let root_length = read_u64(16);
+        let leaf_directories_offset = read_u64(40);
+        let tile_data_offset = read_u64(56);
+        let internal_compression = Compression::from_byte(header[97])?;
+        let tile_compression = Compression::from_byte(header[98])?;
+        let format = match header[99] {
+            2 => TileFormat::Png,
+            3 => TileFormat::Jpeg,
+            4 => TileFormat::Webp,
+            tile_type => bail!(
+                "Unsupported PMTiles tile type {tile_type} in: {}",
+                path.display()
+            ),
+        };
+
+        let mut pmtiles = Self {
+            file: Arc::new(Mutex::new(file)),
+            root: Arc::new([]),
+            leaf_directories_offset,
+            tile_data_offset,
+            internal_compression,
+            tile_compression,
+            format,
+        };
+        pmtiles.root = pmtiles
+            .read_directory(root_offset, root_length)
+            .with_context(|| format!("Failed to read PMTiles root directory: {}", path.display()))?
+            .into();
+        Ok(pmtiles)
+    }
+
+    /// Reads the given tile, returning its format and bytes.
+    pub fn get_tile(&self, index: &TileIndex) -> anyhow::Result<(TileFormat, Box<[u8]>)> {
+        let tile_id = tile_id(index);
+        let mut directory = self.root.clone();
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let entry = find_entry(&directory, tile_id)
+                .with_context(|| format!("Tile {index:?} not found in PMTiles archive"))?;
+            if entry.run_length > 0 {
+                let offset = self.tile_data_offset.saturating_add(entry.offset);
+                let data = self.read(offset, entry.length)?;
+                let data = self.tile_compression.decompress(data)?;
+                return Ok((self.format, data.into_boxed_slice()));
+            }
+            let offset = self.leaf_directories_offset.saturating_add(entry.offset);
+            directory = self.read_directory(offset, entry.length)?.into();
+        }
+        bail!("Too many nested leaf directories in PMTiles archive for tile {index:?}")
+    }
+
+    /// Reads and decodes the directory at the given position.
+    fn read_directory(&self, offset: u64, length: u64) -> anyhow::Result<Vec<Entry>> {
+        let data = self.read(offset, length)?;
+        let data = self.internal_compression.decompress(data)?;
+        decode_directory(&data)
+    }
+
+    /// Reads the given range of the archive.
+    fn read(&self, offset: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+        let mut file = self.file.lock().unwrap();
+        let file_length = file
+            .metadata()
+            .context("Failed to read metadata of PMTiles archive")?
+            .len();
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > file_length)
+        {
+            bail!("Invalid PMTiles range of {length} bytes at offset {offset}");
+        }
+        file.seek(SeekFrom::Start(offset))
+            .context("Failed to seek in PMTiles archive")?;
+        let mut buf = vec![0; length as usize];
+        file.read_exact(&mut buf)
+            .context("Failed to read PMTiles archive")?;
+        Ok(buf)
+    }
+}
+
+/// Finds the last entry whose tile ID is lower than or equal to the given one,
+/// if it covers it.
+fn find_entry(directory: &[Entry], tile_id: u64) -> Option<&Entry> {
+    let i = directory.partition_point(|entry| entry.tile_id <= tile_id);
+    let entry = directory.get(i.checked_sub(1)?)?;
+    if entry.run_length == 0 || tile_id - entry.tile_id < entry.run_length {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+/// Decodes a serialized (and decompressed) directory.
+fn decode_directory(data: &[u8]) -> anyhow::Result<Vec<Entry>> {
+    let mut reader = data;
+    let count = read_varint(&mut reader)?;
+    // Each entry takes at least one byte, which bounds the allocation below.
+    if count > reader.len() as u64 {
+        bail!(
+            "Invalid PMTiles directory with {count} entries in {} bytes",
+            data.len()
+        );
+    }
+    let count = count as usize;
+    let mut entries = vec![
+        Entry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        };
+        count
+    ];
+
+    let mut tile_id = 0;
+    for entry in entries.iter_mut() {
+        tile_id = read_varint(&mut reader)?
+            .checked_add(tile_id)
+            .context("Invalid PMTiles directory tile ID")?;
+        entry.tile_id = tile_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(&mut reader)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(&mut reader)?;
+    }
+    for i in 0..count {
+        let value = read_varint(&mut reader)?;
+        entries[i].offset = if value == 0 && i > 0 {
+            entries[i - 1]
+                .offset
+                .checked_add(entries[i - 1].length)
+                .context("Invalid PMTiles directory offset")?
+        } else {
+            value
+                .checked_sub(1)
+                .context("Invalid PMTiles directory offset")?
+        };
+    }
+    Ok(entries)
+}
+
+/// Reads an unsigned LEB128 variable-length integer.
+fn read_varint(reader: &mut &[u8]) -> anyhow::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = reader
+            .split_first()
+            .context("Unexpected end of PMTiles directory")?;
+        *reader = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Invalid varint in PMTiles directory")
+}
+
+/// Computes the PMTiles ID of the given tile, i.e. its position along a
+/// Hilbert curve after all the tiles of lower zoom levels.
+fn tile_id(index: &TileIndex) -> u64 {
+    let mut id = ((1u64 << (2 * index.z)) - 1) / 3;
+    let (mut x, mut y) = (index.x as u64, index.y as u64);
+    let mut s = (1u64 << index.z) / 2;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        id += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant.
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x &= s - 1;
+        y &= s - 1;
+        s /= 2;
+    }
+    id
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pmtiles_tile_id() {
+        let id = |z, x, y| tile_id(&TileIndex { z, x, y });
+        assert_eq!(id(0, 0, 0), 0);
+        assert_eq!(id(1, 0, 0), 1);
+        assert_eq!(id(1, 0, 1), 2);
+        assert_eq!(id(1, 1, 1), 3);
+        assert_eq!(id(1, 1, 0), 4);
+        assert_eq!(id(2, 0, 0), 5);
+        assert_eq!(id(2, 3, 0), 20);
+        assert_eq!(id(3, 0, 0), 21);
+    }
+
+    #[test]
+    fn pmtiles_directory() {
+        // Two entries: tile 0 alone, and tiles 1 to 4 sharing the same data.
+        let directory = decode_directory(&[2, 0, 1, 1, 4, 10, 20, 1, 0]).unwrap();
+        assert_eq!(
+            directory,
+            [
+                Entry {
+                    tile_id: 0,
+                    offset: 0,
+                    length: 10,
+                    run_length: 1
+                },
+                Entry {
+                    tile_id: 1,
+                    offset: 10,
+                    length: 20,
+                    run_length: 4
+                },
+            ]
+        );
+        assert_eq!(find_entry(&directory, 0), Some(&directory[0]));
+        assert_eq!(find_entry(&directory, 3), Some(&directory[1]));
+        assert_eq!(find_entry(&directory, 5), None);
+    }
+
+    #[test]
+    fn pmtiles_directory_invalid() {
+        // A count of entries larger than the directory.
+        assert!(decode_directory(&[0xff, 0xff, 0xff, 0xff, 0x0f, 0]).is_err());
+        // Tile IDs overflowing.
+        assert!(decode_directory(&[
+            2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 1, 1, 1, 1, 1, 1, 1
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,384 @@
+//! Prefetching of the tiles covering an area into the cache, for offline use.
+
+use super::tile_box::TileBox;
+use super::tiles::{Prefetched, TileIndex, Tiles};
+use crate::tracks::polyline::{LatLon, Point};
+use anyhow::{bail, Context};
+use futures::{stream, StreamExt};
+use log::error;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+/// Circumference of the Earth at the equator, in meters.
+const EARTH_CIRCUMFERENCE: f64 = 40_075_016.686;
+/// Number of processed tiles between two progress reports.
+const PROGRESS_INTERVAL: usize = 100;
+
+/// Rectangle in latitude-longitude coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    /// Western longitude.
+    pub min_lon: f64,
+    /// Southern latitude.
+    pub min_lat: f64,
+    /// Eastern longitude.
+    pub max_lon: f64,
+    /// Northern latitude.
+    pub max_lat: f64,
+}
+
+impl BoundingBox {
+    /// Returns the North-western and South-eastern corners of this bounding
+    /// box, in Mercator coordinates.
+    pub fn mercator_corners(&self) -> (Point<f64>, Point<f64>) {
+        // The y axis of Mercator coordinates goes from North to South.
+        let min = LatLon {
+            lat: self.max_lat,
+            lon: self.min_lon,
+        }
+        .as_mercator();
+        let max = LatLon {
+            lat: self.min_lat,
+            lon: self.max_lon,
+        }
+        .as_mercator();
+        (min, max)
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = anyhow::Error;
+
+    /// Parses a bounding box in the `min_lon,min_lat,max_lon,max_lat` format.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let values = s
+            .split(',')
+            .map(|value| {
+                value
+                    .trim()
+                    .parse::<f64>()
+                    .with_context(|| format!("Invalid coordinate: {value}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let [min_lon, min_lat, max_lon, max_lat] = values[..] else {
+            bail!("Expected 4 coordinates `min_lon,min_lat,max_lon,max_lat`, found {s}");
+        };
+        if !(-180.0..=180.0).contains(&min_lon) || !(-180.0..=180.0).contains(&max_lon) {
+            bail!("Longitudes must be between -180 and 180 degrees");
+        }
+        if !(-90.0..=90.0).contains(&min_lat) || !(-90.0..=90.0).contains(&max_lat) {
+            bail!("Latitudes must be between -90 and 90 degrees");
+        }
+        if min_lon > max_lon || min_lat > max_lat {
+            bail!("The minimum coordinates must be lower than the maximum coordinates");
+        }
+        Ok(BoundingBox {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        })
+    }
+}
+
+/// Area to prefetch, in Mercator coordinates.
+#[derive(Debug)]
+pub enum Area {
+    /// Rectangle between the given corners.
+    Rect {
+        /// North-western corner.
+        min: Point<f64>,
+        /// South-eastern corner.
+        max: Point<f64>,
+    },
+    /// Corridor around the given tracks.
+    Corridor {
+        /// Points of each track.
+        tracks: Vec<Vec<Point<f64>>>,
+        /// Distance from the tracks to cover, in meters.
+        buffer_meters: f64,
+    },
+}
+
+impl Area {
+    /// Returns the area covered by the given bounding box.
+    pub fn from_bbox(bbox: &BoundingBox) -> Self {
+        let (min, max) = bbox.mercator_corners();
+        Area::Rect { min, max }
+    }
+
+    /// Returns the smallest rectangle containing all the given tracks, or
+    /// [`None`] if there are no points.
+    pub fn extent(tracks: &[Vec<Point<f64>>]) -> Option<Self> {
+        let mut points = tracks.iter().flatten();
+        let first = *points.next()?;
+        let (min, max) = points.fold((first, first), |(min, max), p| {
+            (
+                Point {
+                    x: min.x.min(p.x),
+                    y: min.y.min(p.y),
+                },
+                Point {
+                    x: max.x.max(p.x),
+                    y: max.y.max(p.y),
+                },
+            )
+        });
+        Some(Area::Rect { min, max })
+    }
+
+    /// Counts the tiles covering this area at the given zoom level, without
+    /// enumerating them for a rectangle.
+    pub fn tile_count(&self, z: u32) -> usize {
+        match self {
+            Area::Rect { min, max } => TileBox::covering(z, *min, *max).len(),
+            Area::Corridor { .. } => self.tile_indices(z).len(),
+        }
+    }
+
+    /// Returns the tiles covering this area at the given zoom level.
+    pub fn tile_indices(&self, z: u32) -> BTreeSet<TileIndex> {
+        match self {
+            Area::Rect { min, max } => TileBox::covering(z, *min, *max)
+                .tile_indices()
+                .into_iter()
+                .collect(),
+            Area::Corridor {
+                tracks,
+                buffer_meters,
+            } => {
+                let tile_size = 0.5_f64.powi(z as i32);
+                let mut result = BTreeSet::new();
+                for track in tracks {
+                    let mut add_point = |p: Point<f64>| {
+                        let buffer = buffer_meters * mercator_scale(p.y);
+                        let min = Point {
+                            x: p.x - buffer,
+                            y: p.y - buffer,
+                        };
+                        let max = Point {
+                            x: p.x + buffer,
+                            y: p.y + buffer,
+                        };
+                        result.extend(TileBox::covering(z, min, max).tile_indices());
+                    };
+
+                    if let Some(&first) = track.first() {
+                        add_point(first);
+                    }
+                    for segment in track.windows(2) {
+                        let (p0, p1) = (segment[0], segment[1]);
+                        // Sample the segment finely enough for the buffers around
+                        // consecutive samples to overlap.
+                        let step = (buffer_meters * mercator_scale(p0.y)).max(tile_size / 8.0);
+                        let length = (p1.x - p0.x).hypot(p1.y - p0.y);
+                        let count = (length / step).ceil().max(1.0) as usize;
+                        for i in 1..=count {
+                            let t = i as f64 / count as f64;
+                            add_point(Point {
+                                x: p0.x + t * (p1.x - p0.x),
+                                y: p0.y + t * (p1.y - p0.y),
+                            });
+                        }
+                    }
+                }
+                result
+            }
+        }
+    }
+}
+
+/// Returns the length of one meter in Mercator coordinates, at the given y
+/// coordinate.
+fn mercator_scale(y: f64) -> f64 {
+    // The Mercator projection stretches distances by 1 / cos(latitude), i.e.
+    // cosh((1 - 2y) * pi).
+    ((1.0 - 2.0 * y) * std::f64::consts::PI).cosh() / EARTH_CIRCUMFERENCE
+}
+
+/// Statistics of a prefetching run.
+#[derive(Debug, Default)]
+pub struct PrefetchStats {
+    /// Number of tiles downloaded (or revalidated).
+    pub downloaded: usize,
+    /// Number of tiles that were already cached.
+    pub cached: usize,
+    /// Number of tiles that the server reported as missing.
+    pub missing: usize,
+    /// Number of tiles that failed to download.
+    pub failed: usize,
+}
+
+/// Downloads the given tiles of the given map layers into the cache, with up
+/// to `parallel_requests` requests in parallel, and prints the progress.
+///
+/// Tiles that are already cached are skipped, so that an interrupted run can
+/// be resumed by running it again.
+pub async fn prefetch_tiles(
+    tiles: &Tiles<'_>,
+    layers: &[usize],
+    indices: &BTreeSet<TileIndex>,
+    parallel_requests: usize,
+) -> PrefetchStats {
+    let total = layers.len() * indices.len();
+    let mut stats = PrefetchStats::default();
+    let mut results = stream::iter(
+        layers
+            .iter()
+            .flat_map(|&layer| indices.iter().map(move |index| (layer, index))),
+    )
+    .map(|(layer, index)| async move { (index, tiles.prefetch_tile(layer, index).await) })
+    .buffer_unordered(parallel_requests);
+
+    let mut done = 0;
+    while let Some((index, result)) = results.next().await {
+        match result {
+            Ok(Prefetched::Downloaded) => stats.downloaded += 1,
+            Ok(Prefetched::Cached) => stats.cached += 1,
+            Ok(Prefetched::Missing) => stats.missing += 1,
+            Err(e) => {
+                error!("Failed to prefetch tile {index:?}: {e:?}");
+                stats.failed += 1;
+            }
+        }
+        done += 1;
+        if done % PROGRESS_INTERVAL == 0 || done == total {
+            println!(
+                "Prefetched {done} / {total} tiles ({} downloaded, {} already cached, {} missing, {} failed)",
+                stats.downloaded, stats.cached, stats.missing, stats.failed
+            );
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::caching::cache::{Cache, CacheAccess, TileCacheBackend, TileCacheParams};
+    use crate::config::MapProvider;
+    use crate::tracks::oauth::read_request_head;
+    use image::{ImageFormat, RgbaImage};
+    use std::io::Cursor;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn bounding_box_parse() {
+        assert_eq!(
+            "2.2,48.8,2.5,48.9".parse::<BoundingBox>().unwrap(),
+            BoundingBox {
+                min_lon: 2.2,
+                min_lat: 48.8,
+                max_lon: 2.5,
+                max_lat: 48.9,
+            }
+        );
+        assert!("2.2,48.8,2.5".parse::<BoundingBox>().is_err());
+        assert!("2.5,48.8,2.2,48.9".parse::<BoundingBox>().is_err());
+        assert!("2.2,48.8,2.5,95".parse::<BoundingBox>().is_err());
+    }
+
+    #[test]
+    fn corridor_tiles() {
+        // A horizontal track along the equator, in the middle of the tiles at
+        // zoom level 4.
+        let y = 0.5 - 1.0 / 32.0;
+        let corridor = |buffer_meters| Area::Corridor {
+            tracks: vec![vec![Point { x: 0.1, y }, Point { x: 0.3, y }]],
+            buffer_meters,
+        };
+        let tiles = corridor(1000.0).tile_indices(4);
+        assert_eq!(
+            tiles.iter().map(|index| index.x).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert!(tiles.iter().all(|index| index.y == 7));
+
+        // A buffer of ~1/16th of the Earth reaches the neighboring rows.
+        let tiles = corridor(EARTH_CIRCUMFERENCE / 16.0).tile_indices(4);
+        assert_eq!(
+            tiles.iter().map(|index| index.y).collect::<BTreeSet<_>>(),
+            BTreeSet::from([6, 7, 8])
+        );
+    }
+
+    #[test]
+    fn prefetch_missing_tiles() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut png = Vec::new();
+            RgbaImage::new(1, 1)
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .unwrap();
+
+            // Tile server that only has the root tile.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    let head = read_request_head(&mut socket).await.unwrap();
+                    let response = if head.starts_with("GET /0/0/0.png ") {
+                        let mut response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            png.len()
+                        )
+                        .into_bytes();
+                        response.extend_from_slice(&png);
+                        response
+                    } else {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec()
+                    };
+                    socket.write_all(&response).await.unwrap();
+                }
+            });
+
+            let map_provider: MapProvider = serde_json::from_value(serde_json::json!({
+                "url": format!("http://{addr}/{{z}}/{{x}}/{{y}}.png"),
+                "cache_folder": "test",
+            }))
+            .unwrap();
+            let map_providers = [map_provider];
+            let directory = std::env::temp_dir()
+                .join(format!("ridemap-test-prefetch-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&directory);
+            let cache = Cache::new(
+                directory.to_str().unwrap(),
+                &map_providers,
+                TileCacheParams {
+                    backend: TileCacheBackend::Directory,
+                    max_age: Duration::from_secs(3600),
+                    max_size: None,
+                },
+                CacheAccess::Shared,
+            )
+            .unwrap();
+            let client = reqwest::Client::new();
+            let (ui_tx, _ui_rx) = channel();
+            let tiles = Tiles::new(&map_providers, Some(&cache), &client, &ui_tx, false).unwrap();
+
+            let indices = BTreeSet::from([
+                TileIndex { z: 0, x: 0, y: 0 },
+                TileIndex { z: 1, x: 0, y: 0 },
+            ]);
+            let stats = prefetch_tiles(&tiles, &[0], &indices, 2).await;
+            assert_eq!(
+                (stats.downloaded, stats.cached, stats.missing, stats.failed),
+                (1, 0, 1, 0)
+            );
+
+            // The missing tile is remembered rather than requested again.
+            let stats = prefetch_tiles(&tiles, &[0], &indices, 2).await;
+            assert_eq!(
+                (stats.downloaded, stats.cached, stats.missing, stats.failed),
+                (0, 1, 1, 0)
+            );
+
+            server.abort();
+            drop(cache);
+            std::fs::remove_dir_all(&directory).unwrap();
+        });
+    }
+}
@@ -0,0 +1,102 @@
+//! Utilities to throttle and retry requests to tile servers.
+
+use rand::distr::Open01;
+use rand::{rng, Rng};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+/// Delay before the first retry of a failed request.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Maximum delay between two retries of a failed request.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Maximum delay honored from a `Retry-After` header, so that a misconfigured
+/// server can't stall the requests indefinitely.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Limits the rate of requests sent to a server, spacing them evenly.
+pub struct RateLimiter {
+    /// Minimal interval between two requests.
+    interval: Duration,
+    /// Time at which the next request can be sent.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter allowing the given number of requests per
+    /// second, or an unlimited number if `None`.
+    pub fn new(requests_per_second: Option<f32>) -> Self {
+        Self {
+            interval: requests_per_second
+                .map_or(Duration::ZERO, |rps| Duration::from_secs_f32(1.0 / rps)),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until a request can be sent.
+    pub async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        sleep_until(slot).await;
+    }
+
+    /// Pauses all requests for the given duration, e.g. when the server
+    /// replied with a `Retry-After` header.
+    pub fn pause_for(&self, duration: Duration) {
+        let mut next = self.next.lock().unwrap();
+        *next = (*next).max(Instant::now() + duration);
+    }
+}
+
+/// Returns the delay before retrying a request after the given number of
+/// failed attempts, using exponential backoff with random jitter.
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_BACKOFF);
+    // Spread the retries between 50% and 150% of the nominal delay, so that
+    // concurrent requests don't retry in lockstep.
+    let jitter: f64 = rng().sample(Open01);
+    delay.mul_f64(0.5 + jitter)
+}
+
+/// Parses the value of a `Retry-After` HTTP header, capped to a few minutes.
+///
+/// Only the delay in seconds is supported, not the HTTP date format, in which
+/// case the caller falls back to an exponential backoff.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .map(|seconds| Duration::from_secs(seconds).min(MAX_RETRY_AFTER))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_delay_bounds() {
+        for attempt in 0..20 {
+            let nominal = INITIAL_BACKOFF
+                .saturating_mul(1 << attempt.min(16))
+                .min(MAX_BACKOFF);
+            let delay = backoff_delay(attempt);
+            assert!(delay >= nominal / 2);
+            assert!(delay <= nominal * 3 / 2);
+        }
+    }
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("86400"), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+}
@@ -38,7 +38,7 @@ impl TileBox {
     }
 
     /// Counts the number of tiles within the box.
-    fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         (self.max.x - self.min.x) as usize * (self.max.y - self.min.y) as usize
     }
 
@@ -114,6 +114,44 @@ impl TileBox {
         }
     }
 
+    /// Returns the smallest tile box at zoom level `z` containing the given
+    /// rectangle, w.r.t. a world square of length 1.0.
+    ///
+    /// The rectangle is clamped to the world's bounds.
+    pub fn covering(z: u32, min: Point<f64>, max: Point<f64>) -> Self {
+        let count = 1u32 << z;
+        let scale = count as f64;
+        let clamp = |value: f64| (value * scale).floor().clamp(0.0, (count - 1) as f64) as u32;
+        TileBox {
+            z,
+            min: Point {
+                x: clamp(min.x),
+                y: clamp(min.y),
+            },
+            max: Point {
+                x: clamp(max.x) + 1,
+                y: clamp(max.y) + 1,
+            },
+        }
+    }
+
+    /// Returns the center of this box, w.r.t. a world square of length 1.0.
+    pub fn center(&self) -> [f64; 2] {
+        let size = 0.5_f64.powi(self.z as i32);
+        [
+            (self.min.x + self.max.x) as f64 * size / 2.0,
+            (self.min.y + self.max.y) as f64 * size / 2.0,
+        ]
+    }
+
+    /// Returns all the tiles contained in the ancestors of this box, i.e. at
+    /// all the lower zoom levels.
+    pub fn ancestor_indices(&self) -> Vec<TileIndex> {
+        (1..=self.z)
+            .flat_map(|n| self.ancestor(n).unwrap().tile_indices())
+            .collect()
+    }
+
     /// Returns all the tiles contained in this box, at the current zoom level.
     pub fn tile_indices(&self) -> Vec<TileIndex> {
         let mut result = Vec::with_capacity(self.len());
@@ -336,6 +374,49 @@ mod test {
         }
     }
 
+    #[test]
+    fn tile_box_ancestor_indices() {
+        let z = 4;
+        let max = 1 << z;
+        for maxx in 1..=max {
+            for minx in 0..maxx {
+                for maxy in 1..=max {
+                    for miny in 0..maxy {
+                        let tile_box = TileBox {
+                            z,
+                            min: Point { x: minx, y: miny },
+                            max: Point { x: maxx, y: maxy },
+                        };
+
+                        let ancestors = tile_box.ancestor_indices();
+                        assert!(ancestors.contains(&TileIndex { z: 0, x: 0, y: 0 }));
+                        for index in ancestors {
+                            assert_eq!(tile_box.is_ancestor(&index), Some(z - index.z));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tile_box_covering() {
+        let covering = TileBox::covering(2, Point { x: 0.3, y: 0.1 }, Point { x: 0.6, y: 0.2 });
+        assert!(covering.is_valid());
+        assert_eq!(
+            covering.tile_indices(),
+            [
+                TileIndex { z: 2, x: 1, y: 0 },
+                TileIndex { z: 2, x: 2, y: 0 },
+            ]
+        );
+
+        // Out of bounds rectangles are clamped.
+        let covering = TileBox::covering(3, Point { x: -1.0, y: -1.0 }, Point { x: 2.0, y: 1.0 });
+        assert!(covering.is_valid());
+        assert_eq!(covering.len(), 64);
+    }
+
     #[test]
     fn tile_box_contains() {
         let z = 4;
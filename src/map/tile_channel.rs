@@ -6,96 +6,235 @@ use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::future;
 use futures::{stream, Stream, StreamExt};
 use log::debug;
+use std::collections::{HashSet, VecDeque};
 use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use tokio::sync::watch;
 
 /// Similar to [`TileRequest`], but multiple tiles can be requested in a batch.
 #[derive(Clone, Debug)]
 enum BatchTileRequest {
     /// Tile requested.
-    Tiles(Box<[TileIndex]>),
+    Tiles {
+        /// Map layers to fetch the tiles from, in priority order.
+        layers: Box<[usize]>,
+        /// Requested tiles.
+        tiles: Box<[TileIndex]>,
+    },
     /// Notification that the UI evicted a tile.
-    Evicted(TileIndex),
+    Evicted {
+        /// Map layer of the evicted tile.
+        layer: usize,
+        /// Evicted tile.
+        index: TileIndex,
+    },
     /// End of stream.
     End,
 }
 
 /// Incoming request from the UI.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TileRequest {
     /// Tile requested.
-    Tile(TileIndex),
+    Tile {
+        /// Map layer to fetch the tile from, i.e. the index of its map provider.
+        layer: usize,
+        /// Requested tile.
+        index: TileIndex,
+    },
     /// Speculative request of a tile.
-    Speculate(TileIndex),
+    Speculate {
+        /// Map layer to fetch the tile from, i.e. the index of its map provider.
+        layer: usize,
+        /// Speculated tile.
+        index: TileIndex,
+    },
     /// Notification that the UI evicted a tile.
-    Evicted(TileIndex),
+    Evicted {
+        /// Map layer of the evicted tile.
+        layer: usize,
+        /// Evicted tile.
+        index: TileIndex,
+    },
     /// End of stream.
     End,
 }
 
-/// List of currently visible and speculated tiles.
+/// Tiles of the current window, requested when the window moves to a new
+/// tile box.
+#[derive(Clone, Debug, Default)]
+pub struct VisibleTiles {
+    /// Center of the window, w.r.t. a world square of length 1.0.
+    pub center: [f64; 2],
+    /// Visible tiles to fetch.
+    pub tiles: Box<[TileIndex]>,
+    /// Tiles to fetch at the lower zoom levels, to draw instead of the visible
+    /// tiles until these are available. These are only requested for the base
+    /// map, i.e. the first map layer.
+    pub ancestors: Box<[TileIndex]>,
+}
+
+/// Category of a wanted tile, in priority order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum TileCategory {
+    /// Tile in the current window.
+    Visible,
+    /// Ancestor of the current window.
+    Ancestor,
+    /// Speculated tile.
+    Speculative,
+}
+
+/// Priority of a wanted tile, lower being more urgent.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+struct TilePriority {
+    category: TileCategory,
+    /// Zoom level of ancestor tiles, zero for other tiles.
+    level: u32,
+    /// Squared distance to the center of the window.
+    distance: f64,
+}
+
+/// List of currently visible and speculated tiles, for the map layers
+/// currently displayed.
 #[derive(Default)]
 struct CurrentTiles {
-    tiles: Box<[TileIndex]>,
+    layers: Box<[usize]>,
+    visible: VisibleTiles,
     speculative: Box<[TileIndex]>,
 }
 
+impl CurrentTiles {
+    /// Returns the category of the given tile of the given map layer, or
+    /// [`None`] if this tile isn't wanted anymore.
+    fn category(&self, layer: usize, index: &TileIndex) -> Option<TileCategory> {
+        if !self.layers.contains(&layer) {
+            None
+        } else if self.visible.tiles.contains(index) {
+            Some(TileCategory::Visible)
+        } else if self.layers.first() == Some(&layer) && self.visible.ancestors.contains(index) {
+            Some(TileCategory::Ancestor)
+        } else if self.speculative.contains(index) {
+            Some(TileCategory::Speculative)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether the given tile of the given map layer is still wanted,
+    /// i.e. visible, an ancestor of the visible tiles or speculated.
+    fn contains(&self, layer: usize, index: &TileIndex) -> bool {
+        self.category(layer, index).is_some()
+    }
+
+    /// Returns the priority of the given tile.
+    ///
+    /// Visible tiles come first, nearest the center of the window first. Then
+    /// come the missing ancestor levels, coarsest first so that a fallback is
+    /// available as soon as possible, and lastly the speculated tiles.
+    fn priority(&self, category: TileCategory, index: &TileIndex) -> TilePriority {
+        let [left, top, width, height] = index.rect();
+        let dx = left + width / 2.0 - self.visible.center[0];
+        let dy = top + height / 2.0 - self.visible.center[1];
+        let level = match category {
+            TileCategory::Ancestor => index.z,
+            TileCategory::Visible | TileCategory::Speculative => 0,
+        };
+        TilePriority {
+            category,
+            level,
+            distance: dx * dx + dy * dy,
+        }
+    }
+}
+
 /// Sending side of the channel, for the UI to request tiles.
 pub struct TileRequestSender {
     tx: UnboundedSender<BatchTileRequest>,
     current: Arc<RwLock<CurrentTiles>>,
+    /// Notifies the [`TileWatcher`]s that the [`CurrentTiles`] changed.
+    updated_tx: watch::Sender<()>,
 }
 
 /// Receiving side of the channel, to process requests sent by the UI.
 pub struct TileRequestReceiver {
     rx: UnboundedReceiver<BatchTileRequest>,
     current: Arc<RwLock<CurrentTiles>>,
+    updated_rx: watch::Receiver<()>,
+}
+
+/// Handle to watch whether requested tiles are still wanted by the UI.
+#[derive(Clone)]
+pub struct TileWatcher {
+    current: Arc<RwLock<CurrentTiles>>,
+    updated_rx: watch::Receiver<()>,
 }
 
 /// Constructs a channel to communicate [`TileRequest`]s.
 pub fn tile_channel() -> (TileRequestSender, TileRequestReceiver) {
     let (tx, rx) = unbounded();
+    let (updated_tx, updated_rx) = watch::channel(());
     let current_tx = Arc::new(RwLock::new(CurrentTiles::default()));
     let current_rx = current_tx.clone();
     (
         TileRequestSender {
             tx,
             current: current_tx,
+            updated_tx,
         },
         TileRequestReceiver {
             rx,
             current: current_rx,
+            updated_rx,
         },
     )
 }
 
 impl TileRequestSender {
-    /// Requests a set of tiles.
+    /// Requests a set of tiles for each of the given map layers.
     ///
-    /// This replaces the set of [`CurrentTiles`].
+    /// This replaces the set of [`CurrentTiles`], which the receiving end uses
+    /// to prioritize the pending requests. The `visible` tiles must be set
+    /// when the set of layers changes.
     pub fn request_tiles(
         &self,
-        tiles: Option<Box<[TileIndex]>>,
+        layers: &[usize],
+        visible: Option<VisibleTiles>,
         speculative: Box<[TileIndex]>,
     ) -> anyhow::Result<()> {
         // Replaces the current set of tiles.
-        let mut current = self.current.write().unwrap();
-        if let Some(ref tiles) = tiles {
-            current.tiles.clone_from(tiles);
+        {
+            let mut current = self.current.write().unwrap();
+            current.layers = layers.into();
+            if let Some(ref visible) = visible {
+                current.visible.clone_from(visible);
+            }
+            current.speculative.clone_from(&speculative);
         }
-        current.speculative.clone_from(&speculative);
+        self.updated_tx.send_replace(());
 
-        // Send the visible tiles in priority.
-        if let Some(tiles) = tiles {
-            self.send(BatchTileRequest::Tiles(tiles))?;
+        if let Some(visible) = visible {
+            self.send(BatchTileRequest::Tiles {
+                layers: layers.into(),
+                tiles: visible.tiles,
+            })?;
+            if let Some(&base_layer) = layers.first() {
+                self.send(BatchTileRequest::Tiles {
+                    layers: Box::new([base_layer]),
+                    tiles: visible.ancestors,
+                })?;
+            }
         }
 
-        // Then send the speculated tiles.
-        self.send(BatchTileRequest::Tiles(speculative))
+        self.send(BatchTileRequest::Tiles {
+            layers: layers.into(),
+            tiles: speculative,
+        })
     }
 
-    /// Notifies that a tile is evicted.
-    pub fn evict_tile(&self, tile: TileIndex) -> anyhow::Result<()> {
-        self.send(BatchTileRequest::Evicted(tile))
+    /// Notifies that a tile of the given map layer is evicted.
+    pub fn evict_tile(&self, layer: usize, index: TileIndex) -> anyhow::Result<()> {
+        self.send(BatchTileRequest::Evicted { layer, index })
     }
 
     /// Notifies that the channel is ready to close.
@@ -112,42 +251,225 @@ impl TileRequestSender {
 }
 
 impl TileRequestReceiver {
+    /// Returns a handle to watch whether requested tiles are still wanted.
+    pub fn watcher(&self) -> TileWatcher {
+        TileWatcher {
+            current: self.current.clone(),
+            updated_rx: self.updated_rx.clone(),
+        }
+    }
+
     /// Transforms the receiving end of the channel into an asynchronous stream
     /// of [`TileRequest`]s.
+    ///
+    /// Requested tiles are queued, and each time the stream is polled the
+    /// most urgent one is returned, based on the [`CurrentTiles`] at that
+    /// time. Tiles that aren't wanted anymore are dropped from the queue.
+    /// Evictions are forwarded as soon as they are received.
     pub fn into_stream(self) -> impl Stream<Item = TileRequest> {
-        let current = self.current;
+        let mut scheduler = TileScheduler {
+            rx: self.rx,
+            current: self.current,
+            pending: HashSet::new(),
+            immediate: VecDeque::new(),
+            closed: false,
+        };
+        stream::poll_fn(move |cx| scheduler.poll_next(cx))
+    }
+}
 
-        self.rx
-            .flat_map(|batch_request| {
-                let requests = match batch_request {
-                    BatchTileRequest::Tiles(tiles) => {
-                        tiles.iter().map(|&tile| TileRequest::Tile(tile)).collect()
-                    }
-                    BatchTileRequest::Evicted(tile) => vec![TileRequest::Evicted(tile)],
-                    BatchTileRequest::End => vec![TileRequest::End],
-                };
-                stream::iter(requests)
-            })
-            .take_while(|tile_request| future::ready(!matches!(tile_request, TileRequest::End)))
-            .filter_map(move |tile_request| {
-                future::ready(match tile_request {
-                    TileRequest::Tile(tile) => {
-                        // Check whether the request is still valid, and categorize it (visible or
-                        // speculated).
-                        let current = current.read().unwrap();
-                        if current.tiles.contains(&tile) {
-                            debug!("Request {tile:?}");
-                            Some(TileRequest::Tile(tile))
-                        } else if current.speculative.contains(&tile) {
-                            debug!("Speculate {tile:?}");
-                            Some(TileRequest::Speculate(tile))
-                        } else {
-                            debug!("Drop {tile:?}");
-                            None
-                        }
+/// Priority queue of the [`TileRequest`]s received from the UI.
+struct TileScheduler {
+    rx: UnboundedReceiver<BatchTileRequest>,
+    current: Arc<RwLock<CurrentTiles>>,
+    /// Requested tiles that aren't processed yet, with their map layer.
+    pending: HashSet<(usize, TileIndex)>,
+    /// Requests to forward before any pending tile.
+    immediate: VecDeque<TileRequest>,
+    /// Whether the sending side of the channel was dropped.
+    closed: bool,
+}
+
+impl TileScheduler {
+    /// Returns the next request to process, if any.
+    fn poll_next(&mut self, cx: &mut TaskContext<'_>) -> Poll<Option<TileRequest>> {
+        // Receive all the available requests.
+        while !self.closed {
+            match self.rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(batch_request)) => self.receive(batch_request),
+                Poll::Ready(None) => self.closed = true,
+                Poll::Pending => break,
+            }
+        }
+
+        if let Some(tile_request) = self.immediate.pop_front() {
+            return match tile_request {
+                TileRequest::End => {
+                    // Ignore any remaining request.
+                    self.closed = true;
+                    self.pending.clear();
+                    self.immediate.clear();
+                    Poll::Ready(None)
+                }
+                _ => Poll::Ready(Some(tile_request)),
+            };
+        }
+
+        if let Some(tile_request) = self.pop_most_urgent() {
+            Poll::Ready(Some(tile_request))
+        } else if self.closed {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Queues the given batch of requests.
+    fn receive(&mut self, batch_request: BatchTileRequest) {
+        match batch_request {
+            BatchTileRequest::Tiles { layers, tiles } => {
+                for &layer in layers.iter() {
+                    self.pending
+                        .extend(tiles.iter().map(|&index| (layer, index)));
+                }
+            }
+            BatchTileRequest::Evicted { layer, index } => {
+                self.immediate
+                    .push_back(TileRequest::Evicted { layer, index });
+            }
+            BatchTileRequest::End => self.immediate.push_back(TileRequest::End),
+        }
+    }
+
+    /// Removes the most urgent pending tile from the queue, dropping the tiles
+    /// that aren't wanted anymore.
+    fn pop_most_urgent(&mut self) -> Option<TileRequest> {
+        let current = self.current.read().unwrap();
+        let mut most_urgent: Option<(usize, TileIndex, TilePriority)> = None;
+        self.pending
+            .retain(|&(layer, index)| match current.category(layer, &index) {
+                None => {
+                    debug!("Drop {index:?} of layer #{layer}");
+                    false
+                }
+                Some(category) => {
+                    let priority = current.priority(category, &index);
+                    if most_urgent.is_none_or(|(_, _, most_urgent)| priority < most_urgent) {
+                        most_urgent = Some((layer, index, priority));
                     }
-                    _ => Some(tile_request),
+                    true
+                }
+            });
+
+        let (layer, index, priority) = most_urgent?;
+        self.pending.remove(&(layer, index));
+        Some(match priority.category {
+            TileCategory::Visible | TileCategory::Ancestor => {
+                debug!("Request {index:?} of layer #{layer}");
+                TileRequest::Tile { layer, index }
+            }
+            TileCategory::Speculative => {
+                debug!("Speculate {index:?} of layer #{layer}");
+                TileRequest::Speculate { layer, index }
+            }
+        })
+    }
+}
+
+impl TileWatcher {
+    /// Resolves once the given tile of the given map layer is neither visible
+    /// nor speculated anymore, so that fetching it can be cancelled.
+    pub async fn unwanted(&self, layer: usize, index: TileIndex) {
+        let mut updated_rx = self.updated_rx.clone();
+        updated_rx.mark_unchanged();
+        while self.current.read().unwrap().contains(layer, &index) {
+            if updated_rx.changed().await.is_err() {
+                // The UI closed the channel, so the tile won't become unwanted anymore.
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    fn tile(z: u32, x: u32, y: u32) -> TileIndex {
+        TileIndex { z, x, y }
+    }
+
+    #[test]
+    fn tile_requests_priority() {
+        let (tx, rx) = tile_channel();
+        let mut stream = Box::pin(rx.into_stream());
+        let mut next_request = || stream.next().now_or_never();
+
+        tx.request_tiles(
+            &[0, 1],
+            Some(VisibleTiles {
+                center: [0.6, 0.6],
+                tiles: Box::new([tile(2, 0, 0), tile(2, 2, 2)]),
+                ancestors: Box::new([tile(1, 1, 1), tile(0, 0, 0)]),
+            }),
+            Box::new([tile(2, 3, 3)]),
+        )
+        .unwrap();
+
+        // Visible tiles nearest the center first, in any layer order.
+        for index in [tile(2, 2, 2), tile(2, 0, 0)] {
+            let mut layers: Vec<usize> = (0..2)
+                .map(|_| match next_request() {
+                    Some(Some(TileRequest::Tile { layer, index: i })) if i == index => layer,
+                    request => panic!("Unexpected request: {request:?}"),
                 })
-            })
+                .collect();
+            layers.sort();
+            assert_eq!(layers, [0, 1]);
+        }
+
+        // Ancestors are only requested for the base layer, coarsest first.
+        assert_eq!(
+            next_request(),
+            Some(Some(TileRequest::Tile {
+                layer: 0,
+                index: tile(0, 0, 0)
+            }))
+        );
+
+        // Evictions are forwarded before pending tiles.
+        tx.evict_tile(1, tile(3, 0, 0)).unwrap();
+        assert_eq!(
+            next_request(),
+            Some(Some(TileRequest::Evicted {
+                layer: 1,
+                index: tile(3, 0, 0)
+            }))
+        );
+
+        // Updating the current tiles re-prioritizes the pending requests, so
+        // the remaining ancestor is dropped.
+        tx.request_tiles(
+            &[0],
+            Some(VisibleTiles {
+                center: [0.5, 0.5],
+                tiles: Box::new([]),
+                ancestors: Box::new([]),
+            }),
+            Box::new([tile(2, 3, 3)]),
+        )
+        .unwrap();
+        assert_eq!(
+            next_request(),
+            Some(Some(TileRequest::Speculate {
+                layer: 0,
+                index: tile(2, 3, 3)
+            }))
+        );
+        assert_eq!(next_request(), None);
+
+        tx.close().unwrap();
+        assert_eq!(next_request(), Some(None));
     }
 }
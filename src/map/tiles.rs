@@ -1,20 +1,42 @@
 //! Background service to request tiles from a map provider.
 
-use super::tile_channel::{TileRequest, TileRequestReceiver};
-use crate::caching::cache::Cache;
-use crate::config::MapProvider;
-use crate::ui::util::decode_png;
+use super::local::LocalTiles;
+use super::rate_limit::{backoff_delay, parse_retry_after, RateLimiter};
+use super::tile_channel::{TileRequest, TileRequestReceiver, TileWatcher};
+use crate::caching::cache::{Cache, TileMetadata};
+use crate::config::{MapProvider, TileFormat};
+use crate::ui::util::{decode_image, upscale_tile};
 use crate::ui::UiMessage;
-use anyhow::bail;
 use anyhow::Context;
-use futures::StreamExt;
+use anyhow::{anyhow, bail};
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use futures::{select, StreamExt};
 use image::RgbaImage;
 use log::{debug, error, info, trace, warn};
-use reqwest::header::{REFERER, USER_AGENT};
-use reqwest::{Client, StatusCode};
-use std::collections::HashSet;
+use reqwest::header::{
+    CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, REFERER, RETRY_AFTER,
+    USER_AGENT,
+};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Maximum number of retries of a failed tile request.
+const MAX_RETRIES: u32 = 4;
+/// Timeout of each tile request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Duration during which a tile that the server reported as missing isn't
+/// requested again.
+const NOT_FOUND_TTL: Duration = Duration::from_secs(3600);
+/// Maximum number of zoom levels by which a cached ancestor is scaled up in
+/// offline mode, to replace a tile that isn't cached.
+const MAX_UPSCALE_LEVELS: u32 = 6;
 
 /// Index of a tile in Mercator coordinates.
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -50,35 +72,126 @@ impl TileIndex {
     }
 }
 
+/// Error of a tile that the server reported as missing, e.g. outside of the
+/// provider's coverage.
+#[derive(Clone, Copy, Debug)]
+pub struct TileNotFound {
+    /// Missing tile.
+    index: TileIndex,
+}
+
+impl fmt::Display for TileNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tile {:?} was reported as missing by the server",
+            self.index
+        )
+    }
+}
+
+impl std::error::Error for TileNotFound {}
+
+/// Outcome of prefetching a tile into the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prefetched {
+    /// The tile was downloaded (or revalidated).
+    Downloaded,
+    /// An up-to-date copy of the tile was already cached.
+    Cached,
+    /// The server reported the tile as missing, e.g. outside of its coverage.
+    Missing,
+}
+
+/// Result of fetching a tile from the network, shared between all the callers
+/// requesting this tile concurrently.
+type SharedFetch = Shared<oneshot::Receiver<Result<(TileFormat, Box<[u8]>), Arc<anyhow::Error>>>>;
+
 /// Handle to access the tiles.
 pub struct Tiles<'a> {
-    /// Provider to fetch the tiles from (on the Internet).
-    map_provider: &'a MapProvider,
+    /// Providers to fetch the tiles from (on the Internet), indexed by the map
+    /// layer of each [`TileRequest`].
+    map_providers: &'a [MapProvider],
+    /// Opened local sources of the map providers that have one, indexed by map
+    /// layer.
+    local_tiles: Vec<Option<LocalTiles>>,
     /// On-disk cache of tiles.
     cache: Option<&'a Cache>,
     /// Network client.
     client: &'a Client,
     /// Channel to send tiles to the UI thread.
     ui_tx: &'a Sender<UiMessage>,
-    /// Set of currently requested tiles.
-    requested: Mutex<HashSet<TileIndex>>,
+    /// Set of currently requested tiles, with their map layer.
+    requested: Mutex<HashSet<(usize, TileIndex)>>,
+    /// Number of tile requests currently being processed.
+    pending: AtomicUsize,
+    /// Rate limiters of the map providers, indexed by map layer.
+    rate_limiters: Vec<RateLimiter>,
+    /// Tiles that the server reported as missing (e.g. outside of the
+    /// provider's coverage), with their map layer and the time of the report.
+    not_found: Mutex<HashMap<(usize, TileIndex), Instant>>,
+    /// Tiles currently being fetched from the network, with their map layer.
+    in_flight: Mutex<HashMap<(usize, TileIndex), SharedFetch>>,
+    /// Whether to only serve tiles from the local sources and the cache,
+    /// without accessing the network.
+    offline: bool,
+}
+
+/// Removes a tile from the [`Tiles::in_flight`] requests when its fetch
+/// completes or is cancelled.
+struct InFlightGuard<'a> {
+    /// Map of in-flight requests.
+    in_flight: &'a Mutex<HashMap<(usize, TileIndex), SharedFetch>>,
+    /// Map layer and index of the fetched tile.
+    key: (usize, TileIndex),
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.key);
+    }
 }
 
 impl<'a> Tiles<'a> {
-    /// Creates a new handle to fetch tiles.
+    /// Creates a new handle to fetch tiles, opening the local sources of the
+    /// given map providers.
+    ///
+    /// In offline mode, tiles are never fetched from the network.
     pub fn new(
-        map_provider: &'a MapProvider,
+        map_providers: &'a [MapProvider],
         cache: Option<&'a Cache>,
         client: &'a Client,
         ui_tx: &'a Sender<UiMessage>,
-    ) -> Self {
-        Self {
-            map_provider,
+        offline: bool,
+    ) -> anyhow::Result<Self> {
+        let local_tiles = map_providers
+            .iter()
+            .enumerate()
+            .map(|(i, map_provider)| {
+                map_provider
+                    .local
+                    .as_ref()
+                    .map(LocalTiles::open)
+                    .transpose()
+                    .with_context(|| format!("Failed to open local source of map provider #{i}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            map_providers,
+            local_tiles,
             cache,
             client,
             ui_tx,
             requested: Mutex::new(HashSet::new()),
-        }
+            pending: AtomicUsize::new(0),
+            rate_limiters: map_providers
+                .iter()
+                .map(|map_provider| RateLimiter::new(map_provider.max_requests_per_second))
+                .collect(),
+            not_found: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            offline,
+        })
     }
 
     /// Loop that fetches the tiles requested by the given
@@ -86,14 +199,18 @@ impl<'a> Tiles<'a> {
     ///
     /// This loop terminates when the EOF message is sent through the tile
     /// request channel.
+    ///
+    /// Fetching a tile is cancelled once the UI doesn't display nor speculate
+    /// it anymore.
     pub async fn query_loop(
         &self,
         tiles_rx: TileRequestReceiver,
         parallel_requests: usize,
     ) -> anyhow::Result<()> {
+        let watcher = tiles_rx.watcher();
         tiles_rx
             .into_stream()
-            .map(|tile_request| async move { self.get_tile(tile_request).await })
+            .map(|tile_request| self.get_tile(tile_request, &watcher))
             .buffer_unordered(parallel_requests)
             .for_each(|result| async {
                 match result {
@@ -107,35 +224,30 @@ impl<'a> Tiles<'a> {
         Ok(())
     }
 
-    /// Processes the given tile request.
-    async fn get_tile(&self, tile_request: TileRequest) -> anyhow::Result<()> {
+    /// Processes the given tile request, until the given [`TileWatcher`]
+    /// reports that the tile isn't wanted anymore.
+    async fn get_tile(
+        &self,
+        tile_request: TileRequest,
+        watcher: &TileWatcher,
+    ) -> anyhow::Result<()> {
         match tile_request {
-            TileRequest::Evicted(index) => {
-                if !self.requested.lock().unwrap().remove(&index) {
-                    warn!("Tile {index:?} was already evicted");
+            TileRequest::Evicted { layer, index } => {
+                if !self.requested.lock().unwrap().remove(&(layer, index)) {
+                    warn!("Tile {index:?} of layer #{layer} was already evicted");
                 }
             }
-            TileRequest::Speculate(index) | TileRequest::Tile(index) => {
-                // TODO: don't speculatively request on network.
-                let is_new = self.requested.lock().unwrap().insert(index);
+            TileRequest::Speculate { layer, index } | TileRequest::Tile { layer, index } => {
+                let speculative = matches!(tile_request, TileRequest::Speculate { .. });
+                let is_new = self.requested.lock().unwrap().insert((layer, index));
                 if is_new {
-                    debug!("New tile {:?}", index);
-                    match self.get_tile_png(&index).await {
-                        Ok((png_image, rgba_image)) => {
-                            debug!("Sending tile {index:?} to UI = {} bytes", png_image.len());
-                            self.ui_tx.send(UiMessage::Tile {
-                                index,
-                                png_image,
-                                rgba_image,
-                            })?;
-                        }
-                        Err(e) => {
-                            error!("Requesting tile {index:?} returned an error: {e}");
-                            // TODO: send error
-                        }
-                    }
+                    debug!("New tile {index:?} of layer #{layer}");
+                    self.send_progress(self.pending.fetch_add(1, Ordering::Relaxed) + 1)?;
+                    let result = self.get_new_tile(layer, index, speculative, watcher).await;
+                    self.send_progress(self.pending.fetch_sub(1, Ordering::Relaxed) - 1)?;
+                    result?;
                 } else {
-                    trace!("Tile {index:?} already requested");
+                    trace!("Tile {index:?} of layer #{layer} already requested");
                 }
             }
             _ => error!("Unexpected tile request: {tile_request:?}"),
@@ -143,62 +255,556 @@ impl<'a> Tiles<'a> {
         Ok(())
     }
 
-    /// Fetches the given tile, and decodes it as a PNG image.
-    async fn get_tile_png(&self, index: &TileIndex) -> anyhow::Result<(Box<[u8]>, RgbaImage)> {
-        let bytes = self.get_tile_index(index).await?;
-        debug!("Decoding tile {index:?} = {} bytes", bytes.len());
-        let rgba_image = decode_png(bytes.as_ref())
-            .with_context(|| format!("Failed to decode PNG data for tile: {index:?}"))?;
-        Ok((bytes, rgba_image))
+    /// Processes the given tile of the given map layer, that wasn't requested
+    /// yet, reporting any error to the UI.
+    async fn get_new_tile(
+        &self,
+        layer: usize,
+        index: TileIndex,
+        speculative: bool,
+        watcher: &TileWatcher,
+    ) -> anyhow::Result<()> {
+        if self.offline {
+            return self.get_offline_tile(layer, index).await;
+        }
+
+        let tile = select!(
+            tile = self.get_tile_index(layer, &index, speculative).fuse() => tile,
+            () = watcher.unwanted(layer, index).fuse() => {
+                debug!("Cancelled tile {index:?} of layer #{layer}");
+                // The tile can be requested again later.
+                self.requested.lock().unwrap().remove(&(layer, index));
+                return Ok(());
+            },
+        );
+        match tile {
+            Ok(None) => {
+                debug!("Skipped speculative tile {index:?} of layer #{layer}");
+                // The tile can be speculated again later.
+                self.requested.lock().unwrap().remove(&(layer, index));
+            }
+            Ok(Some((raw_image, rgba_image, stale))) => {
+                debug!("Sending tile {index:?} to UI = {} bytes", raw_image.len());
+                self.ui_tx.send(UiMessage::Tile {
+                    layer,
+                    index,
+                    raw_image,
+                    rgba_image,
+                })?;
+                // The stale tile is displayed until the server confirms or
+                // replaces it.
+                if let Some(metadata) = stale {
+                    self.revalidate_tile(layer, index, &metadata, watcher)
+                        .await?;
+                }
+            }
+            Err(e) if e.is::<TileNotFound>() => {
+                debug!("Tile {index:?} of layer #{layer} is missing on the server");
+                // The tile can be requested again later, within the limits of
+                // the negative cache of missing tiles.
+                self.requested.lock().unwrap().remove(&(layer, index));
+                self.ui_tx.send(UiMessage::TileMissing { layer, index })?;
+            }
+            Err(e) => {
+                error!("Requesting tile {index:?} returned an error: {e}");
+                // The tile can be requested again later.
+                self.requested.lock().unwrap().remove(&(layer, index));
+                self.ui_tx.send(UiMessage::TileError {
+                    layer,
+                    index,
+                    error: e.to_string(),
+                })?;
+            }
+        }
+        Ok(())
     }
 
-    /// Fetches the given tile from the local cache or the network.
-    async fn get_tile_index(&self, index: &TileIndex) -> anyhow::Result<Box<[u8]>> {
+    /// Processes the given tile of the given map layer without accessing the
+    /// network, falling back to scaling up a cached ancestor.
+    ///
+    /// Tiles that aren't available offline are reported to the UI as missing.
+    async fn get_offline_tile(&self, layer: usize, index: TileIndex) -> anyhow::Result<()> {
+        if let Some((format, raw_image)) = self.get_offline_tile_index(layer, &index).await {
+            match decode_tile(&raw_image, format) {
+                Ok(rgba_image) => {
+                    self.ui_tx.send(UiMessage::Tile {
+                        layer,
+                        index,
+                        raw_image,
+                        rgba_image,
+                    })?;
+                    return Ok(());
+                }
+                Err(e) => warn!("Tile {index:?} of layer #{layer} is corrupt: {e:?}"),
+            }
+        }
+
+        let mut ancestor = index;
+        for _ in 0..MAX_UPSCALE_LEVELS {
+            let Some(parent) = ancestor.parent() else {
+                break;
+            };
+            ancestor = parent;
+            let Some((format, raw_image)) = self.get_offline_tile_index(layer, &ancestor).await
+            else {
+                continue;
+            };
+            match decode_image(raw_image.as_ref(), format) {
+                Ok(rgba_image) => {
+                    debug!("Scaling up ancestor {ancestor:?} to replace tile {index:?}");
+                    self.ui_tx.send(UiMessage::Tile {
+                        layer,
+                        index,
+                        raw_image,
+                        rgba_image: upscale_tile(&rgba_image, &ancestor, &index),
+                    })?;
+                    return Ok(());
+                }
+                Err(e) => warn!("Failed to decode {format:?} data for tile {ancestor:?}: {e:?}"),
+            }
+        }
+
+        debug!("Tile {index:?} of layer #{layer} isn't available offline");
+        // The tile is reported as missing again if the UI requests it again.
+        self.requested.lock().unwrap().remove(&(layer, index));
+        self.ui_tx.send(UiMessage::TileMissing { layer, index })?;
+        Ok(())
+    }
+
+    /// Reads the given tile of the given map layer from the provider's local
+    /// source or the local cache, returning its format and raw bytes.
+    ///
+    /// Stale cached tiles are returned as is, without revalidation.
+    async fn get_offline_tile_index(
+        &self,
+        layer: usize,
+        index: &TileIndex,
+    ) -> Option<(TileFormat, Box<[u8]>)> {
+        if let Some(local_tiles) = &self.local_tiles[layer] {
+            return match local_tiles.get_tile(index).await {
+                Ok(tile) => Some(tile),
+                Err(e) => {
+                    debug!("Tile {index:?} isn't available in local source: {e}");
+                    None
+                }
+            };
+        }
+
+        let cached = self
+            .cache?
+            .get_tile(&self.map_providers[layer], index)
+            .await
+            .ok()?;
+        debug!(
+            "Obtained tile {index:?} from cache (stale = {})",
+            cached.stale
+        );
+        Some((cached.format, cached.data))
+    }
+
+    /// Sends the number of tile requests being processed to the UI.
+    fn send_progress(&self, pending: usize) -> anyhow::Result<()> {
+        self.ui_tx.send(UiMessage::TileProgress { pending })?;
+        Ok(())
+    }
+
+    /// Revalidates the given stale tile of the given map layer with the server,
+    /// sending the updated tile to the UI if it changed.
+    async fn revalidate_tile(
+        &self,
+        layer: usize,
+        index: TileIndex,
+        metadata: &TileMetadata,
+        watcher: &TileWatcher,
+    ) -> anyhow::Result<()> {
+        debug!("Revalidating stale tile {index:?} with server");
+        let tile = select!(
+            tile = self.fetch_tile(layer, &index, Some(metadata)).fuse() => tile,
+            () = watcher.unwanted(layer, index).fuse() => {
+                debug!("Cancelled revalidation of tile {index:?} of layer #{layer}");
+                return Ok(());
+            },
+        );
+        match tile {
+            Ok(Some((format, raw_image))) => self.send_tile(layer, index, format, raw_image)?,
+            Ok(None) => debug!("Tile {index:?} is up-to-date"),
+            Err(e) => error!("Revalidating tile {index:?} returned an error: {e}"),
+        }
+        Ok(())
+    }
+
+    /// Decodes the given tile of the given map layer as an image, and sends it
+    /// to the UI.
+    ///
+    /// Tiles that fail to decode are reported to the UI as errors.
+    fn send_tile(
+        &self,
+        layer: usize,
+        index: TileIndex,
+        format: TileFormat,
+        raw_image: Box<[u8]>,
+    ) -> anyhow::Result<()> {
+        debug!(
+            "Decoding tile {index:?} = {} bytes in {format:?} format",
+            raw_image.len()
+        );
+        match decode_tile(&raw_image, format) {
+            Ok(rgba_image) => {
+                debug!("Sending tile {index:?} to UI = {} bytes", raw_image.len());
+                self.ui_tx.send(UiMessage::Tile {
+                    layer,
+                    index,
+                    raw_image,
+                    rgba_image,
+                })?;
+            }
+            Err(e) => {
+                error!("Tile {index:?}: {e:?}");
+                self.ui_tx.send(UiMessage::TileError {
+                    layer,
+                    index,
+                    error: e.to_string(),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches the given tile of the given map layer from the provider's local
+    /// source, the local cache or the network, returning its raw bytes and
+    /// decoded image.
+    ///
+    /// If the tile was served from the cache but is stale, its cached
+    /// [`TileMetadata`] is returned as well, to revalidate it. Cached tiles that
+    /// fail to decode are deleted from the cache and fetched again.
+    ///
+    /// Speculative tiles are only fetched from the network when no other tile
+    /// is being downloaded, otherwise `None` is returned.
+    async fn get_tile_index(
+        &self,
+        layer: usize,
+        index: &TileIndex,
+        speculative: bool,
+    ) -> anyhow::Result<Option<(Box<[u8]>, RgbaImage, Option<TileMetadata>)>> {
+        let map_provider = &self.map_providers[layer];
+
+        // Local sources are already on disk, so their tiles aren't cached.
+        if let Some(local_tiles) = &self.local_tiles[layer] {
+            debug!("Reading tile {index:?} from local source");
+            let (format, bytes) = local_tiles.get_tile(index).await?;
+            let rgba_image = decode_tile(&bytes, format)?;
+            return Ok(Some((bytes, rgba_image, None)));
+        }
+
         if let Some(cache) = self.cache {
-            let cached = cache.get_tile(index);
-            if cached.is_ok() {
-                debug!("Obtained tile {index:?} from cache");
-                return cached;
+            if let Ok(cached) = cache.get_tile(map_provider, index).await {
+                debug!(
+                    "Obtained tile {index:?} from cache (stale = {})",
+                    cached.stale
+                );
+                match decode_tile(&cached.data, cached.format) {
+                    Ok(rgba_image) => {
+                        let stale = cached.stale.then_some(cached.metadata);
+                        return Ok(Some((cached.data, rgba_image, stale)));
+                    }
+                    Err(e) => {
+                        warn!("Deleting corrupt tile {index:?} from cache: {e:?}");
+                        if let Err(e) = cache.delete_tiles(map_provider, vec![*index]) {
+                            error!("Couldn't delete tile {index:?} from cache: {e:?}");
+                        }
+                    }
+                }
             }
         }
 
-        // TODO: only request once from server
+        self.check_not_found(layer, index)?;
+
+        if speculative && !self.in_flight.lock().unwrap().is_empty() {
+            return Ok(None);
+        }
+
         debug!("Requesting tile {index:?} from server");
+        let (format, bytes) = self.fetch_tile_once(layer, index).await?;
+        let rgba_image = decode_tile(&bytes, format)?;
+        Ok(Some((bytes, rgba_image, None)))
+    }
 
-        let url = format!(
-            "https://{server}/{z}/{x}/{y}{extension}",
-            server = self.map_provider.server,
-            z = index.z,
-            x = index.x,
-            y = index.y,
-            extension = self.map_provider.extension
-        );
+    /// Downloads the given tile of the given map layer into the cache, unless an
+    /// up-to-date copy is already cached.
+    ///
+    /// Cached tiles that fail to decode are downloaded again.
+    pub async fn prefetch_tile(
+        &self,
+        layer: usize,
+        index: &TileIndex,
+    ) -> anyhow::Result<Prefetched> {
+        let cache = self
+            .cache
+            .context("A cache is required to prefetch tiles")?;
+        let metadata = match cache.get_tile(&self.map_providers[layer], index).await {
+            Ok(cached) if decode_tile(&cached.data, cached.format).is_err() => {
+                warn!("Downloading corrupt tile {index:?} again");
+                None
+            }
+            Ok(cached) if !cached.stale => return Ok(Prefetched::Cached),
+            Ok(cached) => Some(cached.metadata),
+            Err(_) => None,
+        };
+        let result = match self.check_not_found(layer, index) {
+            Ok(()) => self.fetch_tile(layer, index, metadata.as_ref()).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => Ok(Prefetched::Downloaded),
+            Err(e) if e.is::<TileNotFound>() => Ok(Prefetched::Missing),
+            Err(e) => Err(e),
+        }
+    }
 
-        let mut request = self.client.get(&url);
-        if let Some(user_agent) = &self.map_provider.user_agent {
-            request = request.header(USER_AGENT, user_agent);
+    /// Fails with [`TileNotFound`] if the server recently reported the given
+    /// tile of the given map layer as missing.
+    fn check_not_found(&self, layer: usize, index: &TileIndex) -> anyhow::Result<()> {
+        if let Some(reported) = self.not_found.lock().unwrap().get(&(layer, *index)) {
+            if reported.elapsed() < NOT_FOUND_TTL {
+                return Err(TileNotFound { index: *index }.into());
+            }
         }
-        if let Some(referer) = &self.map_provider.referer {
-            request = request.header(REFERER, referer);
+        Ok(())
+    }
+
+    /// Fetches the given tile of the given map layer from the network, sharing
+    /// the request with any concurrent caller fetching the same tile.
+    async fn fetch_tile_once(
+        &self,
+        layer: usize,
+        index: &TileIndex,
+    ) -> anyhow::Result<(TileFormat, Box<[u8]>)> {
+        let key = (layer, *index);
+        loop {
+            let (shared, tx) = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(&key) {
+                    Some(shared) => (shared.clone(), None),
+                    None => {
+                        let (tx, rx) = oneshot::channel();
+                        let shared = rx.shared();
+                        in_flight.insert(key, shared.clone());
+                        (shared, Some(tx))
+                    }
+                }
+            };
+
+            let Some(tx) = tx else {
+                debug!("Tile {index:?} of layer #{layer} is already being fetched");
+                match shared.await {
+                    Ok(result) => return result.map_err(|e| unshare_error(&e)),
+                    // The other request was cancelled, try again.
+                    Err(oneshot::Canceled) => continue,
+                }
+            };
+
+            let _guard = InFlightGuard {
+                in_flight: &self.in_flight,
+                key,
+            };
+            let result = self
+                .fetch_tile(layer, index, None)
+                .await
+                .and_then(|tile| {
+                    tile.with_context(|| format!("Tile server didn't return tile {index:?}"))
+                })
+                .map_err(Arc::new);
+            // Other callers may have stopped waiting for this tile.
+            let _ = tx.send(result.clone());
+            return result.map_err(|e| unshare_error(&e));
         }
-        let response = request
-            .send()
-            .await
-            .with_context(|| format!("Failed to request tile {index:?} from the server"))?;
+    }
+
+    /// Requests the given tile of the given map layer from the server of its
+    /// provider, and writes it to the cache.
+    ///
+    /// If the `cached` metadata of a previous version is given, the request is
+    /// conditional, and `None` is returned if the server confirms that the
+    /// cached tile is unchanged.
+    async fn fetch_tile(
+        &self,
+        layer: usize,
+        index: &TileIndex,
+        cached: Option<&TileMetadata>,
+    ) -> anyhow::Result<Option<(TileFormat, Box<[u8]>)>> {
+        let map_provider = &self.map_providers[layer];
+        let response = self
+            .send_with_retries(layer, index, || {
+                Self::tile_request(self.client, map_provider, index, cached)
+            })
+            .await?;
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let content_type = header(CONTENT_TYPE);
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
 
         let status_code = response.status();
+        if let (StatusCode::NOT_MODIFIED, Some(cached)) = (status_code, cached) {
+            // Keep the previous validators, unless the server sent new ones.
+            let metadata = TileMetadata::fetched_now(
+                etag.or_else(|| cached.etag.clone()),
+                last_modified.or_else(|| cached.last_modified.clone()),
+            );
+            if let Some(cache) = self.cache {
+                if let Err(e) = cache
+                    .set_tile_metadata(map_provider, index, &metadata)
+                    .await
+                {
+                    error!("Couldn't update metadata of tile {index:?} in cache: {e:?}");
+                }
+            }
+            return Ok(None);
+        }
+        if status_code == StatusCode::NOT_FOUND {
+            self.not_found
+                .lock()
+                .unwrap()
+                .insert((layer, *index), Instant::now());
+            return Err(TileNotFound { index: *index }.into());
+        }
         if status_code != StatusCode::OK {
-            error!("Tile server replied with status code {status_code}");
             bail!("Tile server replied with status code {status_code} for tile {index:?}");
         }
 
         let bytes = response.bytes().await?;
+
+        let format = match map_provider.format {
+            Some(format) => format,
+            None => content_type
+                .as_deref()
+                .and_then(TileFormat::from_mime_type)
+                .or_else(|| TileFormat::from_magic_bytes(bytes.as_ref()))
+                .with_context(|| {
+                    format!(
+                        "Failed to detect the image format of tile {index:?} (Content-Type = {content_type:?})"
+                    )
+                })?,
+        };
+
         if let Some(cache) = self.cache {
-            if let Err(e) = cache.set_tile(index, bytes.as_ref()) {
+            let metadata = TileMetadata::fetched_now(etag, last_modified);
+            if let Err(e) = cache
+                .set_tile(map_provider, index, format, bytes.as_ref(), &metadata)
+                .await
+            {
                 error!("Couldn't write tile {index:?} to cache: {e:?}");
             }
         }
 
-        Ok(Box::from(bytes.as_ref()))
+        Ok(Some((format, Box::from(bytes.as_ref()))))
     }
+
+    /// Builds the request of the given tile to the server of the given map
+    /// provider, conditional on the given `cached` metadata if any.
+    fn tile_request(
+        client: &Client,
+        map_provider: &MapProvider,
+        index: &TileIndex,
+        cached: Option<&TileMetadata>,
+    ) -> RequestBuilder {
+        let url = map_provider.tile_url(index);
+
+        let mut request = client.get(&url).timeout(REQUEST_TIMEOUT);
+        if let Some(user_agent) = &map_provider.user_agent {
+            request = request.header(USER_AGENT, user_agent);
+        }
+        if let Some(referer) = &map_provider.referer {
+            request = request.header(REFERER, referer);
+        }
+        if let Some(cached) = cached {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        request
+    }
+
+    /// Sends the request built by the given function to the server of the
+    /// given map layer, within the rate limit of its provider.
+    ///
+    /// Network errors, server errors (5xx) and rate limiting errors (429) are
+    /// retried with exponential backoff, waiting for the `Retry-After` delay
+    /// if the server specified one.
+    async fn send_with_retries(
+        &self,
+        layer: usize,
+        index: &TileIndex,
+        request: impl Fn() -> RequestBuilder,
+    ) -> anyhow::Result<Response> {
+        let rate_limiter = &self.rate_limiters[layer];
+        let mut attempt = 0;
+        loop {
+            rate_limiter.wait().await;
+            let delay = match request().send().await {
+                Ok(response) => {
+                    let status_code = response.status();
+                    if status_code != StatusCode::TOO_MANY_REQUESTS
+                        && !status_code.is_server_error()
+                    {
+                        return Ok(response);
+                    }
+                    if attempt == MAX_RETRIES {
+                        return Ok(response);
+                    }
+                    warn!("Tile server replied with status code {status_code} for tile {index:?}");
+                    let retry_after = response
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(parse_retry_after);
+                    match retry_after {
+                        Some(retry_after) => {
+                            // Pause all the requests to this provider.
+                            rate_limiter.pause_for(retry_after);
+                            retry_after
+                        }
+                        None => backoff_delay(attempt),
+                    }
+                }
+                Err(e) => {
+                    if attempt == MAX_RETRIES
+                        || !(e.is_timeout() || e.is_connect() || e.is_request())
+                    {
+                        return Err(e).with_context(|| {
+                            format!("Failed to request tile {index:?} from the server")
+                        });
+                    }
+                    warn!("Failed to request tile {index:?} from the server: {e}");
+                    backoff_delay(attempt)
+                }
+            };
+            attempt += 1;
+            debug!("Retrying request of tile {index:?} in {delay:?} (attempt #{attempt})");
+            sleep(delay).await;
+        }
+    }
+}
+
+/// Converts the given error shared between the callers fetching a tile back
+/// into an owned error, preserving a [`TileNotFound`] error.
+fn unshare_error(e: &anyhow::Error) -> anyhow::Error {
+    match e.downcast_ref::<TileNotFound>() {
+        Some(not_found) => (*not_found).into(),
+        None => anyhow!("{e:#}"),
+    }
+}
+
+/// Decodes the given raw tile in the given format as an RGBA image.
+fn decode_tile(raw_image: &[u8], format: TileFormat) -> anyhow::Result<RgbaImage> {
+    decode_image(raw_image, format).map_err(|e| anyhow!("Failed to decode {format:?} tile: {e}"))
 }
@@ -0,0 +1,191 @@
+//! Templates to build the URL of each tile requested to a map provider.
+
+use super::tiles::TileIndex;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+/// Placeholder or literal part of a [`UrlTemplate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    /// Literal string, copied as-is.
+    Literal(String),
+    /// `{z}`: zoom level.
+    Z,
+    /// `{x}`: index from West to East.
+    X,
+    /// `{y}`: index from North to South.
+    Y,
+    /// `{-y}`: index from South to North, as used by TMS servers.
+    FlippedY,
+    /// `{s}`: subdomain, rotating between the configured subdomains.
+    Subdomain,
+    /// `{quadkey}`: Bing-style quadtree key.
+    Quadkey,
+    /// `{r}`: suffix requesting high-resolution tiles (e.g. `@2x`).
+    Retina,
+}
+
+/// Template of tile URLs, such as `https://{s}.example.com/{z}/{x}/{y}{r}.png`.
+///
+/// The following placeholders are supported:
+/// - `{z}`, `{x}` and `{y}` for the tile index,
+/// - `{-y}` for the y index counted from the South, as used by TMS servers,
+/// - `{s}` for a subdomain, rotating between the provider's subdomains,
+/// - `{quadkey}` for the quadtree key used by Bing-style servers,
+/// - `{r}` for a retina suffix, empty unless the provider enables it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UrlTemplate {
+    parts: Vec<Part>,
+}
+
+impl UrlTemplate {
+    /// Parses a URL template, failing on unknown or unterminated placeholders.
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .with_context(|| format!("Unterminated placeholder in URL template: {template}"))?;
+            let part = match &rest[start + 1..start + end] {
+                "z" => Part::Z,
+                "x" => Part::X,
+                "y" => Part::Y,
+                "-y" => Part::FlippedY,
+                "s" => Part::Subdomain,
+                "quadkey" => Part::Quadkey,
+                "r" => Part::Retina,
+                name => bail!("Unknown placeholder {{{name}}} in URL template: {template}"),
+            };
+            parts.push(part);
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Checks whether this template contains a `{s}` placeholder.
+    pub fn has_subdomain(&self) -> bool {
+        self.parts.contains(&Part::Subdomain)
+    }
+
+    /// Builds the URL of the given tile.
+    ///
+    /// The subdomain is chosen deterministically among the given `subdomains`
+    /// for each tile, so that a tile is always requested at the same address.
+    pub fn expand(&self, index: &TileIndex, subdomains: &[String], retina: &str) -> String {
+        let mut url = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => url.push_str(literal),
+                Part::Z => url.push_str(&index.z.to_string()),
+                Part::X => url.push_str(&index.x.to_string()),
+                Part::Y => url.push_str(&index.y.to_string()),
+                Part::FlippedY => {
+                    let flipped = (1u32 << index.z) - 1 - index.y;
+                    url.push_str(&flipped.to_string())
+                }
+                Part::Subdomain => {
+                    if !subdomains.is_empty() {
+                        let i = (index.x as usize + index.y as usize) % subdomains.len();
+                        url.push_str(&subdomains[i]);
+                    }
+                }
+                Part::Quadkey => url.push_str(&quadkey(index)),
+                Part::Retina => url.push_str(retina),
+            }
+        }
+        url
+    }
+}
+
+impl TryFrom<String> for UrlTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        Self::parse(&template)
+    }
+}
+
+/// Computes the [quadtree key](https://learn.microsoft.com/en-us/bingmaps/articles/bing-maps-tile-system)
+/// of the given tile.
+fn quadkey(index: &TileIndex) -> String {
+    (1..=index.z)
+        .rev()
+        .map(|i| {
+            let mask = 1 << (i - 1);
+            let mut digit = b'0';
+            if index.x & mask != 0 {
+                digit += 1;
+            }
+            if index.y & mask != 0 {
+                digit += 2;
+            }
+            digit as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn url_template_xyz() {
+        let template = UrlTemplate::parse("https://example.com/{z}/{x}/{y}.png").unwrap();
+        assert_eq!(
+            template.expand(&TileIndex { z: 3, x: 5, y: 2 }, &[], ""),
+            "https://example.com/3/5/2.png"
+        );
+    }
+
+    #[test]
+    fn url_template_tms() {
+        let template = UrlTemplate::parse("{z}/{x}/{-y}").unwrap();
+        assert_eq!(
+            template.expand(&TileIndex { z: 0, x: 0, y: 0 }, &[], ""),
+            "0/0/0"
+        );
+        assert_eq!(
+            template.expand(&TileIndex { z: 3, x: 5, y: 2 }, &[], ""),
+            "3/5/5"
+        );
+    }
+
+    #[test]
+    fn url_template_subdomains_and_retina() {
+        let template = UrlTemplate::parse("https://{s}.example.com/{z}/{x}/{y}{r}.png").unwrap();
+        let subdomains = ["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        assert_eq!(
+            template.expand(&TileIndex { z: 1, x: 1, y: 0 }, &subdomains, "@2x"),
+            "https://b.example.com/1/1/0@2x.png"
+        );
+        assert_eq!(
+            template.expand(&TileIndex { z: 1, x: 1, y: 1 }, &subdomains, ""),
+            "https://c.example.com/1/1/1.png"
+        );
+    }
+
+    #[test]
+    fn url_template_quadkey() {
+        let template = UrlTemplate::parse("https://example.com/tiles/{quadkey}.jpeg").unwrap();
+        assert_eq!(
+            template.expand(&TileIndex { z: 0, x: 0, y: 0 }, &[], ""),
+            "https://example.com/tiles/.jpeg"
+        );
+        // Example from Bing's documentation.
+        assert_eq!(quadkey(&TileIndex { z: 3, x: 3, y: 5 }), "213");
+    }
+
+    #[test]
+    fn url_template_invalid() {
+        assert!(UrlTemplate::parse("https://example.com/{z}/{x}/{y").is_err());
+        assert!(UrlTemplate::parse("https://example.com/{zoom}/{x}/{y}").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Module to convert the dates and times of Strava's API to Unix timestamps.
+
+use anyhow::{bail, Context};
+
+/// Parses a UTC date and time in the ISO 8601 format used by Strava's API,
+/// e.g. `2024-05-18T07:30:00Z`, and returns the corresponding Unix timestamp in
+/// seconds.
+pub fn parse_date_time(date_time: &str) -> anyhow::Result<i64> {
+    let parse = || -> Option<i64> {
+        let (date, time) = date_time.strip_suffix('Z')?.split_once('T')?;
+        let days = parse_days(date)?;
+        let mut parts = time.split(':');
+        let hours: i64 = parts.next()?.parse().ok()?;
+        let minutes: i64 = parts.next()?.parse().ok()?;
+        let seconds: i64 = parts.next()?.parse().ok()?;
+        if parts.next().is_some() || hours >= 24 || minutes >= 60 || seconds >= 61 {
+            return None;
+        }
+        Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+    };
+    parse().with_context(|| format!("Invalid date and time: {date_time}"))
+}
+
+/// Parses either a UTC date in the `YYYY-MM-DD` format (at midnight), or a UTC
+/// date and time in the format of [`parse_date_time`], and returns the
+/// corresponding Unix timestamp in seconds.
+pub fn parse_date_or_date_time(date: &str) -> anyhow::Result<i64> {
+    if date.contains('T') {
+        parse_date_time(date)
+    } else {
+        parse_days(date)
+            .map(|days| days * 86400)
+            .with_context(|| format!("Invalid date: {date}"))
+    }
+}
+
+/// Parses a date in the `YYYY-MM-DD` format, and returns the number of days
+/// since the Unix epoch.
+fn parse_days(date: &str) -> Option<i64> {
+    let mut parts = date.split('-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    days_from_civil(year, month, day).ok()
+}
+
+/// Returns the number of days between the Unix epoch and the given date of the
+/// proleptic Gregorian calendar.
+///
+/// This follows Howard Hinnant's
+/// [algorithm](https://howardhinnant.github.io/date_algorithms.html#days_from_civil).
+fn days_from_civil(year: i64, month: i64, day: i64) -> anyhow::Result<i64> {
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        bail!("Invalid date: {year}-{month}-{day}");
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Ok(era * 146097 + day_of_era - 719468)
+}
+
+/// Returns the number of days in the given month (from 1 to 12) of the given
+/// year of the proleptic Gregorian calendar.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_date_time_valid() {
+        assert_eq!(parse_date_time("1970-01-01T00:00:00Z").unwrap(), 0);
+        assert_eq!(parse_date_time("2024-02-29T12:34:56Z").unwrap(), 1709210096);
+        assert_eq!(parse_date_time("1969-12-31T23:59:59Z").unwrap(), -1);
+    }
+
+    #[test]
+    fn parse_date_or_date_time_valid() {
+        assert_eq!(parse_date_or_date_time("2024-02-29").unwrap(), 1709164800);
+        assert_eq!(
+            parse_date_or_date_time("2024-02-29T12:34:56Z").unwrap(),
+            1709210096
+        );
+        assert!(parse_date_or_date_time("2024-02").is_err());
+        // Only leap years have a February 29th.
+        assert!(parse_date_or_date_time("2024-02-30").is_err());
+        assert!(parse_date_or_date_time("2023-02-29").is_err());
+        assert!(parse_date_or_date_time("1900-02-29").is_err());
+        assert_eq!(parse_date_or_date_time("2000-02-29").unwrap(), 951782400);
+        assert!(parse_date_or_date_time("2024-02-29T12:34").is_err());
+    }
+
+    #[test]
+    fn parse_date_time_invalid() {
+        assert!(parse_date_time("2024-02-29T12:34:56").is_err());
+        assert!(parse_date_time("2024-13-01T00:00:00Z").is_err());
+        assert!(parse_date_time("2024-04-31T00:00:00Z").is_err());
+        assert!(parse_date_time("2024-02-29 12:34:56Z").is_err());
+        assert!(parse_date_time("2024-02-29T12:34Z").is_err());
+    }
+}
@@ -0,0 +1,344 @@
+//! In-process stand-in for [Strava's API](https://developers.strava.com/docs/reference/), to test
+//! the Strava client without accessing the real service.
+
+use super::date::parse_date_time;
+use super::oauth::read_request_head;
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Strava application ID accepted by the fake server.
+pub const CLIENT_ID: &str = "1234";
+/// Client secret accepted by the fake server.
+pub const CLIENT_SECRET: &str = "0123abcd";
+/// OAuth authorization code accepted by the fake server.
+pub const AUTHORIZATION_CODE: &str = "fake-code";
+/// OAuth refresh token issued by the fake server.
+pub const REFRESH_TOKEN: &str = "fake-refresh-token";
+/// OAuth access token issued by the fake server.
+pub const ACCESS_TOKEN: &str = "fake-access-token";
+/// ID of the authenticated athlete.
+pub const ATHLETE_ID: u64 = 42;
+/// Rate limits of the fake server, in the 15-minute and daily windows.
+pub const RATE_LIMITS: [u64; 2] = [100, 1000];
+/// Encoded polyline of each activity, with 3 points.
+const POLYLINE: &str = "_p~iF~ps|U_ulLnnqC_mqNvxq`@";
+/// Lifetime of the access tokens issued by the fake server, in seconds.
+const TOKEN_LIFETIME: u64 = 6 * 60 * 60;
+
+/// Fake Strava server, listening on localhost until it's dropped.
+pub struct FakeStrava {
+    /// Address of the server.
+    addr: SocketAddr,
+    /// State of the server, shared with the task serving the requests.
+    state: Arc<Mutex<State>>,
+    /// Task accepting the connections.
+    task: JoinHandle<()>,
+}
+
+/// State of the fake server.
+struct State {
+    /// Number of activities of the athlete, with IDs from 1 to this number.
+    activity_count: u64,
+    /// Request lines (method and target) received so far.
+    requests: Vec<String>,
+    /// Number of requests to the API so far, counted by the rate limits.
+    api_requests: u64,
+}
+
+/// Response of the fake server.
+struct FakeResponse {
+    /// HTTP status line.
+    status: &'static str,
+    /// JSON body.
+    body: Value,
+    /// Whether to report the rate limits.
+    rate_limited: bool,
+}
+
+impl FakeResponse {
+    /// Returns a successful response with the given body.
+    fn ok(body: Value) -> Self {
+        Self {
+            status: "200 OK",
+            body,
+            rate_limited: true,
+        }
+    }
+
+    /// Returns a [Fault](https://developers.strava.com/docs/reference/#api-models-Fault)
+    /// response with the given status, message and error.
+    fn fault(status: &'static str, message: &str, resource: &str, field: &str, code: &str) -> Self {
+        Self {
+            status,
+            body: json!({
+                "message": message,
+                "errors": [{"resource": resource, "field": field, "code": code}],
+            }),
+            rate_limited: true,
+        }
+    }
+
+    /// Returns a response for a record that doesn't exist.
+    fn not_found(resource: &str) -> Self {
+        Self::fault(
+            "404 Not Found",
+            "Record Not Found",
+            resource,
+            "path",
+            "invalid",
+        )
+    }
+}
+
+impl FakeStrava {
+    /// Starts a fake server on localhost, for an athlete who recorded the
+    /// given number of activities (at most 28), started on consecutive days of
+    /// February 2024.
+    pub async fn start(activity_count: u64) -> Self {
+        assert!(activity_count <= 28);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State {
+            activity_count,
+            requests: Vec::new(),
+            api_requests: 0,
+        }));
+
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    tokio::spawn(handle_connection(socket, state.clone()));
+                }
+            }
+        });
+        Self { addr, state, task }
+    }
+
+    /// Returns the base URL of the API of this server.
+    pub fn api_url(&self) -> String {
+        format!("http://{}/api/v3", self.addr)
+    }
+
+    /// Returns the URL of the OAuth authorization page of this server.
+    pub fn authorize_url(&self) -> String {
+        format!("http://{}/oauth/authorize", self.addr)
+    }
+
+    /// Returns the URL of the OAuth token exchange of this server.
+    pub fn token_url(&self) -> String {
+        format!("http://{}/oauth/token", self.addr)
+    }
+
+    /// Returns the request lines (method and target) received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns the number of requests to the API so far, as counted by the
+    /// rate limits.
+    pub fn api_requests(&self) -> u64 {
+        self.state.lock().unwrap().api_requests
+    }
+}
+
+impl Drop for FakeStrava {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Returns the start date of the activity with the given ID.
+pub fn start_date(id: u64) -> String {
+    format!("2024-02-{id:02}T08:00:00Z")
+}
+
+/// Serves a request on the given connection, and closes it.
+async fn handle_connection(mut socket: TcpStream, state: Arc<Mutex<State>>) {
+    let Ok(head) = read_request_head(&mut socket).await else {
+        return;
+    };
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let authorized = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("authorization")
+                && value.trim() == format!("Bearer {ACCESS_TOKEN}")
+        })
+    });
+
+    let (response, usage) = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(format!("{method} {target}"));
+        let response = match Url::parse("http://localhost").and_then(|base| base.join(target)) {
+            Ok(url) => respond(&mut state, method, &url, authorized),
+            Err(_) => FakeResponse::not_found("Path"),
+        };
+        (response, state.api_requests)
+    };
+
+    let body = response.body.to_string();
+    let mut reply = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        body.len()
+    );
+    if response.rate_limited {
+        reply.push_str(&format!(
+            "X-RateLimit-Limit: {},{}\r\nX-RateLimit-Usage: {usage},{usage}\r\n",
+            RATE_LIMITS[0], RATE_LIMITS[1]
+        ));
+    }
+    reply.push_str("\r\n");
+    reply.push_str(&body);
+    // The client may have closed the connection already.
+    let _ = socket.write_all(reply.as_bytes()).await;
+    let _ = socket.shutdown().await;
+}
+
+/// Returns the response to the given request.
+fn respond(state: &mut State, method: &str, url: &Url, authorized: bool) -> FakeResponse {
+    let query = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    };
+
+    if url.path() == "/oauth/token" && method == "POST" {
+        let mut response = token(query);
+        response.rate_limited = false;
+        return response;
+    }
+    let Some(path) = url.path().strip_prefix("/api/v3/") else {
+        return FakeResponse::not_found("Path");
+    };
+
+    state.api_requests += 1;
+    if !authorized {
+        return FakeResponse::fault(
+            "401 Unauthorized",
+            "Authorization Error",
+            "Athlete",
+            "access_token",
+            "invalid",
+        );
+    }
+
+    let segments: Vec<&str> = path.split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["athlete"]) => FakeResponse::ok(json!({
+            "id": ATHLETE_ID,
+            "firstname": "Fake",
+            "lastname": "Athlete",
+            "bikes": [{"id": "b1", "name": "Gravel bike"}],
+            "shoes": [{"id": "g1", "name": "Trail shoes"}],
+        })),
+        ("GET", ["athlete", "activities"]) => {
+            let number = |key: &str| query(key).and_then(|v| v.parse::<i64>().ok());
+            let per_page = number("per_page").unwrap_or(30) as usize;
+            let page = number("page").unwrap_or(1).max(1) as usize;
+            let after = number("after");
+            let before = number("before");
+            let activities: Vec<Value> = (1..=state.activity_count)
+                .rev()
+                .filter(|&id| {
+                    let start = parse_date_time(&start_date(id)).unwrap();
+                    after.is_none_or(|after| start > after)
+                        && before.is_none_or(|before| start < before)
+                })
+                .skip((page - 1) * per_page)
+                .take(per_page)
+                .map(summary_activity)
+                .collect();
+            FakeResponse::ok(Value::Array(activities))
+        }
+        ("GET", ["activities", id]) => match id.parse::<u64>() {
+            Ok(id) if (1..=state.activity_count).contains(&id) => {
+                let mut activity = summary_activity(id);
+                activity["distance"] = json!(1000.0 * id as f64);
+                activity["moving_time"] = json!(600);
+                activity["elapsed_time"] = json!(660);
+                activity["total_elevation_gain"] = json!(10.0);
+                activity["workout_type"] = Value::Null;
+                activity["description"] = Value::Null;
+                activity["map"] = json!({
+                    "id": format!("a{id}"),
+                    "polyline": POLYLINE,
+                    "summary_polyline": POLYLINE,
+                });
+                FakeResponse::ok(activity)
+            }
+            _ => FakeResponse::not_found("Activity"),
+        },
+        _ => FakeResponse::not_found("Path"),
+    }
+}
+
+/// Returns the response to an OAuth token exchange or refresh, with the given
+/// query parameters.
+fn token(query: impl Fn(&str) -> Option<String>) -> FakeResponse {
+    if query("client_id").as_deref() != Some(CLIENT_ID)
+        || query("client_secret").as_deref() != Some(CLIENT_SECRET)
+    {
+        return FakeResponse::fault(
+            "401 Unauthorized",
+            "Authorization Error",
+            "Application",
+            "client_id",
+            "invalid",
+        );
+    }
+    let granted = match query("grant_type").as_deref() {
+        Some("authorization_code") => query("code").as_deref() == Some(AUTHORIZATION_CODE),
+        Some("refresh_token") => query("refresh_token").as_deref() == Some(REFRESH_TOKEN),
+        _ => false,
+    };
+    if !granted {
+        return FakeResponse::fault(
+            "400 Bad Request",
+            "Bad Request",
+            "AuthorizationCode",
+            "code",
+            "invalid",
+        );
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    FakeResponse::ok(json!({
+        "token_type": "Bearer",
+        "expires_at": now + TOKEN_LIFETIME,
+        "expires_in": TOKEN_LIFETIME,
+        "refresh_token": REFRESH_TOKEN,
+        "access_token": ACCESS_TOKEN,
+    }))
+}
+
+/// Returns the summary of the activity with the given ID: odd activities are
+/// gravel rides, and even activities are trail runs.
+fn summary_activity(id: u64) -> Value {
+    let (r#type, sport_type, gear_id) = if id % 2 == 1 {
+        ("Ride", "GravelRide", "b1")
+    } else {
+        ("Run", "TrailRun", "g1")
+    };
+    json!({
+        "id": id,
+        "name": format!("Activity {id}"),
+        "type": r#type,
+        "sport_type": sport_type,
+        "start_date": start_date(id),
+        "gear_id": gear_id,
+    })
+}
@@ -1,12 +1,13 @@
 //! Module to extract GPS tracks from a GeoJSON file.
 
 use super::polyline::{LatLon, LatLonLine, ToMercator};
-use super::schema::ActivityType;
+use super::schema::SportType;
+use super::{ProgressReporter, TrackAttributes, TrackLayer};
 use crate::ui::UiMessage;
 use anyhow::Context;
 use futures::{stream, StreamExt};
 use geojson::{Feature, FeatureCollection, GeoJson, Geometry, LineStringType, Value};
-use log::{debug, error, trace};
+use log::{debug, trace};
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
@@ -109,32 +110,33 @@ pub async fn get_tracks_parallel(
     files: &[String],
     parallel_requests: usize,
 ) -> anyhow::Result<()> {
-    let tracks = stream::iter(files)
+    let progress = ProgressReporter::new(tx, Some(files.len()))?;
+    let mut tracks = stream::iter(files)
         .enumerate()
         .map(|(i, path)| async move { get_tracks(path.clone(), i).await.map(|t| (i, t)) })
         .buffer_unordered(parallel_requests);
 
-    tracks
-        .for_each(|tracks| async {
-            match tracks {
-                Ok((i, tracks)) => {
-                    debug!("GeoJson has {} tracks", tracks.len());
-                    for track in tracks {
-                        trace!("Track = {track:#?}");
-                        debug!("Polyline has {} points", track.line.len());
-                        tx.send(UiMessage::Activity {
-                            id: i,
-                            // TODO: Track type?
-                            r#type: ActivityType::Ride,
-                            points: track.line.mercator_points(),
-                        })
-                        .unwrap();
-                    }
+    while let Some(tracks) = tracks.next().await {
+        match tracks {
+            Ok((i, tracks)) => {
+                debug!("GeoJson has {} tracks", tracks.len());
+                for track in tracks {
+                    trace!("Track = {track:#?}");
+                    debug!("Polyline has {} points", track.line.len());
+                    tx.send(UiMessage::Activity {
+                        id: i,
+                        layer: TrackLayer::Activity,
+                        // TODO: Track type?
+                        sport_type: SportType::Ride,
+                        points: track.line.mercator_points(),
+                        attributes: TrackAttributes::new(),
+                    })?;
                 }
-                Err(e) => error!("Got an error: {e}"),
+                progress.loaded()?;
             }
-        })
-        .await;
+            Err(e) => progress.failed(&e)?,
+        }
+    }
 
     Ok(())
 }
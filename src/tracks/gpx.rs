@@ -1,11 +1,12 @@
 //! Module to extract a GPS track from a GPX file.
 
 use super::polyline::{LatLon, LatLonLine, ToMercator};
-use super::schema::ActivityType;
+use super::schema::SportType;
+use super::{ProgressReporter, TrackAttributes, TrackLayer};
 use crate::ui::UiMessage;
 use anyhow::Context;
 use futures::{stream, StreamExt};
-use log::{debug, error, trace};
+use log::{debug, trace};
 use serde::Deserialize;
 use std::fs::File;
 use std::io::BufReader;
@@ -93,29 +94,30 @@ pub async fn get_tracks_parallel(
     files: &[String],
     parallel_requests: usize,
 ) -> anyhow::Result<()> {
-    let tracks = stream::iter(files)
+    let progress = ProgressReporter::new(tx, Some(files.len()))?;
+    let mut tracks = stream::iter(files)
         .enumerate()
         .map(|(i, path)| async move { get_track(path.clone(), i).await.map(|t| (i, t)) })
         .buffer_unordered(parallel_requests);
 
-    tracks
-        .for_each(|track| async {
-            match track {
-                Ok((i, track)) => {
-                    trace!("Track = {:#?}", track);
-                    debug!("Polyline has {} points", track.line.len());
-                    tx.send(UiMessage::Activity {
-                        id: i,
-                        // TODO: Track type?
-                        r#type: ActivityType::Ride,
-                        points: track.line.mercator_points(),
-                    })
-                    .unwrap();
-                }
-                Err(e) => error!("Got an error: {e}"),
+    while let Some(track) = tracks.next().await {
+        match track {
+            Ok((i, track)) => {
+                trace!("Track = {:#?}", track);
+                debug!("Polyline has {} points", track.line.len());
+                tx.send(UiMessage::Activity {
+                    id: i,
+                    layer: TrackLayer::Activity,
+                    // TODO: Track type?
+                    sport_type: SportType::Ride,
+                    points: track.line.mercator_points(),
+                    attributes: TrackAttributes::new(),
+                })?;
+                progress.loaded()?;
             }
-        })
-        .await;
+            Err(e) => progress.failed(&e)?,
+        }
+    }
 
     Ok(())
 }
@@ -1,7 +1,136 @@
 //! Module to manage and fetch GPS tracks from various providers.
 
+pub mod date;
+#[cfg(test)]
+mod fake_strava;
 pub mod geojson;
 pub mod gpx;
+pub mod oauth;
 pub mod polyline;
+pub mod rate_limit;
 pub mod schema;
 pub mod strava;
+
+use crate::ui::UiMessage;
+use log::error;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+
+/// Layer of the map on which a track is displayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrackLayer {
+    /// A recorded activity.
+    Activity,
+    /// A planned route.
+    Route,
+    /// A segment.
+    Segment,
+}
+
+impl TrackLayer {
+    /// All the track layers, from the bottom to the top of the map.
+    pub const ALL: [TrackLayer; 3] = [TrackLayer::Activity, TrackLayer::Route, TrackLayer::Segment];
+}
+
+/// Attribute recorded at each point of a track.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrackAttribute {
+    /// Time elapsed since the start of the track, in seconds.
+    Time,
+    /// Altitude, in meters.
+    Altitude,
+    /// Heart rate, in beats per minute.
+    HeartRate,
+    /// Power, in watts.
+    Power,
+    /// Speed, in meters per second.
+    Speed,
+}
+
+impl TrackAttribute {
+    /// All the track attributes.
+    pub const ALL: [TrackAttribute; 5] = [
+        TrackAttribute::Time,
+        TrackAttribute::Altitude,
+        TrackAttribute::HeartRate,
+        TrackAttribute::Power,
+        TrackAttribute::Speed,
+    ];
+
+    /// Returns a human-readable name of this attribute.
+    pub fn name(self) -> &'static str {
+        match self {
+            TrackAttribute::Time => "time",
+            TrackAttribute::Altitude => "altitude",
+            TrackAttribute::HeartRate => "heart rate",
+            TrackAttribute::Power => "power",
+            TrackAttribute::Speed => "speed",
+        }
+    }
+}
+
+/// Values of the attributes recorded at each point of a track, each with
+/// exactly one (possibly missing) value per point.
+pub type TrackAttributes = HashMap<TrackAttribute, Vec<Option<f64>>>;
+
+/// Counts the tracks loaded from a source, and reports the progress and errors
+/// to the UI.
+pub struct ProgressReporter<'a> {
+    /// Channel to send the progress to the UI thread.
+    tx: &'a Sender<UiMessage>,
+    /// Number of tracks successfully loaded so far.
+    loaded: Cell<usize>,
+    /// Number of tracks that failed to load so far.
+    failed: Cell<usize>,
+    /// Total number of tracks to load, if known.
+    total: Cell<Option<usize>>,
+}
+
+impl<'a> ProgressReporter<'a> {
+    /// Creates a new reporter for the given total number of tracks (if known),
+    /// and reports the start of loading to the UI.
+    pub fn new(tx: &'a Sender<UiMessage>, total: Option<usize>) -> anyhow::Result<Self> {
+        let reporter = Self {
+            tx,
+            loaded: Cell::new(0),
+            failed: Cell::new(0),
+            total: Cell::new(total),
+        };
+        reporter.send_progress()?;
+        Ok(reporter)
+    }
+
+    /// Reports that a track was successfully loaded.
+    pub fn loaded(&self) -> anyhow::Result<()> {
+        self.loaded.set(self.loaded.get() + 1);
+        self.send_progress()
+    }
+
+    /// Reports that a track failed to load with the given error.
+    pub fn failed(&self, e: &anyhow::Error) -> anyhow::Result<()> {
+        error!("Got an error: {e}");
+        self.failed.set(self.failed.get() + 1);
+        self.tx.send(UiMessage::TrackError {
+            error: e.to_string(),
+        })?;
+        self.send_progress()
+    }
+
+    /// Reports that all the tracks were processed, which sets the total if it
+    /// wasn't known.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        self.total.set(Some(self.loaded.get() + self.failed.get()));
+        self.send_progress()
+    }
+
+    /// Sends the current progress to the UI.
+    fn send_progress(&self) -> anyhow::Result<()> {
+        self.tx.send(UiMessage::TrackProgress {
+            loaded: self.loaded.get(),
+            failed: self.failed.get(),
+            total: self.total.get(),
+        })?;
+        Ok(())
+    }
+}
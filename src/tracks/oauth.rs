@@ -0,0 +1,344 @@
+//! Local HTTP server receiving the [OAuth authorization
+//! redirect](https://developers.strava.com/docs/authentication/#requestingaccess) from the
+//! browser.
+
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use rand::distr::Alphanumeric;
+use rand::{rng, Rng};
+use reqwest::Url;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Length of the random `state` parameter of an OAuth authorization.
+const STATE_LENGTH: usize = 32;
+/// Maximum size of the head of an HTTP request sent by the browser.
+const MAX_REQUEST_HEAD_SIZE: usize = 16 * 1024;
+/// Maximum time to wait for the browser to send a request on a connection.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Result of an OAuth authorization, carried by the redirect request.
+#[derive(Debug, PartialEq, Eq)]
+enum Redirect {
+    /// The user granted access with the given scope, in exchange of the given
+    /// code.
+    Granted { code: String, scope: String },
+    /// The authorization failed with the given error, e.g. `access_denied` if
+    /// the user declined it.
+    Failed { error: String },
+}
+
+/// Invalid request received by the redirect server.
+#[derive(Debug, PartialEq, Eq)]
+enum RequestError {
+    /// The request isn't for the redirect path, e.g. a favicon request.
+    NotFound,
+    /// The request is malformed, or its state doesn't match the expected one.
+    Invalid(String),
+}
+
+/// HTTP response status sent by the redirect server.
+#[derive(Clone, Copy, Debug)]
+enum Status {
+    /// The request was successful.
+    Ok,
+    /// The request was malformed.
+    BadRequest,
+    /// The request was refused.
+    Forbidden,
+    /// The requested path doesn't exist.
+    NotFound,
+}
+
+impl Status {
+    /// Returns the status line of this HTTP status.
+    fn line(self) -> &'static str {
+        match self {
+            Status::Ok => "200 OK",
+            Status::BadRequest => "400 Bad Request",
+            Status::Forbidden => "403 Forbidden",
+            Status::NotFound => "404 Not Found",
+        }
+    }
+}
+
+/// Generates a random `state` parameter for an OAuth authorization, to
+/// protect the redirect against cross-site request forgery.
+pub fn random_state() -> String {
+    rng()
+        .sample_iter(Alphanumeric)
+        .take(STATE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Serves the given listener until the browser is redirected to it with the
+/// result of the OAuth authorization with the given `state`, and returns the
+/// authorization code.
+///
+/// Unrelated or forged requests are answered with an error page and ignored.
+/// The granted scope is checked with the given function before answering the
+/// browser.
+pub async fn receive_authorization_code(
+    listener: &TcpListener,
+    state: &str,
+    check_scope: impl Fn(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<String> {
+    loop {
+        let (mut socket, addr) = listener
+            .accept()
+            .await
+            .context("Failed to accept a connection for the OAuth redirect")?;
+        info!("Request from: {addr}");
+
+        if !is_authorized_ip(addr.ip()) {
+            warn!("Ignoring request from unauthorized IP address: {addr}");
+            send_page(
+                &mut socket,
+                Status::Forbidden,
+                "Unauthorized",
+                "Requests are only accepted from this computer.",
+            )
+            .await;
+            continue;
+        }
+
+        let head = match timeout(READ_TIMEOUT, read_request_head(&mut socket)).await {
+            Ok(Ok(head)) => head,
+            Ok(Err(e)) => {
+                warn!("Ignoring invalid request from {addr}: {e:?}");
+                continue;
+            }
+            Err(_) => {
+                warn!("Timed out reading request from {addr}");
+                continue;
+            }
+        };
+
+        match parse_request_target(&head).and_then(|target| parse_redirect(&target, state)) {
+            Ok(Redirect::Granted { code, scope }) => {
+                debug!("Granted OAuth scope: {scope}");
+                return match check_scope(&scope) {
+                    Ok(()) => {
+                        send_page(
+                            &mut socket,
+                            Status::Ok,
+                            "Ridemap is authorized",
+                            "You can close this window and return to Ridemap.",
+                        )
+                        .await;
+                        Ok(code)
+                    }
+                    Err(e) => {
+                        send_page(
+                            &mut socket,
+                            Status::Forbidden,
+                            "Insufficient access",
+                            "Ridemap needs access to your activities. Please restart it and grant access to your activities.",
+                        )
+                        .await;
+                        Err(e)
+                    }
+                };
+            }
+            Ok(Redirect::Failed { error }) => {
+                send_page(
+                    &mut socket,
+                    Status::Forbidden,
+                    "Ridemap isn't authorized",
+                    "The authorization was declined. You can close this window.",
+                )
+                .await;
+                if error == "access_denied" {
+                    bail!("The OAuth authorization was declined");
+                }
+                bail!("The OAuth authorization failed: {error}");
+            }
+            Err(RequestError::NotFound) => {
+                debug!("Ignoring request for an unknown path from {addr}");
+                send_page(
+                    &mut socket,
+                    Status::NotFound,
+                    "Not found",
+                    "This page doesn't exist.",
+                )
+                .await;
+            }
+            Err(RequestError::Invalid(reason)) => {
+                warn!("Ignoring invalid request from {addr}: {reason}");
+                send_page(
+                    &mut socket,
+                    Status::BadRequest,
+                    "Invalid request",
+                    "This request isn't a valid authorization for Ridemap.",
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Checks whether requests from the given IP address are accepted, i.e. from
+/// the loopback interface.
+fn is_authorized_ip(ip: IpAddr) -> bool {
+    ip.is_loopback()
+}
+
+/// Reads the head of an HTTP request (i.e. up to the empty line before the
+/// body) from the given connection.
+pub async fn read_request_head(socket: &mut TcpStream) -> anyhow::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        let count = socket.read(&mut buf).await?;
+        if count == 0 {
+            bail!("Connection closed before the end of the request");
+        }
+        head.extend_from_slice(&buf[..count]);
+        if head.len() > MAX_REQUEST_HEAD_SIZE {
+            bail!("Request is too large");
+        }
+    }
+    Ok(String::from_utf8(head)?)
+}
+
+/// Parses the target of the given HTTP `GET` request head.
+fn parse_request_target(head: &str) -> Result<String, RequestError> {
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("GET"), Some(target), Some(version), None) if version.starts_with("HTTP/") => {
+            Ok(target.to_owned())
+        }
+        _ => Err(RequestError::Invalid(format!(
+            "Invalid request line: {request_line:?}"
+        ))),
+    }
+}
+
+/// Parses the OAuth redirect from the given request target, checking that it
+/// carries the given `state`.
+fn parse_redirect(target: &str, state: &str) -> Result<Redirect, RequestError> {
+    let url = Url::parse("http://localhost")
+        .and_then(|base| base.join(target))
+        .map_err(|e| RequestError::Invalid(format!("Invalid request target: {e}")))?;
+    if url.path() != "/" {
+        return Err(RequestError::NotFound);
+    }
+
+    let mut request_state = None;
+    let mut code = None;
+    let mut scope = None;
+    let mut error = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "state" => request_state = Some(value.into_owned()),
+            "code" => code = Some(value.into_owned()),
+            "scope" => scope = Some(value.into_owned()),
+            "error" => error = Some(value.into_owned()),
+            _ => (),
+        }
+    }
+
+    if request_state.as_deref() != Some(state) {
+        return Err(RequestError::Invalid(
+            "Missing or mismatched OAuth state".to_owned(),
+        ));
+    }
+    match (error, code) {
+        (Some(error), _) => Ok(Redirect::Failed { error }),
+        (None, Some(code)) => Ok(Redirect::Granted {
+            code,
+            scope: scope.unwrap_or_default(),
+        }),
+        (None, None) => Err(RequestError::Invalid(
+            "Missing OAuth code or error".to_owned(),
+        )),
+    }
+}
+
+/// Sends an HTML page with the given status, title and message on the given
+/// connection, and closes it. Errors are logged, as the browser may have
+/// closed the connection already.
+async fn send_page(socket: &mut TcpStream, status: Status, title: &str, message: &str) {
+    let body = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body style=\"font-family: sans-serif; text-align: center; margin-top: 4em\">\n<h1>{title}</h1>\n<p>{message}</p>\n</body>\n</html>\n"
+    );
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        status.line(),
+        body.len()
+    );
+    let result = async {
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown().await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Couldn't send the response to the browser: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_request_target_get() {
+        assert_eq!(
+            parse_request_target("GET /?state=abc&code=123 HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Ok("/?state=abc&code=123".to_owned())
+        );
+        assert!(matches!(
+            parse_request_target("POST / HTTP/1.1\r\n\r\n"),
+            Err(RequestError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse_request_target("garbage\r\n\r\n"),
+            Err(RequestError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn authorized_ip_loopback_only() {
+        assert!(is_authorized_ip("127.0.0.1".parse().unwrap()));
+        assert!(is_authorized_ip("::1".parse().unwrap()));
+        assert!(!is_authorized_ip("192.168.1.2".parse().unwrap()));
+        assert!(!is_authorized_ip("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_redirect_outcomes() {
+        assert_eq!(
+            parse_redirect(
+                "/?state=abc&code=0123abcd&scope=read,activity:read_all",
+                "abc"
+            ),
+            Ok(Redirect::Granted {
+                code: "0123abcd".to_owned(),
+                scope: "read,activity:read_all".to_owned(),
+            })
+        );
+        assert_eq!(
+            parse_redirect("/?state=abc&error=access_denied", "abc"),
+            Ok(Redirect::Failed {
+                error: "access_denied".to_owned(),
+            })
+        );
+        assert_eq!(
+            parse_redirect("/favicon.ico", "abc"),
+            Err(RequestError::NotFound)
+        );
+        assert!(matches!(
+            parse_redirect("/?state=forged&code=0123abcd&scope=read", "abc"),
+            Err(RequestError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse_redirect("/?code=0123abcd&scope=read", "abc"),
+            Err(RequestError::Invalid(_))
+        ));
+    }
+}
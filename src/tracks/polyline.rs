@@ -23,7 +23,7 @@ pub struct LatLon {
 
 impl LatLon {
     /// Converts the coordinates into Mercator's projection.
-    fn as_mercator(&self) -> Point<f64> {
+    pub fn as_mercator(&self) -> Point<f64> {
         let x = 0.5 + self.lon / 360.0;
         let s = (self.lat * std::f64::consts::PI / 180.0).tan().asinh();
         let y = 0.5 - s / (2.0 * std::f64::consts::PI);
@@ -0,0 +1,258 @@
+//! Tracking of the [rate limits](https://developers.strava.com/docs/rate-limits/) of Strava's
+//! API.
+
+use log::{debug, info, warn};
+use reqwest::header::HeaderMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+/// Durations of the rate limit windows of Strava's API, in seconds: 15 minutes
+/// and a day (reset at midnight UTC).
+const WINDOWS: [u64; 2] = [15 * 60, 24 * 60 * 60];
+/// Names of the rate limit windows, for logging.
+const WINDOW_NAMES: [&str; 2] = ["15-minute", "daily"];
+/// Headers reporting the limits and usage of the overall and read rate limits,
+/// each as a comma-separated pair of 15-minute and daily values.
+const HEADERS: [(&str, &str); 2] = [
+    ("x-ratelimit-limit", "x-ratelimit-usage"),
+    ("x-readratelimit-limit", "x-readratelimit-usage"),
+];
+/// Extra delay to wait after a window is reset, to account for clock skew with
+/// Strava's servers.
+const RESET_MARGIN: Duration = Duration::from_secs(5);
+
+/// Limits and usage of one of the rate limits of Strava's API, in each window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Usage {
+    /// Maximum number of requests in each window.
+    limit: [u64; 2],
+    /// Number of requests sent in each window so far.
+    used: [u64; 2],
+}
+
+impl Usage {
+    /// Parses the values of a pair of limit and usage headers.
+    fn parse(limit: &str, used: &str) -> Option<Self> {
+        Some(Self {
+            limit: parse_pair(limit)?,
+            used: parse_pair(used)?,
+        })
+    }
+}
+
+/// State of the rate limits of Strava's API.
+#[derive(Debug, Default)]
+struct State {
+    /// Usage of the overall and read rate limits, once reported by Strava.
+    usages: [Option<Usage>; 2],
+    /// Indices of the current 15-minute and daily windows since the Unix
+    /// epoch.
+    windows: [u64; 2],
+    /// Unix timestamp until which requests are paused after Strava rejected a
+    /// request without reporting which limit was exceeded.
+    paused_until: u64,
+}
+
+impl State {
+    /// Resets the usage of the windows that ended before the given Unix
+    /// timestamp.
+    fn roll_windows(&mut self, now: u64) {
+        for (i, window) in WINDOWS.iter().enumerate() {
+            let index = now / window;
+            if index != self.windows[i] {
+                self.windows[i] = index;
+                for usage in self.usages.iter_mut().flatten() {
+                    usage.used[i] = 0;
+                }
+            }
+        }
+    }
+
+    /// Returns the exhausted window that will reset the latest (if any), and
+    /// the Unix timestamp at which it will reset.
+    fn exhausted_until(&self, now: u64) -> Option<(&'static str, u64)> {
+        let mut result = (self.paused_until > now).then_some((WINDOW_NAMES[0], self.paused_until));
+        for usage in self.usages.iter().flatten() {
+            for (i, window) in WINDOWS.iter().enumerate() {
+                if usage.used[i] >= usage.limit[i] {
+                    let reset = (now / window + 1) * window;
+                    if result.is_none_or(|(_, until)| reset > until) {
+                        result = Some((WINDOW_NAMES[i], reset));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the number of requests that can still be sent in each window,
+    /// if known.
+    fn remaining(&self) -> Option<[u64; 2]> {
+        self.usages.iter().flatten().fold(None, |remaining, usage| {
+            let current = [0, 1].map(|i| usage.limit[i].saturating_sub(usage.used[i]));
+            Some(remaining.map_or(current, |r: [u64; 2]| {
+                [r[0].min(current[0]), r[1].min(current[1])]
+            }))
+        })
+    }
+}
+
+/// Throttles the requests to Strava's API to stay within its rate limits, as
+/// reported in the response headers.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Current state of the rate limits.
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    /// Waits until a request can be sent without exceeding the rate limits,
+    /// and counts it.
+    pub async fn acquire(&self) {
+        loop {
+            let (window, wait) = {
+                let mut state = self.state.lock().unwrap();
+                let now = unix_time();
+                state.roll_windows(now);
+                match state.exhausted_until(now) {
+                    None => {
+                        for usage in state.usages.iter_mut().flatten() {
+                            usage.used[0] += 1;
+                            usage.used[1] += 1;
+                        }
+                        return;
+                    }
+                    Some((window, until)) => (window, Duration::from_secs(until - now)),
+                }
+            };
+            warn!(
+                "Strava's {window} rate limit is reached, pausing requests for {} minutes until it resets",
+                wait.as_secs().div_ceil(60)
+            );
+            sleep(wait + RESET_MARGIN).await;
+            info!("Resuming requests to Strava");
+        }
+    }
+
+    /// Updates the usage of the rate limits from the headers of a response of
+    /// Strava's API.
+    pub fn update(&self, headers: &HeaderMap) {
+        let mut state = self.state.lock().unwrap();
+        state.roll_windows(unix_time());
+        for (i, (limit_header, usage_header)) in HEADERS.iter().enumerate() {
+            let limit = headers.get(*limit_header).and_then(|v| v.to_str().ok());
+            let used = headers.get(*usage_header).and_then(|v| v.to_str().ok());
+            let Some(usage) = limit.zip(used).and_then(|(l, u)| Usage::parse(l, u)) else {
+                continue;
+            };
+            // Requests sent concurrently may not be counted yet in the usage
+            // reported by Strava.
+            state.usages[i] = Some(match state.usages[i] {
+                Some(previous) => Usage {
+                    limit: usage.limit,
+                    used: [0, 1].map(|w| usage.used[w].max(previous.used[w])),
+                },
+                None => usage,
+            });
+        }
+        if let Some(remaining) = state.remaining() {
+            debug!(
+                "Strava's rate limits allow {} more requests in this 15-minute window, and {} more today",
+                remaining[0], remaining[1]
+            );
+        }
+    }
+
+    /// Pauses the requests until the end of the exhausted window, after Strava
+    /// rejected a request for exceeding the rate limits.
+    pub fn exhaust(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = unix_time();
+        if state.exhausted_until(now).is_none() {
+            state.paused_until = (now / WINDOWS[0] + 1) * WINDOWS[0];
+        }
+    }
+
+    /// Returns the number of requests that can still be sent in the current
+    /// 15-minute and daily windows, if known.
+    pub fn remaining(&self) -> Option<[u64; 2]> {
+        let mut state = self.state.lock().unwrap();
+        state.roll_windows(unix_time());
+        state.remaining()
+    }
+
+    /// Logs the number of requests that can still be sent within the rate
+    /// limits.
+    pub fn log_budget(&self) {
+        match self.remaining() {
+            Some(remaining) => info!(
+                "Strava API budget: {} requests left in this 15-minute window, {} left today",
+                remaining[0], remaining[1]
+            ),
+            None => info!("Strava API budget: unknown"),
+        }
+    }
+}
+
+/// Parses a comma-separated pair of 15-minute and daily values.
+fn parse_pair(value: &str) -> Option<[u64; 2]> {
+    let (short, daily) = value.split_once(',')?;
+    Some([short.trim().parse().ok()?, daily.trim().parse().ok()?])
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn usage_parse() {
+        assert_eq!(
+            Usage::parse("200,2000", "12, 300"),
+            Some(Usage {
+                limit: [200, 2000],
+                used: [12, 300],
+            })
+        );
+        assert_eq!(Usage::parse("200", "12,300"), None);
+        assert_eq!(Usage::parse("200,2000", "a,b"), None);
+    }
+
+    #[test]
+    fn state_exhausted_until() {
+        let now = 1000 * WINDOWS[1] + 100;
+        let mut state = State::default();
+        state.roll_windows(now);
+        assert_eq!(state.exhausted_until(now), None);
+
+        state.usages[0] = Some(Usage {
+            limit: [200, 2000],
+            used: [200, 300],
+        });
+        assert_eq!(
+            state.exhausted_until(now),
+            Some(("15-minute", now - 100 + 900))
+        );
+        assert_eq!(state.remaining(), Some([0, 1700]));
+
+        state.usages[1] = Some(Usage {
+            limit: [100, 1000],
+            used: [50, 1000],
+        });
+        assert_eq!(
+            state.exhausted_until(now),
+            Some(("daily", 1001 * WINDOWS[1]))
+        );
+
+        // The usage of the 15-minute window is reset in the next window.
+        state.roll_windows(now + 900);
+        assert_eq!(state.remaining(), Some([100, 0]));
+    }
+}
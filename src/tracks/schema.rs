@@ -1,9 +1,11 @@
 //! JSON schemas for [Strava's API](https://developers.strava.com/docs/reference/).
 
-use anyhow::bail;
-use clap::ValueEnum;
-use serde::{Deserialize, Serialize};
+use super::date::parse_date_time;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::Infallible;
+use std::fmt;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// A [Fault](https://developers.strava.com/docs/reference/#api-models-Fault) message in Strava's
 /// API.
@@ -27,31 +29,87 @@ struct Error {
 }
 
 /// The result of a [OAuth token
-/// exchange](https://developers.strava.com/docs/authentication/#tokenexchange) performed on
-/// Strava's API.
-#[derive(Debug, Deserialize)]
+/// exchange](https://developers.strava.com/docs/authentication/#tokenexchange) or [token
+/// refresh](https://developers.strava.com/docs/authentication/#refreshingexpiredaccesstokens)
+/// performed on Strava's API.
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Token {
     #[allow(dead_code)]
     token_type: String,
+    /// Expiration time of the access token, in seconds since the Unix epoch.
+    pub expires_at: u64,
     #[allow(dead_code)]
-    expires_at: u32,
-    #[allow(dead_code)]
-    expires_in: u32,
-    #[allow(dead_code)]
-    refresh_token: String,
+    expires_in: u64,
+    /// OAuth refresh token, to obtain a new access token once this one has
+    /// expired.
+    pub refresh_token: String,
     /// OAuth access token.
     pub access_token: String,
 }
 
+impl Token {
+    /// Checks whether the access token is expired, or will expire within the
+    /// given duration.
+    pub fn expires_within(&self, duration: Duration) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        self.expires_at <= now.saturating_add(duration.as_secs())
+    }
+}
+
 /// An [DetailedAthlete](https://developers.strava.com/docs/reference/#api-models-DetailedAthlete)
 /// message in Strava's API.
 // The Rust compiler considers the fields as dead code, even though we Debug them in logs.
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct DetailedAthlete {
-    id: u64,
+    /// The unique identifier of the athlete.
+    pub id: u64,
     firstname: String,
     lastname: String,
+    /// The bikes of the athlete.
+    #[serde(default)]
+    pub bikes: Vec<SummaryGear>,
+    /// The shoes of the athlete.
+    #[serde(default)]
+    pub shoes: Vec<SummaryGear>,
+}
+
+impl DetailedAthlete {
+    /// Returns the gear (bike or shoes) of the athlete with the given ID or
+    /// name, ignoring the case of the name.
+    pub fn find_gear(&self, id_or_name: &str) -> Option<&SummaryGear> {
+        self.gear().find(|gear| gear.id == id_or_name).or_else(|| {
+            self.gear()
+                .find(|gear| gear.name.eq_ignore_ascii_case(id_or_name))
+        })
+    }
+
+    /// Returns an iterator over all the gear (bikes and shoes) of the athlete.
+    pub fn gear(&self) -> impl Iterator<Item = &SummaryGear> {
+        self.bikes.iter().chain(&self.shoes)
+    }
+}
+
+/// A [SummaryGear](https://developers.strava.com/docs/reference/#api-models-SummaryGear) in
+/// Strava's API.
+#[derive(Debug, Deserialize)]
+pub struct SummaryGear {
+    /// The unique identifier of the gear, e.g. `b1234567` for a bike.
+    pub id: String,
+    /// The name of the gear.
+    pub name: String,
+}
+
+impl SummaryGear {
+    /// Returns whether the given string has the format of a gear ID, i.e. `b`
+    /// (bikes) or `g` (shoes) followed by digits.
+    pub fn is_id(id_or_name: &str) -> bool {
+        id_or_name
+            .strip_prefix(['b', 'g'])
+            .is_some_and(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+    }
 }
 
 /// A [SummaryActivity](https://developers.strava.com/docs/reference/#api-models-SummaryActivity)
@@ -59,34 +117,175 @@ pub struct DetailedAthlete {
 ///
 /// Note: although `SummaryActivity` has a map field, the full polyline is only
 /// available in [`DetailedActivity`].
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct SummaryActivity {
     /// The unique identifier of the activity.
     pub id: u64,
+    /// The name of the activity.
+    pub name: String,
     /// The type of activity.
-    // TODO: Deprecated in favor of SportType.
     pub r#type: ActivityType,
+    /// The sport type of the activity, absent from older cached entries.
+    #[serde(default)]
+    pub sport_type: Option<SportType>,
+    /// The time at which the activity was started, in the ISO 8601 format
+    /// (e.g. `2024-05-18T07:30:00Z`).
+    pub start_date: String,
+    /// The identifier of the gear used for the activity, if any (absent from
+    /// older cached entries).
+    #[serde(default)]
+    pub gear_id: Option<String>,
+}
+
+impl SummaryActivity {
+    /// Returns the sport type of the activity, derived from its activity type
+    /// for older cached entries.
+    pub fn sport_type(&self) -> SportType {
+        self.sport_type
+            .clone()
+            .unwrap_or_else(|| SportType::from(&self.r#type))
+    }
+
+    /// Returns the time at which the activity was started, as a Unix timestamp
+    /// in seconds.
+    pub fn start_timestamp(&self) -> anyhow::Result<i64> {
+        parse_date_time(&self.start_date)
+    }
 }
 
 /// A [DetailedActivity](https://developers.strava.com/docs/reference/#api-models-DetailedActivity)
 /// in Strava's API.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DetailedActivity {
-    id: u64,
+    /// The unique identifier of the activity.
+    pub id: u64,
     name: String,
     distance: f64,
     moving_time: u32,
     elapsed_time: u32,
     total_elevation_gain: f64,
     /// The type of activity.
-    // TODO: Deprecated in favor of SportType.
     pub r#type: ActivityType,
+    /// The sport type of the activity, absent from older cached entries.
+    #[serde(default)]
+    pub sport_type: Option<SportType>,
+    /// The time at which the activity was started, in the ISO 8601 format
+    /// (e.g. `2024-05-18T07:30:00Z`), absent from older cached entries.
+    #[serde(default)]
+    pub start_date: Option<String>,
+    /// The identifier of the gear used for the activity, if any (absent from
+    /// older cached entries).
+    #[serde(default)]
+    pub gear_id: Option<String>,
     workout_type: Option<u32>,
     description: Option<String>,
     /// The polyline track of the activity on the map.
     pub map: PolylineMap,
 }
 
+impl DetailedActivity {
+    /// Returns the sport type of the activity, derived from its activity type
+    /// for older cached entries.
+    pub fn sport_type(&self) -> SportType {
+        self.sport_type
+            .clone()
+            .unwrap_or_else(|| SportType::from(&self.r#type))
+    }
+}
+
+/// A [Route](https://developers.strava.com/docs/reference/#api-models-Route) in Strava's API.
+#[derive(Debug, Deserialize)]
+pub struct Route {
+    /// The unique identifier of the route.
+    pub id: u64,
+    /// The name of the route.
+    pub name: String,
+    /// The type of the route: 1 for a ride, 2 for a run.
+    pub r#type: u32,
+    /// The polyline of the route on the map.
+    pub map: PolylineMap,
+}
+
+impl Route {
+    /// Returns the sport type corresponding to the type of the route.
+    pub fn sport_type(&self) -> SportType {
+        match self.r#type {
+            2 => SportType::Run,
+            _ => SportType::Ride,
+        }
+    }
+}
+
+/// A [SummarySegment](https://developers.strava.com/docs/reference/#api-models-SummarySegment)
+/// in Strava's API.
+///
+/// Note: the polyline of a segment is only available in [`DetailedSegment`].
+#[derive(Debug, Deserialize)]
+pub struct SummarySegment {
+    /// The unique identifier of the segment.
+    pub id: u64,
+    /// The name of the segment.
+    pub name: String,
+}
+
+/// A [DetailedSegment](https://developers.strava.com/docs/reference/#api-models-DetailedSegment)
+/// in Strava's API.
+#[derive(Debug, Deserialize)]
+pub struct DetailedSegment {
+    /// The unique identifier of the segment.
+    pub id: u64,
+    /// The name of the segment.
+    pub name: String,
+    /// The type of activity of the segment.
+    pub activity_type: ActivityType,
+    /// The efforts of the authenticated athlete on this segment.
+    pub athlete_segment_stats: Option<SegmentEffortStats>,
+    /// The polyline of the segment on the map.
+    pub map: PolylineMap,
+}
+
+/// Statistics about the efforts of the authenticated athlete on a segment in
+/// Strava's API.
+#[derive(Debug, Deserialize)]
+pub struct SegmentEffortStats {
+    /// The number of efforts on the segment.
+    pub effort_count: u32,
+    /// The elapsed time of the personal record on the segment, in seconds.
+    pub pr_elapsed_time: Option<u32>,
+    /// The date of the personal record on the segment.
+    pub pr_date: Option<String>,
+}
+
+/// A [StreamSet](https://developers.strava.com/docs/reference/#api-models-StreamSet) in
+/// Strava's API, keyed by stream type.
+///
+/// Each stream has one value per recorded point of the activity, and is absent
+/// if the activity doesn't have the corresponding data (e.g. no heart rate
+/// monitor).
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct StreamSet {
+    /// Latitude and longitude of each point, in degrees.
+    pub latlng: Option<ActivityStream<[f64; 2]>>,
+    /// Time elapsed since the start of the activity, in seconds.
+    pub time: Option<ActivityStream<Option<f64>>>,
+    /// Altitude, in meters.
+    pub altitude: Option<ActivityStream<Option<f64>>>,
+    /// Heart rate, in beats per minute.
+    pub heartrate: Option<ActivityStream<Option<f64>>>,
+    /// Power, in watts.
+    pub watts: Option<ActivityStream<Option<f64>>>,
+    /// Smoothed speed, in meters per second.
+    pub velocity_smooth: Option<ActivityStream<Option<f64>>>,
+}
+
+/// A stream of values recorded during an activity in Strava's API, e.g. a
+/// [LatLngStream](https://developers.strava.com/docs/reference/#api-models-LatLngStream).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ActivityStream<T> {
+    /// Values of the stream, one per recorded point.
+    pub data: Vec<T>,
+}
+
 /// A [PolylineMap](https://developers.strava.com/docs/reference/#api-models-PolylineMap) in
 /// Strava's API.
 #[derive(Debug, Deserialize, Serialize)]
@@ -100,93 +299,264 @@ pub struct PolylineMap {
     pub summary_polyline: Option<String>,
 }
 
-/// An [ActivityType](https://developers.strava.com/docs/reference/#api-models-ActivityType) in
-/// Strava's API.
-#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
-#[allow(missing_docs)]
-pub enum ActivityType {
-    AlpineSki,
-    BackcountrySki,
-    Canoeing,
-    Crossfit,
-    EBikeRide,
-    Elliptical,
-    Golf,
-    Handcycle,
-    Hike,
-    IceSkate,
-    InlineSkate,
-    Kayaking,
-    Kitesurf,
-    NordicSki,
-    Ride,
-    RockClimbing,
-    RollerSki,
-    Rowing,
-    Run,
-    Sail,
-    Skateboard,
-    Snowboard,
-    Snowshoe,
-    Soccer,
-    StairStepper,
-    StandUpPaddling,
-    Surfing,
-    Swim,
-    Velomobile,
-    VirtualRide,
-    VirtualRun,
-    Walk,
-    WeightTraining,
-    Wheelchair,
-    Windsurf,
-    Workout,
-    Yoga,
-}
-
-impl FromStr for ActivityType {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "AlpineSki" => Ok(ActivityType::AlpineSki),
-            "BackcountrySki" => Ok(ActivityType::BackcountrySki),
-            "Canoeing" => Ok(ActivityType::Canoeing),
-            "Crossfit" => Ok(ActivityType::Crossfit),
-            "EBikeRide" => Ok(ActivityType::EBikeRide),
-            "Elliptical" => Ok(ActivityType::Elliptical),
-            "Golf" => Ok(ActivityType::Golf),
-            "Handcycle" => Ok(ActivityType::Handcycle),
-            "Hike" => Ok(ActivityType::Hike),
-            "IceSkate" => Ok(ActivityType::IceSkate),
-            "InlineSkate" => Ok(ActivityType::InlineSkate),
-            "Kayaking" => Ok(ActivityType::Kayaking),
-            "Kitesurf" => Ok(ActivityType::Kitesurf),
-            "NordicSki" => Ok(ActivityType::NordicSki),
-            "Ride" => Ok(ActivityType::Ride),
-            "RockClimbing" => Ok(ActivityType::RockClimbing),
-            "RollerSki" => Ok(ActivityType::RollerSki),
-            "Rowing" => Ok(ActivityType::Rowing),
-            "Run" => Ok(ActivityType::Run),
-            "Sail" => Ok(ActivityType::Sail),
-            "Skateboard" => Ok(ActivityType::Skateboard),
-            "Snowboard" => Ok(ActivityType::Snowboard),
-            "Snowshoe" => Ok(ActivityType::Snowshoe),
-            "Soccer" => Ok(ActivityType::Soccer),
-            "StairStepper" => Ok(ActivityType::StairStepper),
-            "StandUpPaddling" => Ok(ActivityType::StandUpPaddling),
-            "Surfing" => Ok(ActivityType::Surfing),
-            "Swim" => Ok(ActivityType::Swim),
-            "Velomobile" => Ok(ActivityType::Velomobile),
-            "VirtualRide" => Ok(ActivityType::VirtualRide),
-            "VirtualRun" => Ok(ActivityType::VirtualRun),
-            "Walk" => Ok(ActivityType::Walk),
-            "WeightTraining" => Ok(ActivityType::WeightTraining),
-            "Wheelchair" => Ok(ActivityType::Wheelchair),
-            "Windsurf" => Ok(ActivityType::Windsurf),
-            "Workout" => Ok(ActivityType::Workout),
-            "Yoga" => Ok(ActivityType::Yoga),
-            _ => bail!("Unknown activity type: {s}"),
+/// Defines an enum of the string values of a field in Strava's API, with an
+/// `Other` variant to hold the values unknown to this version of Ridemap.
+///
+/// The enum is (de)serialized and parsed from the Strava names of its values,
+/// i.e. the names of its variants.
+macro_rules! strava_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $(
+                #[allow(missing_docs)]
+                $variant,
+            )*
+            /// A value unknown to this version of Ridemap.
+            Other(String),
         }
+
+        impl $name {
+            /// All the values known to this version of Ridemap.
+            pub const KNOWN: &[$name] = &[$($name::$variant),*];
+
+            /// Returns the name of this value in Strava's API.
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $($name::$variant => stringify!($variant),)*
+                    $name::Other(name) => name,
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Infallible;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Ok(match s {
+                    $(stringify!($variant) => $name::$variant,)*
+                    _ => $name::Other(s.to_owned()),
+                })
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let name = String::deserialize(deserializer)?;
+                let Ok(value) = name.parse();
+                Ok(value)
+            }
+        }
+    };
+}
+
+strava_enum! {
+    /// An [ActivityType](https://developers.strava.com/docs/reference/#api-models-ActivityType) in
+    /// Strava's API.
+    ActivityType {
+        AlpineSki,
+        BackcountrySki,
+        Canoeing,
+        Crossfit,
+        EBikeRide,
+        Elliptical,
+        Golf,
+        Handcycle,
+        Hike,
+        IceSkate,
+        InlineSkate,
+        Kayaking,
+        Kitesurf,
+        NordicSki,
+        Ride,
+        RockClimbing,
+        RollerSki,
+        Rowing,
+        Run,
+        Sail,
+        Skateboard,
+        Snowboard,
+        Snowshoe,
+        Soccer,
+        StairStepper,
+        StandUpPaddling,
+        Surfing,
+        Swim,
+        Velomobile,
+        VirtualRide,
+        VirtualRun,
+        Walk,
+        WeightTraining,
+        Wheelchair,
+        Windsurf,
+        Workout,
+        Yoga,
+    }
+}
+
+strava_enum! {
+    /// A [SportType](https://developers.strava.com/docs/reference/#api-models-SportType) in
+    /// Strava's API, which is more specific than the [`ActivityType`].
+    SportType {
+        AlpineSki,
+        BackcountrySki,
+        Badminton,
+        Canoeing,
+        Crossfit,
+        EBikeRide,
+        Elliptical,
+        EMountainBikeRide,
+        Golf,
+        GravelRide,
+        Handcycle,
+        HighIntensityIntervalTraining,
+        Hike,
+        IceSkate,
+        InlineSkate,
+        Kayaking,
+        Kitesurf,
+        MountainBikeRide,
+        NordicSki,
+        Pickleball,
+        Pilates,
+        Racquetball,
+        Ride,
+        RockClimbing,
+        RollerSki,
+        Rowing,
+        Run,
+        Sail,
+        Skateboard,
+        Snowboard,
+        Snowshoe,
+        Soccer,
+        Squash,
+        StairStepper,
+        StandUpPaddling,
+        Surfing,
+        Swim,
+        TableTennis,
+        Tennis,
+        TrailRun,
+        Velomobile,
+        VirtualRide,
+        VirtualRow,
+        VirtualRun,
+        Walk,
+        WeightTraining,
+        Wheelchair,
+        Windsurf,
+        Workout,
+        Yoga,
+    }
+}
+
+impl From<&ActivityType> for SportType {
+    /// Converts an activity type to the sport type of the same name, as each
+    /// activity type is also a sport type.
+    fn from(activity_type: &ActivityType) -> Self {
+        let Ok(sport_type) = activity_type.as_str().parse();
+        sport_type
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sport_type_round_trip() {
+        for sport_type in SportType::KNOWN {
+            let json = serde_json::to_string(sport_type).unwrap();
+            assert_eq!(json, format!("\"{}\"", sport_type.as_str()));
+            assert_eq!(
+                &serde_json::from_str::<SportType>(&json).unwrap(),
+                sport_type
+            );
+        }
+
+        let other: SportType = serde_json::from_str("\"Padel\"").unwrap();
+        assert_eq!(other, SportType::Other("Padel".to_owned()));
+        assert_eq!(serde_json::to_string(&other).unwrap(), "\"Padel\"");
+    }
+
+    #[test]
+    fn activity_type_round_trip() {
+        for activity_type in ActivityType::KNOWN {
+            let json = serde_json::to_string(activity_type).unwrap();
+            assert_eq!(
+                &serde_json::from_str::<ActivityType>(&json).unwrap(),
+                activity_type
+            );
+            assert_eq!(
+                &activity_type.as_str().parse::<ActivityType>().unwrap(),
+                activity_type
+            );
+        }
+
+        let summary: SummaryActivity = serde_json::from_str(
+            r#"{"id": 1, "name": "Morning", "type": "Padel", "start_date": "2024-05-18T07:30:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(summary.r#type, ActivityType::Other("Padel".to_owned()));
+        assert_eq!(summary.sport_type(), SportType::Other("Padel".to_owned()));
+        assert!(serde_json::to_string(&summary)
+            .unwrap()
+            .contains(r#""type":"Padel""#));
+    }
+
+    #[test]
+    fn detailed_segment_parse() {
+        let segment: DetailedSegment = serde_json::from_str(
+            r#"{
+                "id": 229781,
+                "name": "Hawk Hill",
+                "activity_type": "Ride",
+                "athlete_segment_stats": {"pr_elapsed_time": 553, "pr_date": "1993-04-03", "effort_count": 2},
+                "map": {"id": "s229781", "polyline": "_p~iF~ps|U_ulLnnqC_mqNvxq`@"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(segment.activity_type, ActivityType::Ride);
+        assert_eq!(
+            segment
+                .athlete_segment_stats
+                .as_ref()
+                .map(|stats| (stats.effort_count, stats.pr_elapsed_time)),
+            Some((2, Some(553)))
+        );
+        assert!(segment.map.polyline.is_some());
+    }
+
+    #[test]
+    fn gear_id_format() {
+        assert!(SummaryGear::is_id("b1234567"));
+        assert!(SummaryGear::is_id("g42"));
+        assert!(!SummaryGear::is_id("b"));
+        assert!(!SummaryGear::is_id("Gravel bike"));
+        assert!(!SummaryGear::is_id("bike1"));
+    }
+
+    #[test]
+    fn sport_type_from_activity_type() {
+        assert_eq!(SportType::from(&ActivityType::Ride), SportType::Ride);
+        assert_eq!(
+            SportType::from(&ActivityType::EBikeRide),
+            SportType::EBikeRide
+        );
+        assert_eq!(SportType::from(&ActivityType::Yoga), SportType::Yoga);
     }
 }
@@ -1,33 +1,48 @@
 //! Client to access [Strava's API](https://developers.strava.com/docs/reference/).
 
-use super::polyline::{Polyline, ToMercator};
+use super::date::parse_date_time;
+use super::oauth;
+use super::polyline::{LatLon, Point, Polyline, ToMercator};
+use super::rate_limit::RateLimiter;
 use super::schema::*;
+use super::{ProgressReporter, TrackAttribute, TrackAttributes, TrackLayer};
 use crate::caching::cache::Cache;
 use crate::ui::UiMessage;
-use anyhow::bail;
+use anyhow::{bail, Context};
 use clap::builder;
 use clap::error::ErrorKind;
-use futures::{stream, Stream, StreamExt};
-use log::{debug, error, info, trace};
-use regex::Regex;
-use reqwest::{Client, Response, StatusCode};
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use log::{debug, error, info, trace, warn};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io;
 use std::io::BufReader;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::Ipv4Addr;
 use std::path::Path;
+use std::pin::pin;
 use std::sync::mpsc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
 
-/// Base URL for Strava's API.
-const API_URL: &str = "https://www.strava.com/api/v3";
-/// Base URL for Strava's OAuth authorization.
-const AUTHORIZE_URL: &str = "https://www.strava.com/oauth/authorize";
+/// Default base URL for Strava's API.
+const DEFAULT_API_URL: &str = "https://www.strava.com/api/v3";
+/// Default base URL for Strava's OAuth authorization.
+const DEFAULT_AUTHORIZE_URL: &str = "https://www.strava.com/oauth/authorize";
+/// Default URL for Strava's OAuth token exchange and refresh.
+const DEFAULT_TOKEN_URL: &str = "https://www.strava.com/oauth/token";
 /// Address to redirect the OAuth authorization to, i.e. localhost.
 const AUTHORIZE_REDIRECT_ADDR: Ipv4Addr = Ipv4Addr::LOCALHOST;
 /// OAuth token scope(s) to request from Strava.
 const AUTHORIZE_SCOPE: &str = "read,activity:read_all";
+/// Types of the activity streams to request from Strava.
+const STREAM_KEYS: &str = "latlng,time,altitude,heartrate,watts,velocity_smooth";
+/// Maximum number of times to send a request again after Strava rejected it for
+/// exceeding the rate limits.
+const MAX_RATE_LIMITED_RETRIES: u32 = 2;
+/// Minimum remaining validity of a saved OAuth access token to reuse it rather
+/// than refreshing it.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10 * 60);
 
 /// Configuration to identify an application on Strava's API. See
 /// <https://developers.strava.com/docs/getting-started/#account>.
@@ -37,6 +52,30 @@ pub struct StravaConfig {
     client_id: String,
     /// Client secret for this Strava application.
     client_secret: String,
+    /// Base URL of Strava's API.
+    #[serde(default = "default_api_url")]
+    api_url: String,
+    /// Base URL of Strava's OAuth authorization page.
+    #[serde(default = "default_authorize_url")]
+    authorize_url: String,
+    /// URL of Strava's OAuth token exchange and refresh.
+    #[serde(default = "default_token_url")]
+    token_url: String,
+}
+
+/// Returns the default base URL of Strava's API.
+fn default_api_url() -> String {
+    DEFAULT_API_URL.to_owned()
+}
+
+/// Returns the default base URL of Strava's OAuth authorization page.
+fn default_authorize_url() -> String {
+    DEFAULT_AUTHORIZE_URL.to_owned()
+}
+
+/// Returns the default URL of Strava's OAuth token exchange and refresh.
+fn default_token_url() -> String {
+    DEFAULT_TOKEN_URL.to_owned()
 }
 
 impl StravaConfig {
@@ -44,7 +83,7 @@ impl StravaConfig {
     fn read_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
-        let config: Self = serde_json::from_reader(reader)?;
+        let mut config: Self = serde_json::from_reader(reader)?;
 
         if !config.client_id.chars().all(|c| c.is_ascii_digit()) {
             bail!("Expected only digits for client_id: {}", config.client_id);
@@ -55,6 +94,17 @@ impl StravaConfig {
                 config.client_secret
             );
         }
+        for (name, url) in [
+            ("api_url", &config.api_url),
+            ("authorize_url", &config.authorize_url),
+            ("token_url", &config.token_url),
+        ] {
+            let parsed = Url::parse(url).with_context(|| format!("Invalid {name}: {url}"))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                bail!("Expected an HTTP(S) URL for {name}: {url}");
+            }
+        }
+        config.api_url = config.api_url.trim_end_matches('/').to_owned();
 
         Ok(config)
     }
@@ -98,109 +148,457 @@ impl builder::ValueParserFactory for StravaConfig {
     }
 }
 
+/// Criteria to select the activities to display.
+#[derive(Debug, Default)]
+pub struct ActivityFilter {
+    /// Activity types to display, or any type if empty.
+    pub activity_types: Vec<ActivityType>,
+    /// Sport types to display, or any sport type if empty.
+    pub sport_types: Vec<SportType>,
+    /// Only display the activities started at or after this Unix timestamp.
+    pub after: Option<i64>,
+    /// Only display the activities started before this Unix timestamp.
+    pub before: Option<i64>,
+    /// Identifiers of the gear to display the activities of, or any gear if
+    /// empty.
+    pub gear_ids: Vec<String>,
+}
+
+impl ActivityFilter {
+    /// Checks whether an activity of the given activity and sport types,
+    /// start date and gear matches this filter.
+    ///
+    /// An activity without a start date or gear (e.g. an older cached entry)
+    /// doesn't match a date range or gear filter.
+    pub fn matches(
+        &self,
+        r#type: &ActivityType,
+        sport_type: &SportType,
+        start_date: Option<&str>,
+        gear_id: Option<&str>,
+    ) -> bool {
+        (self.activity_types.is_empty() || self.activity_types.contains(r#type))
+            && (self.sport_types.is_empty() || self.sport_types.contains(sport_type))
+            && self.matches_date(start_date)
+            && (self.gear_ids.is_empty()
+                || gear_id.is_some_and(|id| self.gear_ids.iter().any(|g| g == id)))
+    }
+
+    /// Checks whether the given start date is within the date range of this
+    /// filter.
+    fn matches_date(&self, start_date: Option<&str>) -> bool {
+        if self.after.is_none() && self.before.is_none() {
+            return true;
+        }
+        let Some(start) = start_date.and_then(|date| parse_date_time(date).ok()) else {
+            return false;
+        };
+        self.after.is_none_or(|after| start >= after)
+            && self.before.is_none_or(|before| start < before)
+    }
+}
+
+/// Reads the `count` most recent activities matching the given filter from the
+/// cache, without accessing Strava's API, and sends them as UI messages to the
+/// given sending channel.
+///
+/// This reads up to `parallel_requests` activities in parallel.
+pub async fn get_cached_activities_parallel(
+    tx: &mpsc::Sender<UiMessage>,
+    cache: &Cache,
+    filter: &ActivityFilter,
+    count: usize,
+    parallel_requests: usize,
+) -> anyhow::Result<()> {
+    let ids = cache.list_activities()?;
+    info!("Found {} activities in the cache", ids.len());
+
+    let progress = ProgressReporter::new(tx, None)?;
+    let mut activities = pin!(stream::iter(ids)
+        .map(|id| cache.get_activity(id))
+        .buffered(parallel_requests)
+        .filter(|activity| {
+            future::ready(match activity {
+                Ok(a) => filter.matches(
+                    &a.r#type,
+                    &a.sport_type(),
+                    a.start_date.as_deref(),
+                    a.gear_id.as_deref(),
+                ),
+                Err(_) => true,
+            })
+        })
+        .take(count)
+        .enumerate());
+
+    while let Some((i, activity)) = activities.next().await {
+        match activity {
+            Ok(a) => {
+                let streams = cache.get_activity_streams(a.id).await.ok();
+                send_activity(tx, i, a, streams)?;
+                progress.loaded()?;
+            }
+            Err(e) => progress.failed(&e)?,
+        }
+    }
+
+    progress.finish()
+}
+
+/// Sends the track of the given detailed activity as a UI message to the given
+/// sending channel.
+///
+/// The track is obtained from the given streams of the activity if they
+/// contain its coordinates, along with the other recorded attributes, and from
+/// the activity's polyline otherwise.
+fn send_activity(
+    tx: &mpsc::Sender<UiMessage>,
+    i: usize,
+    activity: DetailedActivity,
+    streams: Option<StreamSet>,
+) -> anyhow::Result<()> {
+    trace!("Activity = {activity:#?}");
+    if let Some((points, attributes)) = streams.and_then(stream_track) {
+        debug!(
+            "Streams have {} points with {} attributes",
+            points.len(),
+            attributes.len()
+        );
+        tx.send(UiMessage::Activity {
+            id: i,
+            layer: TrackLayer::Activity,
+            sport_type: activity.sport_type(),
+            points,
+            attributes,
+        })?;
+        return Ok(());
+    }
+
+    let summary = activity
+        .map
+        .summary_polyline
+        .as_ref()
+        .and_then(|p| Polyline::new(p));
+    let polyline = activity
+        .map
+        .polyline
+        .as_ref()
+        .and_then(|p| Polyline::new(p));
+    debug!(
+        "Summary polyline has {:?} points in {:?} bytes",
+        summary.map(|p| p.len()),
+        activity.map.summary_polyline.as_ref().map(|p| p.len())
+    );
+    debug!(
+        "Polyline has {:?} points in {:?} bytes",
+        polyline.as_ref().map(|p| p.len()),
+        activity.map.polyline.as_ref().map(|p| p.len())
+    );
+    if let Some(p) = polyline {
+        tx.send(UiMessage::Activity {
+            id: i,
+            layer: TrackLayer::Activity,
+            sport_type: activity.sport_type(),
+            points: p.mercator_points(),
+            attributes: TrackAttributes::new(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Sends the given segment as a UI message on the segment layer to the given
+/// sending channel, and logs the efforts of the athlete on it.
+fn send_segment(
+    tx: &mpsc::Sender<UiMessage>,
+    i: usize,
+    segment: DetailedSegment,
+) -> anyhow::Result<()> {
+    trace!("Segment = {segment:#?}");
+    match &segment.athlete_segment_stats {
+        Some(SegmentEffortStats {
+            effort_count,
+            pr_elapsed_time: Some(pr_elapsed_time),
+            pr_date,
+        }) => info!(
+            "Segment {:?} ({}): {effort_count} efforts, personal record of {}:{:02} on {}",
+            segment.name,
+            segment.activity_type,
+            pr_elapsed_time / 60,
+            pr_elapsed_time % 60,
+            pr_date.as_deref().unwrap_or("an unknown date")
+        ),
+        Some(SegmentEffortStats { effort_count, .. }) => info!(
+            "Segment {:?} ({}): {effort_count} efforts",
+            segment.name, segment.activity_type
+        ),
+        None => info!("Segment {:?} ({})", segment.name, segment.activity_type),
+    }
+
+    match segment.map.polyline.as_deref().and_then(Polyline::new) {
+        Some(p) => tx.send(UiMessage::Activity {
+            id: i,
+            layer: TrackLayer::Segment,
+            sport_type: SportType::from(&segment.activity_type),
+            points: p.mercator_points(),
+            attributes: TrackAttributes::new(),
+        })?,
+        None => warn!(
+            "Segment {} ({:?}) has no polyline",
+            segment.id, segment.name
+        ),
+    }
+    Ok(())
+}
+
+/// Converts the given streams of an activity into a track in Mercator
+/// coordinates, with the attributes recorded at each point.
+///
+/// Returns `None` if the streams don't contain the coordinates of the points.
+fn stream_track(streams: StreamSet) -> Option<(Vec<Point<f64>>, TrackAttributes)> {
+    let latlng = streams.latlng?.data;
+    if latlng.is_empty() {
+        return None;
+    }
+    let points = latlng
+        .iter()
+        .map(|&[lat, lon]| LatLon { lat, lon }.as_mercator())
+        .collect();
+
+    let mut attributes = TrackAttributes::new();
+    for (attribute, stream) in [
+        (TrackAttribute::Time, streams.time),
+        (TrackAttribute::Altitude, streams.altitude),
+        (TrackAttribute::HeartRate, streams.heartrate),
+        (TrackAttribute::Power, streams.watts),
+        (TrackAttribute::Speed, streams.velocity_smooth),
+    ] {
+        match stream {
+            Some(stream) if stream.data.len() == latlng.len() => {
+                attributes.insert(attribute, stream.data);
+            }
+            Some(stream) => warn!(
+                "Ignoring {} stream with {} values for {} points",
+                attribute.name(),
+                stream.data.len(),
+                latlng.len()
+            ),
+            None => (),
+        }
+    }
+    Some((points, attributes))
+}
+
+/// Sorts the given activities from the most recent to the oldest.
+fn sort_activities(activities: &mut [SummaryActivity]) {
+    // Strava's start dates are all in UTC, so their lexicographic order is
+    // chronological.
+    activities.sort_by(|a, b| b.start_date.cmp(&a.start_date).then(b.id.cmp(&a.id)));
+}
+
+/// Merges the given newly listed activities into the given index, replacing the
+/// indexed activities with the same IDs, and sorts the index from the most
+/// recent activity to the oldest.
+fn merge_activities(index: &mut Vec<SummaryActivity>, new_activities: Vec<SummaryActivity>) {
+    let new_ids: HashSet<u64> = new_activities.iter().map(|a| a.id).collect();
+    index.retain(|a| !new_ids.contains(&a.id));
+    index.extend(new_activities);
+    sort_activities(index);
+}
+
+/// Checks that the given OAuth scope granted by the user allows to read
+/// activities.
+///
+/// The user may decline access to private activities, in which case only the
+/// public ones can be fetched.
+fn check_scope(scope: &str) -> anyhow::Result<()> {
+    let scopes: Vec<&str> = scope.split(',').collect();
+    if scopes.contains(&"activity:read_all") {
+        Ok(())
+    } else if scopes.contains(&"activity:read") {
+        warn!(
+            "Access to private activities wasn't granted, only public activities will be fetched"
+        );
+        Ok(())
+    } else {
+        bail!("Access to activities wasn't granted (scope = {scope:?})");
+    }
+}
+
 /// Client to connect to [Strava's API](https://developers.strava.com/docs/reference/). This
 /// maintains state for the authenticated Strava athlete.
 pub struct StravaClient<'a> {
     cache: Option<&'a Cache>,
     client: &'a Client,
+    api_url: String,
     bearer_token: String,
+    rate_limiter: RateLimiter,
 }
 
 impl<'a> StravaClient<'a> {
-    /// Creates a new client for the given Strava application, performing an
-    /// OAuth token exchange on the given redirection port on localhost.
+    /// Creates a new client for the given Strava application.
+    ///
+    /// The OAuth token saved in the cache is reused while it's valid, and
+    /// refreshed once it has expired. Otherwise, an OAuth token exchange is
+    /// performed on the given redirection port on localhost, and the resulting
+    /// token is saved in the cache.
     pub async fn new(
         cache: Option<&'a Cache>,
         client: &'a Client,
         config: &StravaConfig,
         authorize_redirect_port: u16,
     ) -> anyhow::Result<StravaClient<'a>> {
-        let oauth_code = StravaClient::oauth_authorize(config, authorize_redirect_port).await?;
-
-        let bearer_token = StravaClient::oauth_exchange(client, config, &oauth_code).await?;
+        let token = StravaClient::get_token(cache, client, config, authorize_redirect_port).await?;
 
         Ok(Self {
             cache,
             client,
-            bearer_token,
+            api_url: config.api_url.clone(),
+            bearer_token: token.access_token,
+            rate_limiter: RateLimiter::default(),
         })
     }
 
-    /// Performs an OAuth token exchange for the given Strava application, using
-    /// the given redirection port on localhost.
+    /// Obtains an OAuth token for the given Strava application, either from
+    /// the cache, by refreshing the cached token, or by asking the user to
+    /// authorize the application.
+    async fn get_token(
+        cache: Option<&Cache>,
+        client: &Client,
+        config: &StravaConfig,
+        authorize_redirect_port: u16,
+    ) -> anyhow::Result<Token> {
+        let saved_token = match cache.map(|cache| cache.get_strava_token(&config.client_id)) {
+            Some(Ok(token)) => token,
+            Some(Err(e)) => {
+                warn!("Couldn't read the Strava token from the cache: {e:?}");
+                None
+            }
+            None => None,
+        };
+
+        let token = match saved_token {
+            Some(token) if !token.expires_within(TOKEN_EXPIRY_MARGIN) => {
+                info!("Reusing the Strava token from the cache");
+                return Ok(token);
+            }
+            Some(token) => match StravaClient::oauth_refresh(client, config, &token).await {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("Couldn't refresh the Strava token, authorizing again: {e:?}");
+                    StravaClient::oauth_authorize_exchange(client, config, authorize_redirect_port)
+                        .await?
+                }
+            },
+            None => {
+                StravaClient::oauth_authorize_exchange(client, config, authorize_redirect_port)
+                    .await?
+            }
+        };
+
+        match cache {
+            Some(cache) => {
+                if let Err(e) = cache.set_strava_token(&config.client_id, &token) {
+                    error!("Couldn't write the Strava token to the cache: {e:?}");
+                }
+            }
+            None => warn!("No cache directory, the Strava token won't be saved"),
+        }
+        Ok(token)
+    }
+
+    /// Asks the user to authorize the given Strava application, using the
+    /// given redirection port on localhost, and exchanges the resulting code
+    /// for an OAuth token.
+    async fn oauth_authorize_exchange(
+        client: &Client,
+        config: &StravaConfig,
+        authorize_redirect_port: u16,
+    ) -> anyhow::Result<Token> {
+        let oauth_code = StravaClient::oauth_authorize(config, authorize_redirect_port).await?;
+        StravaClient::oauth_exchange(client, config, &oauth_code).await
+    }
+
+    /// Asks the user to authorize the given Strava application, using the
+    /// given redirection port on localhost, and returns the resulting OAuth
+    /// code.
     async fn oauth_authorize(
         config: &StravaConfig,
         authorize_redirect_port: u16,
     ) -> anyhow::Result<String> {
+        let listener =
+            tokio::net::TcpListener::bind((AUTHORIZE_REDIRECT_ADDR, authorize_redirect_port))
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to listen on port {authorize_redirect_port} for the OAuth redirect"
+                    )
+                })?;
+
+        let state = oauth::random_state();
         println!(
-            "Please visit: {}?client_id={}&redirect_uri=http%3A%2F%2F{}%3A{}&response_type=code&approval_prompt=auto&scope={}",
-            AUTHORIZE_URL,
+            "Please visit: {}?client_id={}&redirect_uri=http%3A%2F%2F{}%3A{}&response_type=code&approval_prompt=auto&scope={}&state={}",
+            config.authorize_url,
             config.client_id,
             AUTHORIZE_REDIRECT_ADDR,
             authorize_redirect_port,
-            AUTHORIZE_SCOPE
+            AUTHORIZE_SCOPE,
+            state
         );
 
-        let listener =
-            tokio::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, authorize_redirect_port)).await?;
-
-        let (mut socket, addr) = listener.accept().await?;
-        info!("Request from: {addr}");
-
-        let authorized_ip = match addr.ip() {
-            IpAddr::V4(ip4) => ip4.is_loopback() || ip4.is_private(),
-            IpAddr::V6(_) => false,
-        };
-        if !authorized_ip {
-            socket
-                .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nUnauthorized IP address")
-                .await?;
-            bail!("Unauthorized IP address: {addr}");
-        }
-
-        // TODO: newline
-        const REGEX: &str = r"^GET /\?state=&code=([0-9a-f]+)&scope=([a-z_,:]+) HTTP/1\.1";
-        let re = Regex::new(REGEX).unwrap();
-
-        let mut buf = [0; 128];
-        socket.read_exact(&mut buf).await?;
-        socket
-            .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nAll good!")
-            .await?;
-
-        debug!("Parsing OAuth code");
-        let req_utf8 = std::str::from_utf8(&buf)?;
-        let captures = re.captures(req_utf8).ok_or_else(|| {
-            Box::new(io::Error::other(
-                "Invalid request from the browser".to_owned(),
-            ))
-        })?;
-
-        let oauth_code = &captures[1];
-        let scope = &captures[2];
-        assert_eq!(scope, AUTHORIZE_SCOPE);
-
-        Ok(oauth_code.to_owned())
+        oauth::receive_authorization_code(&listener, &state, check_scope).await
     }
 
     /// Performs an [OAuth token
     /// exchange](https://developers.strava.com/docs/authentication/#tokenexchange) with Strava's
-    /// API, using the given `oauth_code`, and returns the corresponding access
-    /// token.
+    /// API, using the given `oauth_code`, and returns the corresponding token.
     async fn oauth_exchange(
         client: &Client,
         config: &StravaConfig,
         oauth_code: &str,
-    ) -> anyhow::Result<String> {
+    ) -> anyhow::Result<Token> {
         debug!("Exchanging OAuth token");
+        StravaClient::oauth_token(
+            client,
+            config,
+            &[("code", oauth_code), ("grant_type", "authorization_code")],
+        )
+        .await
+    }
+
+    /// [Refreshes](https://developers.strava.com/docs/authentication/#refreshingexpiredaccesstokens)
+    /// the given expired OAuth token with Strava's API, and returns the new
+    /// token.
+    async fn oauth_refresh(
+        client: &Client,
+        config: &StravaConfig,
+        token: &Token,
+    ) -> anyhow::Result<Token> {
+        debug!("Refreshing OAuth token");
+        StravaClient::oauth_token(
+            client,
+            config,
+            &[
+                ("refresh_token", token.refresh_token.as_str()),
+                ("grant_type", "refresh_token"),
+            ],
+        )
+        .await
+    }
+
+    /// Requests an OAuth token for the given Strava application, with the
+    /// given grant parameters.
+    async fn oauth_token(
+        client: &Client,
+        config: &StravaConfig,
+        grant: &[(&str, &str)],
+    ) -> anyhow::Result<Token> {
         let response = client
-            .post("https://www.strava.com/oauth/token")
+            .post(&config.token_url)
             .query(&[
                 ("client_id", config.client_id.as_str()),
                 ("client_secret", config.client_secret.as_str()),
-                ("code", oauth_code),
-                ("grant_type", "authorization_code"),
             ])
+            .query(grant)
             .send()
             .await?;
 
@@ -209,7 +607,7 @@ impl<'a> StravaClient<'a> {
         let token: Token = response.json().await?;
         debug!("Token = {token:#?}");
 
-        Ok(token.access_token)
+        Ok(token)
     }
 
     /// Gets the authenticated athlete in Strava's API
@@ -217,71 +615,170 @@ impl<'a> StravaClient<'a> {
     pub async fn get_athlete(&self) -> anyhow::Result<DetailedAthlete> {
         debug!("Query authenticated athlete");
         let response = self
-            .client
-            .get(format!("{API_URL}/athlete"))
-            .bearer_auth(&self.bearer_token)
-            .send()
+            .send(self.client.get(format!("{}/athlete", self.api_url)))
             .await?;
 
-        let response = StravaClient::check_response_status(response).await?;
-
         let athlete = response.json().await?;
         Ok(athlete)
     }
 
-    /// Returns a stream of summary activities for the authenticated athlete in
-    /// Strava's API.
+    /// Lists the activities of the authenticated athlete in Strava's API, from
+    /// the most recent to the oldest, and updates the local index of the
+    /// activities in the cache, if any.
+    ///
+    /// With an index, only the activities started after the most recent
+    /// indexed activity are listed. Otherwise, the most recent `count_pages`
+    /// pages of activities within the date range of the given filter are
+    /// listed. A `backfill` lists the whole history of activities instead, and
+    /// rebuilds the index (e.g. to forget deleted activities).
+    ///
+    /// Without an index, the listing is only saved as the index if it covers
+    /// the whole history, i.e. it isn't restricted to a date range and ended
+    /// before the last page, as the following syncs only list the activities
+    /// newer than the index.
+    pub async fn sync_activity_index(
+        &self,
+        count_per_page: usize,
+        count_pages: usize,
+        backfill: bool,
+        filter: &ActivityFilter,
+        parallel_requests: usize,
+    ) -> anyhow::Result<Vec<SummaryActivity>> {
+        let index = match self.cache {
+            Some(cache) if !backfill => cache.get_activity_index().unwrap_or_else(|e| {
+                warn!("Couldn't read the Strava activity index, listing activities again: {e:?}");
+                None
+            }),
+            _ => None,
+        };
+
+        let indexed = index.is_some();
+        let mut activities = match index {
+            Some(mut index) => {
+                sort_activities(&mut index);
+                let after = index
+                    .first()
+                    .map(|a| a.start_timestamp())
+                    .transpose()
+                    .context("Invalid start date in the Strava activity index")?;
+                info!(
+                    "Found {} activities in the index, listing newer activities",
+                    index.len()
+                );
+                let new_activities = self.get_all_activities(count_per_page, after).await?;
+                info!("Found {} new activities", new_activities.len());
+                merge_activities(&mut index, new_activities);
+                index
+            }
+            None if backfill => {
+                info!("Listing all activities");
+                self.get_all_activities(count_per_page, None).await?
+            }
+            None => {
+                self.get_activity_list(
+                    count_per_page,
+                    count_pages,
+                    filter.after,
+                    filter.before,
+                    parallel_requests,
+                )
+                .await?
+            }
+        };
+        sort_activities(&mut activities);
+
+        // A listing that filled all the pages may have missed older activities.
+        let complete = indexed
+            || backfill
+            || (filter.after.is_none()
+                && filter.before.is_none()
+                && activities.len() < count_per_page * count_pages);
+        if let Some(cache) = self.cache {
+            if !complete {
+                info!("Not saving the Strava activity index, as the listing may not cover all the activities (run with --backfill to list them all)");
+            } else if let Err(e) = cache.set_activity_index(&activities) {
+                error!("Couldn't write the Strava activity index to the cache: {e:?}");
+            }
+        }
+        Ok(activities)
+    }
+
+    /// Lists all the activities of the authenticated athlete in Strava's API
+    /// that started after the given Unix timestamp (if any), paging until an
+    /// empty page.
+    async fn get_all_activities(
+        &self,
+        count_per_page: usize,
+        after: Option<i64>,
+    ) -> anyhow::Result<Vec<SummaryActivity>> {
+        let mut activities = Vec::new();
+        for i in 0.. {
+            let list = self
+                .get_activity_list_page(count_per_page, i, after, None)
+                .await
+                .with_context(|| format!("Failed to list page #{i} of activities"))?;
+            debug!("Received page #{i} with {} activities", list.len());
+            if list.is_empty() {
+                break;
+            }
+            activities.extend(list);
+        }
+        Ok(activities)
+    }
+
+    /// Lists the summary activities of the authenticated athlete in Strava's
+    /// API.
     ///
     /// This fetches the most recent `count_pages` pages, each containing
-    /// `count_per_page` activities.
-    pub fn get_activity_list(
+    /// `count_per_page` activities, optionally only the ones started within
+    /// the given Unix timestamps, and fails if any page couldn't be fetched.
+    pub async fn get_activity_list(
         &self,
         count_per_page: usize,
         count_pages: usize,
+        after: Option<i64>,
+        before: Option<i64>,
         parallel_requests: usize,
-    ) -> impl Stream<Item = SummaryActivity> + '_ {
-        stream::iter(0..count_pages)
-            .map(move |i| async move {
-                match self.get_activity_list_page(count_per_page, i).await {
-                    Ok(list) => Ok((i, list)),
-                    Err(e) => Err(e),
-                }
+    ) -> anyhow::Result<Vec<SummaryActivity>> {
+        let pages: Vec<Vec<SummaryActivity>> = stream::iter(0..count_pages)
+            .map(|i| async move {
+                let list = self
+                    .get_activity_list_page(count_per_page, i, after, before)
+                    .await
+                    .with_context(|| format!("Failed to list page #{i} of activities"))?;
+                debug!("Received page #{i} with {} activities", list.len());
+                Ok::<_, anyhow::Error>(list)
             })
             .buffered(parallel_requests)
-            .flat_map(|result| {
-                let list = match result {
-                    Ok((i, list)) => {
-                        debug!("Received page #{i}");
-                        list
-                    }
-                    Err(e) => {
-                        error!("Error receiving page: {e}");
-                        Vec::new()
-                    }
-                };
-                stream::iter(list)
-            })
+            .try_collect()
+            .await?;
+        Ok(pages.into_iter().flatten().collect())
     }
 
     /// Gets the list of activities for the authenticated athlete in Strava's
-    /// API ([getLoggedInAthleteActivities](https://developers.strava.com/docs/reference/#api-Activities-getLoggedInAthleteActivities)).
+    /// API ([getLoggedInAthleteActivities](https://developers.strava.com/docs/reference/#api-Activities-getLoggedInAthleteActivities)),
+    /// optionally only the ones started after and/or before the given Unix
+    /// timestamps.
     async fn get_activity_list_page(
         &self,
         count_per_page: usize,
         i: usize,
+        after: Option<i64>,
+        before: Option<i64>,
     ) -> anyhow::Result<Vec<SummaryActivity>> {
         debug!("Query page {}", i);
-        let response = self
-            .client
-            .get(format!(
-                "{API_URL}/athlete/activities?per_page={count_per_page}&page={page}",
-                page = i + 1
-            ))
-            .bearer_auth(&self.bearer_token)
-            .send()
-            .await?;
-
-        let response = StravaClient::check_response_status(response).await?;
+        let mut request = self.client.get(format!(
+            "{}/athlete/activities?per_page={count_per_page}&page={page}",
+            self.api_url,
+            page = i + 1
+        ));
+        if let Some(after) = after {
+            request = request.query(&[("after", after)]);
+        }
+        if let Some(before) = before {
+            request = request.query(&[("before", before)]);
+        }
+        let response = self.send(request).await?;
 
         let activity_list = response.json().await?;
         Ok(activity_list)
@@ -291,58 +788,46 @@ impl<'a> StravaClient<'a> {
     /// activities, and sends the results as UI messages to the given sending
     /// channel.
     ///
+    /// The streams of the activities are read from the cache, or fetched if
+    /// `fetch_streams` is set.
+    ///
     /// This makes up to `parallel_requests` in parallel.
     pub async fn get_detailed_activities_parallel(
         &self,
         tx: &mpsc::Sender<UiMessage>,
         activity_stream: impl Stream<Item = SummaryActivity>,
+        fetch_streams: bool,
         parallel_requests: usize,
     ) -> anyhow::Result<()> {
-        let detailed_activities = activity_stream
+        let progress = ProgressReporter::new(tx, None)?;
+        let mut detailed_activities = pin!(activity_stream
             .enumerate()
             .map(|(i, activity)| async move {
-                self.get_detailed_activity(&activity, i)
+                let detailed = self.get_detailed_activity(&activity, i).await?;
+                let streams = self
+                    .get_activity_streams(activity.id, i, fetch_streams)
                     .await
-                    .map(|a| (i, a))
-            })
-            .buffer_unordered(parallel_requests);
-
-        detailed_activities
-            .for_each(|activity| async {
-                match activity {
-                    Ok((i, a)) => {
-                        trace!("Activity = {a:#?}");
-                        let summary = a
-                            .map
-                            .summary_polyline
-                            .as_ref()
-                            .and_then(|p| Polyline::new(p));
-                        let polyline = a.map.polyline.as_ref().and_then(|p| Polyline::new(p));
-                        debug!(
-                            "Summary polyline has {:?} points in {:?} bytes",
-                            summary.map(|p| p.len()),
-                            a.map.summary_polyline.map(|p| p.len())
+                    .unwrap_or_else(|e| {
+                        warn!(
+                            "Couldn't get the streams of activity {i}, using its polyline: {e:?}"
                         );
-                        debug!(
-                            "Polyline has {:?} points in {:?} bytes",
-                            polyline.as_ref().map(|p| p.len()),
-                            a.map.polyline.map(|p| p.len())
-                        );
-                        if let Some(p) = polyline {
-                            tx.send(UiMessage::Activity {
-                                id: i,
-                                r#type: a.r#type,
-                                points: p.mercator_points(),
-                            })
-                            .unwrap();
-                        }
-                    }
-                    Err(e) => error!("Got an error: {e}"),
-                }
+                        None
+                    });
+                anyhow::Ok((i, detailed, streams))
             })
-            .await;
+            .buffer_unordered(parallel_requests));
 
-        Ok(())
+        while let Some(activity) = detailed_activities.next().await {
+            match activity {
+                Ok((i, a, streams)) => {
+                    send_activity(tx, i, a, streams)?;
+                    progress.loaded()?;
+                }
+                Err(e) => progress.failed(&e)?,
+            }
+        }
+
+        progress.finish()
     }
 
     /// Gets the detailed activity corresponding to a summary activity in
@@ -364,15 +849,12 @@ impl<'a> StravaClient<'a> {
 
         debug!("Query activity {i}");
         let response = self
-            .client
-            .get(format!("{API_URL}/activities/{}", activity.id))
-            .bearer_auth(&self.bearer_token)
-            .send()
+            .send(
+                self.client
+                    .get(format!("{}/activities/{}", self.api_url, activity.id)),
+            )
             .await?;
 
-        debug!("Checking response for activity {i}");
-        let response = StravaClient::check_response_status(response).await?;
-
         let activity_bytes = response.bytes().await?;
         let activity = match serde_json::from_slice(&activity_bytes) {
             Ok(a) => a,
@@ -391,6 +873,203 @@ impl<'a> StravaClient<'a> {
         Ok(activity)
     }
 
+    /// Gets the [streams](https://developers.strava.com/docs/reference/#api-Streams-getActivityStreams)
+    /// of the given activity, from the cache or, if `fetch` is set, from
+    /// Strava's API.
+    async fn get_activity_streams(
+        &self,
+        id: u64,
+        i: usize,
+        fetch: bool,
+    ) -> anyhow::Result<Option<StreamSet>> {
+        if let Some(cache) = self.cache {
+            if let Ok(streams) = cache.get_activity_streams(id).await {
+                debug!("Obtained streams of activity {i} from cache");
+                return Ok(Some(streams));
+            }
+        }
+        if !fetch {
+            return Ok(None);
+        }
+
+        debug!("Query streams of activity {i}");
+        let response = self
+            .send(
+                self.client
+                    .get(format!("{}/activities/{id}/streams", self.api_url))
+                    .query(&[("keys", STREAM_KEYS), ("key_by_type", "true")]),
+            )
+            .await?;
+        let streams: StreamSet = response.json().await?;
+
+        if let Some(cache) = self.cache {
+            if let Err(e) = cache.set_activity_streams(id, &streams) {
+                error!("Couldn't write streams of activity {i} to cache: {e:?}");
+            }
+        }
+        Ok(Some(streams))
+    }
+
+    /// Fetches the routes of the given athlete in Strava's API
+    /// ([getRoutesByAthleteId](https://developers.strava.com/docs/reference/#api-Routes-getRoutesByAthleteId)),
+    /// and sends them as UI messages on the route layer to the given sending
+    /// channel.
+    pub async fn send_routes(
+        &self,
+        tx: &mpsc::Sender<UiMessage>,
+        athlete_id: u64,
+        count_per_page: usize,
+    ) -> anyhow::Result<()> {
+        let routes: Vec<Route> = self
+            .get_all_pages(
+                &format!("{}/athletes/{athlete_id}/routes", self.api_url),
+                count_per_page,
+                "routes",
+            )
+            .await?;
+        info!("Found {} routes", routes.len());
+
+        for (i, route) in routes.into_iter().enumerate() {
+            trace!("Route = {route:#?}");
+            let polyline = route
+                .map
+                .polyline
+                .as_deref()
+                .or(route.map.summary_polyline.as_deref())
+                .and_then(Polyline::new);
+            match polyline {
+                Some(p) => tx.send(UiMessage::Activity {
+                    id: i,
+                    layer: TrackLayer::Route,
+                    sport_type: route.sport_type(),
+                    points: p.mercator_points(),
+                    attributes: TrackAttributes::new(),
+                })?,
+                None => warn!("Route {} ({:?}) has no polyline", route.id, route.name),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches the segments starred by the authenticated athlete in Strava's
+    /// API ([getLoggedInAthleteStarredSegments](https://developers.strava.com/docs/reference/#api-Segments-getLoggedInAthleteStarredSegments)),
+    /// and sends them as UI messages on the segment layer to the given sending
+    /// channel.
+    ///
+    /// This makes up to `parallel_requests` in parallel.
+    pub async fn send_starred_segments(
+        &self,
+        tx: &mpsc::Sender<UiMessage>,
+        count_per_page: usize,
+        parallel_requests: usize,
+    ) -> anyhow::Result<()> {
+        let segments: Vec<SummarySegment> = self
+            .get_all_pages(
+                &format!("{}/segments/starred", self.api_url),
+                count_per_page,
+                "starred segments",
+            )
+            .await?;
+        info!("Found {} starred segments", segments.len());
+
+        let mut detailed_segments = pin!(stream::iter(segments)
+            .enumerate()
+            .map(|(i, segment)| async move {
+                self.get_detailed_segment(segment.id)
+                    .await
+                    .with_context(|| format!("Failed to get segment {:?}", segment.name))
+                    .map(|detailed| (i, detailed))
+            })
+            .buffer_unordered(parallel_requests));
+
+        while let Some(segment) = detailed_segments.next().await {
+            match segment {
+                Ok((i, segment)) => send_segment(tx, i, segment)?,
+                Err(e) => error!("Got an error: {e:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the detailed segment with the given ID in Strava's API
+    /// ([getSegmentById](https://developers.strava.com/docs/reference/#api-Segments-getSegmentById)).
+    async fn get_detailed_segment(&self, id: u64) -> anyhow::Result<DetailedSegment> {
+        debug!("Query segment {id}");
+        let response = self
+            .send(self.client.get(format!("{}/segments/{id}", self.api_url)))
+            .await?;
+
+        let segment = response.json().await?;
+        Ok(segment)
+    }
+
+    /// Lists all the items of the given paginated endpoint of Strava's API,
+    /// paging until an empty page.
+    async fn get_all_pages<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        count_per_page: usize,
+        what: &str,
+    ) -> anyhow::Result<Vec<T>> {
+        let mut items = Vec::new();
+        for page in 1.. {
+            debug!("Query page {page} of {what}");
+            let list: Vec<T> = async {
+                let response = self
+                    .send(
+                        self.client
+                            .get(url)
+                            .query(&[("per_page", count_per_page), ("page", page)]),
+                    )
+                    .await?;
+                anyhow::Ok(response.json().await?)
+            }
+            .await
+            .with_context(|| format!("Failed to list page #{page} of {what}"))?;
+            debug!("Received page #{page} with {} {what}", list.len());
+            if list.is_empty() {
+                break;
+            }
+            items.extend(list);
+        }
+        Ok(items)
+    }
+
+    /// Logs the number of requests that can still be sent to Strava's API
+    /// within its rate limits.
+    pub fn log_rate_limit_budget(&self) {
+        self.rate_limiter.log_budget();
+    }
+
+    /// Sends the given authenticated request to Strava's API, within its rate
+    /// limits, and checks the status of the response.
+    ///
+    /// If Strava rejects the request for exceeding the rate limits, it's sent
+    /// again once the exhausted window resets.
+    async fn send(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            self.rate_limiter.acquire().await;
+            let response = request
+                .try_clone()
+                .context("Failed to clone request to Strava")?
+                .bearer_auth(&self.bearer_token)
+                .send()
+                .await?;
+            self.rate_limiter.update(response.headers());
+
+            if response.status() == StatusCode::TOO_MANY_REQUESTS
+                && attempt < MAX_RATE_LIMITED_RETRIES
+            {
+                warn!("Strava's rate limit was exceeded, retrying once it resets");
+                self.rate_limiter.exhaust();
+                attempt += 1;
+                continue;
+            }
+            return StravaClient::check_response_status(response).await;
+        }
+    }
+
     /// Checks that a response from Strava's API contains an OK status code,
     /// returning an error with more details otherwise.
     async fn check_response_status(response: Response) -> anyhow::Result<Response> {
@@ -405,3 +1084,364 @@ impl<'a> StravaClient<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::caching::cache::{CacheAccess, TileCacheBackend, TileCacheParams};
+    use crate::tracks::fake_strava::*;
+    use std::future::Future;
+    use std::path::PathBuf;
+
+    /// Runs the given future to completion on a new Tokio runtime.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    /// Returns a configuration for the given fake Strava server.
+    fn fake_config(fake: &FakeStrava) -> StravaConfig {
+        StravaConfig {
+            client_id: CLIENT_ID.to_owned(),
+            client_secret: CLIENT_SECRET.to_owned(),
+            api_url: fake.api_url(),
+            authorize_url: fake.authorize_url(),
+            token_url: fake.token_url(),
+        }
+    }
+
+    /// Returns a client for the given fake Strava server, authenticated with
+    /// the given access token.
+    fn fake_client<'a>(fake: &FakeStrava, client: &'a Client, token: &str) -> StravaClient<'a> {
+        StravaClient {
+            cache: None,
+            client,
+            api_url: fake.api_url(),
+            bearer_token: token.to_owned(),
+            rate_limiter: RateLimiter::default(),
+        }
+    }
+
+    /// Returns a new empty cache in a temporary directory with the given name.
+    fn temp_cache(name: &str) -> (Cache, PathBuf) {
+        let directory =
+            std::env::temp_dir().join(format!("ridemap-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let cache = Cache::new(
+            directory.to_str().unwrap(),
+            &[],
+            TileCacheParams {
+                backend: TileCacheBackend::Directory,
+                max_age: Duration::from_secs(3600),
+                max_size: None,
+            },
+            CacheAccess::Shared,
+        )
+        .unwrap();
+        (cache, directory)
+    }
+
+    fn activity(id: u64, start_date: &str) -> SummaryActivity {
+        SummaryActivity {
+            id,
+            name: format!("Activity {id}"),
+            r#type: ActivityType::Ride,
+            sport_type: None,
+            start_date: start_date.to_owned(),
+            gear_id: None,
+        }
+    }
+
+    #[test]
+    fn stream_track_attributes() {
+        let streams = StreamSet {
+            latlng: Some(ActivityStream {
+                data: vec![[45.0, 5.0], [45.1, 5.1]],
+            }),
+            altitude: Some(ActivityStream {
+                data: vec![Some(200.0), None],
+            }),
+            heartrate: Some(ActivityStream {
+                data: vec![Some(120.0)],
+            }),
+            ..StreamSet::default()
+        };
+        let (points, attributes) = stream_track(streams).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            attributes,
+            TrackAttributes::from([(TrackAttribute::Altitude, vec![Some(200.0), None])])
+        );
+
+        assert!(stream_track(StreamSet::default()).is_none());
+    }
+
+    #[test]
+    fn merge_activities_sorted() {
+        let mut index = vec![
+            activity(2, "2024-05-02T08:00:00Z"),
+            activity(1, "2024-05-01T08:00:00Z"),
+        ];
+        let mut renamed = activity(2, "2024-05-02T08:00:00Z");
+        renamed.name = "Renamed".to_owned();
+        merge_activities(
+            &mut index,
+            vec![activity(4, "2024-05-03T08:00:00Z"), renamed],
+        );
+        let ids: Vec<u64> = index.iter().map(|a| a.id).collect();
+        assert_eq!(ids, [4, 2, 1]);
+        assert_eq!(index[1].name, "Renamed");
+    }
+
+    #[test]
+    fn client_oauth_token() {
+        block_on(async {
+            let fake = FakeStrava::start(0).await;
+            let client = Client::new();
+            let config = fake_config(&fake);
+
+            let token = StravaClient::oauth_exchange(&client, &config, AUTHORIZATION_CODE)
+                .await
+                .unwrap();
+            assert_eq!(token.access_token, ACCESS_TOKEN);
+            assert!(!token.expires_within(TOKEN_EXPIRY_MARGIN));
+
+            let refreshed = StravaClient::oauth_refresh(&client, &config, &token)
+                .await
+                .unwrap();
+            assert_eq!(refreshed.access_token, ACCESS_TOKEN);
+
+            assert!(StravaClient::oauth_exchange(&client, &config, "forged")
+                .await
+                .is_err());
+            let wrong_secret = StravaConfig {
+                client_secret: "ffff".to_owned(),
+                ..fake_config(&fake)
+            };
+            assert!(
+                StravaClient::oauth_exchange(&client, &wrong_secret, AUTHORIZATION_CODE)
+                    .await
+                    .is_err()
+            );
+        });
+    }
+
+    #[test]
+    fn client_sync_activity_index() {
+        block_on(async {
+            let fake = FakeStrava::start(5).await;
+            let client = Client::new();
+            let strava = fake_client(&fake, &client, ACCESS_TOKEN);
+
+            let athlete = strava.get_athlete().await.unwrap();
+            assert_eq!(athlete.id, ATHLETE_ID);
+            assert_eq!(
+                athlete.find_gear("gravel bike").map(|g| g.id.as_str()),
+                Some("b1")
+            );
+
+            // A backfill pages until an empty page.
+            let activities = strava
+                .sync_activity_index(2, 1, true, &ActivityFilter::default(), 1)
+                .await
+                .unwrap();
+            let ids: Vec<u64> = activities.iter().map(|a| a.id).collect();
+            assert_eq!(ids, [5, 4, 3, 2, 1]);
+            assert_eq!(activities[0].sport_type(), SportType::GravelRide);
+            assert_eq!(activities[1].gear_id.as_deref(), Some("g1"));
+            assert!(fake
+                .requests()
+                .contains(&"GET /api/v3/athlete/activities?per_page=2&page=4".to_owned()));
+
+            // Without an index, the date range is passed to the API.
+            let filter = ActivityFilter {
+                after: Some(parse_date_time("2024-02-02T00:00:00Z").unwrap()),
+                before: Some(parse_date_time("2024-02-05T00:00:00Z").unwrap()),
+                ..Default::default()
+            };
+            let activities = strava
+                .sync_activity_index(2, 3, false, &filter, 1)
+                .await
+                .unwrap();
+            let ids: Vec<u64> = activities.iter().map(|a| a.id).collect();
+            assert_eq!(ids, [4, 3, 2]);
+            assert!(fake
+                .requests()
+                .iter()
+                .any(|request| request.contains(&format!(
+                    "after={}&before={}",
+                    filter.after.unwrap(),
+                    filter.before.unwrap()
+                ))));
+        });
+    }
+
+    #[test]
+    fn client_sync_activity_index_date_range() {
+        let (cache, directory) = temp_cache("sync-date-range");
+        block_on(async {
+            let fake = FakeStrava::start(5).await;
+            let client = Client::new();
+            let strava = StravaClient {
+                cache: Some(&cache),
+                ..fake_client(&fake, &client, ACCESS_TOKEN)
+            };
+
+            // A listing restricted to a date range isn't saved as the index.
+            let filter = ActivityFilter {
+                after: Some(parse_date_time("2024-02-04T00:00:00Z").unwrap()),
+                ..Default::default()
+            };
+            let activities = strava
+                .sync_activity_index(10, 1, false, &filter, 1)
+                .await
+                .unwrap();
+            let ids: Vec<u64> = activities.iter().map(|a| a.id).collect();
+            assert_eq!(ids, [5, 4]);
+            assert!(cache.get_activity_index().unwrap().is_none());
+
+            // A later unfiltered sync still lists the earlier activities.
+            let activities = strava
+                .sync_activity_index(10, 1, false, &ActivityFilter::default(), 1)
+                .await
+                .unwrap();
+            let ids: Vec<u64> = activities.iter().map(|a| a.id).collect();
+            assert_eq!(ids, [5, 4, 3, 2, 1]);
+            let index = cache.get_activity_index().unwrap().unwrap();
+            assert_eq!(index.len(), 5);
+        });
+        drop(cache);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn client_sync_activity_index_partial() {
+        let (cache, directory) = temp_cache("sync-partial");
+        block_on(async {
+            let fake = FakeStrava::start(5).await;
+            let client = Client::new();
+            let strava = StravaClient {
+                cache: Some(&cache),
+                ..fake_client(&fake, &client, ACCESS_TOKEN)
+            };
+
+            // A listing that fills all the pages isn't saved as the index, as
+            // it may miss older activities.
+            let activities = strava
+                .sync_activity_index(2, 2, false, &ActivityFilter::default(), 1)
+                .await
+                .unwrap();
+            let ids: Vec<u64> = activities.iter().map(|a| a.id).collect();
+            assert_eq!(ids, [5, 4, 3, 2]);
+            assert!(cache.get_activity_index().unwrap().is_none());
+
+            // A later sync with a date range still lists the older activities.
+            let filter = ActivityFilter {
+                before: Some(parse_date_time("2024-02-02T00:00:00Z").unwrap()),
+                ..Default::default()
+            };
+            let activities = strava
+                .sync_activity_index(2, 2, false, &filter, 1)
+                .await
+                .unwrap();
+            let ids: Vec<u64> = activities.iter().map(|a| a.id).collect();
+            assert_eq!(ids, [1]);
+        });
+        drop(cache);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn client_detailed_activities() {
+        block_on(async {
+            let fake = FakeStrava::start(3).await;
+            let client = Client::new();
+            let strava = fake_client(&fake, &client, ACCESS_TOKEN);
+
+            let mut summaries = strava
+                .sync_activity_index(10, 1, false, &ActivityFilter::default(), 1)
+                .await
+                .unwrap();
+            // The fault of an unknown activity is reported without failing the
+            // other activities.
+            summaries.push(activity(99, &start_date(1)));
+
+            let (tx, rx) = mpsc::channel();
+            strava
+                .get_detailed_activities_parallel(&tx, stream::iter(summaries), false, 1)
+                .await
+                .unwrap();
+            let mut loaded = 0;
+            let mut errors = Vec::new();
+            for message in rx.try_iter() {
+                match message {
+                    UiMessage::Activity { layer, points, .. } => {
+                        assert_eq!(layer, TrackLayer::Activity);
+                        assert_eq!(points.len(), 3);
+                        loaded += 1;
+                    }
+                    UiMessage::TrackError { error } => errors.push(error),
+                    _ => (),
+                }
+            }
+            assert_eq!(loaded, 3);
+            assert_eq!(
+                errors,
+                ["Strava server replied with status code 404 Not Found"]
+            );
+
+            // The rate limits reported by the server are tracked.
+            let used = fake.api_requests();
+            assert_eq!(used, 5);
+            assert_eq!(
+                strava.rate_limiter.remaining(),
+                Some([RATE_LIMITS[0] - used, RATE_LIMITS[1] - used])
+            );
+        });
+    }
+
+    #[test]
+    fn client_unauthorized() {
+        block_on(async {
+            let fake = FakeStrava::start(1).await;
+            let client = Client::new();
+            let strava = fake_client(&fake, &client, "expired-token");
+            let error = strava.get_athlete().await.unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Strava server replied with status code 401 Unauthorized"
+            );
+        });
+    }
+
+    #[test]
+    fn activity_filter_date_and_gear() {
+        let filter = ActivityFilter {
+            after: Some(parse_date_time("2024-01-01T00:00:00Z").unwrap()),
+            before: Some(parse_date_time("2025-01-01T00:00:00Z").unwrap()),
+            gear_ids: vec!["b123".to_owned()],
+            ..Default::default()
+        };
+        let matches = |start_date, gear_id| {
+            filter.matches(
+                &ActivityType::Ride,
+                &SportType::GravelRide,
+                start_date,
+                gear_id,
+            )
+        };
+        assert!(matches(Some("2024-01-01T00:00:00Z"), Some("b123")));
+        assert!(matches(Some("2024-12-31T23:59:59Z"), Some("b123")));
+        assert!(!matches(Some("2025-01-01T00:00:00Z"), Some("b123")));
+        assert!(!matches(Some("2023-12-31T23:59:59Z"), Some("b123")));
+        assert!(!matches(Some("2024-06-01T08:00:00Z"), Some("g456")));
+        assert!(!matches(Some("2024-06-01T08:00:00Z"), None));
+        assert!(!matches(None, Some("b123")));
+
+        assert!(ActivityFilter::default().matches(
+            &ActivityType::Ride,
+            &SportType::GravelRide,
+            None,
+            None
+        ));
+    }
+}
@@ -1,35 +1,82 @@
 //! Module to render the map on the user interface.
 
 mod camera;
+mod status;
 mod tiles;
 mod tracks;
 pub mod util;
 pub mod window;
 
+pub use tiles::{LayerParams, TileParams};
+
 use crate::map::tiles::TileIndex;
 use crate::tracks::polyline::Point;
-use crate::tracks::schema::ActivityType;
+use crate::tracks::schema::SportType;
+use crate::tracks::{TrackAttributes, TrackLayer};
 use image::RgbaImage;
 
 /// Message sent from the background thread to the UI.
 pub enum UiMessage {
     /// GPS track to display on the UI.
     Activity {
-        /// Index of this activity (counter among all the activities requested
-        /// by the program).
+        /// Index of this activity (counter among all the tracks of its layer
+        /// requested by the program).
         id: usize,
-        /// Strava activity type.
-        r#type: ActivityType,
+        /// Layer of the map to display this track on.
+        layer: TrackLayer,
+        /// Strava sport type.
+        sport_type: SportType,
         /// Series of points on this activity, in Mercator coordinates.
         points: Vec<Point<f64>>,
+        /// Attributes recorded at each point of this activity, if any.
+        attributes: TrackAttributes,
     },
     /// Tile of the background map.
     Tile {
+        /// Map layer of this tile, i.e. the index of its map provider.
+        layer: usize,
         /// Position of this tile on the world map.
         index: TileIndex,
-        /// Raw PNG bytes of this tile.
-        png_image: Box<[u8]>,
+        /// Raw encoded bytes of this tile (PNG, JPEG, etc.), or of the ancestor
+        /// it was scaled up from in offline mode.
+        raw_image: Box<[u8]>,
         /// Decoded tile in RGBA format.
         rgba_image: RgbaImage,
     },
+    /// Failure to fetch a tile of the background map.
+    TileError {
+        /// Map layer of this tile, i.e. the index of its map provider.
+        layer: usize,
+        /// Position of this tile on the world map.
+        index: TileIndex,
+        /// Description of the error.
+        error: String,
+    },
+    /// Tile of the background map that isn't available in offline mode, or
+    /// that the server reported as missing.
+    TileMissing {
+        /// Map layer of this tile, i.e. the index of its map provider.
+        layer: usize,
+        /// Position of this tile on the world map.
+        index: TileIndex,
+    },
+    /// Progress of the tile requests.
+    TileProgress {
+        /// Number of tile requests currently being processed.
+        pending: usize,
+    },
+    /// Progress of loading the GPS tracks.
+    TrackProgress {
+        /// Number of tracks successfully loaded so far.
+        loaded: usize,
+        /// Number of tracks that failed to load so far.
+        failed: usize,
+        /// Total number of tracks to load, if known yet.
+        total: Option<usize>,
+    },
+    /// Failure to load GPS tracks.
+    TrackError {
+        /// Description of the error.
+        error: String,
+    },
 }
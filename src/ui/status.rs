@@ -0,0 +1,111 @@
+//! Module to track the progress and errors of the background thread on the UI
+//! thread.
+
+/// Progress of loading the GPS tracks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TrackProgress {
+    /// Number of tracks successfully loaded so far.
+    loaded: usize,
+    /// Number of tracks that failed to load so far.
+    failed: usize,
+    /// Total number of tracks to load, if known yet.
+    total: Option<usize>,
+}
+
+/// State on the UI thread to display the progress of the background thread.
+pub struct StatusState {
+    /// Number of tile requests currently being processed.
+    pending_tiles: usize,
+    /// Progress of loading the GPS tracks, if any are requested.
+    track_progress: Option<TrackProgress>,
+    /// Description of the last error, if any.
+    last_error: Option<String>,
+}
+
+#[allow(clippy::new_without_default)]
+impl StatusState {
+    /// Creates a new empty state.
+    pub fn new() -> Self {
+        Self {
+            pending_tiles: 0,
+            track_progress: None,
+            last_error: None,
+        }
+    }
+
+    /// Processes the progress of the tile requests sent by the background
+    /// thread.
+    pub fn process_tile_progress(&mut self, pending: usize) {
+        self.pending_tiles = pending;
+    }
+
+    /// Processes the progress of loading the GPS tracks sent by the background
+    /// thread.
+    pub fn process_track_progress(&mut self, loaded: usize, failed: usize, total: Option<usize>) {
+        self.track_progress = Some(TrackProgress {
+            loaded,
+            failed,
+            total,
+        });
+    }
+
+    /// Processes an error sent by the background thread.
+    pub fn process_error(&mut self, error: String) {
+        self.last_error = Some(error);
+    }
+
+    /// Returns the lines of text describing the current status, given the
+    /// number of tiles that failed to load and that are missing.
+    pub fn status_lines(&self, failed_tiles: usize, missing_tiles: usize) -> Vec<String> {
+        let mut tiles_line = format!(
+            "Tiles: {} pending, {failed_tiles} failed",
+            self.pending_tiles
+        );
+        if missing_tiles > 0 {
+            tiles_line.push_str(&format!(", {missing_tiles} missing"));
+        }
+        let mut lines = vec![tiles_line];
+        if let Some(progress) = &self.track_progress {
+            lines.push(track_progress_line(progress));
+        }
+        if let Some(error) = &self.last_error {
+            lines.push(format!("Last error: {error}"));
+        }
+        lines
+    }
+}
+
+/// Returns the line of text describing the given progress of loading tracks.
+fn track_progress_line(progress: &TrackProgress) -> String {
+    let done = progress.loaded + progress.failed;
+    match progress.total {
+        Some(total) if done >= total => format!(
+            "Tracks: {} loaded, {} failed",
+            progress.loaded, progress.failed
+        ),
+        Some(total) => format!(
+            "Tracks: loading {done} / {total}, {} failed",
+            progress.failed
+        ),
+        None => format!("Tracks: loading {done}, {} failed", progress.failed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn track_progress_lines() {
+        let line = |loaded, failed, total| {
+            track_progress_line(&TrackProgress {
+                loaded,
+                failed,
+                total,
+            })
+        };
+        assert_eq!(line(3, 1, None), "Tracks: loading 4, 1 failed");
+        assert_eq!(line(3, 1, Some(10)), "Tracks: loading 4 / 10, 1 failed");
+        assert_eq!(line(9, 1, Some(10)), "Tracks: 9 loaded, 1 failed");
+    }
+}
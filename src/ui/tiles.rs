@@ -5,21 +5,85 @@ use super::util::warn_on_error;
 use super::util::Tile;
 use crate::caching::lru::Lru;
 use crate::map::tile_box::TileBox;
-use crate::map::tile_channel::TileRequestSender;
+use crate::map::tile_channel::{TileRequestSender, VisibleTiles};
 use crate::map::tiles::TileIndex;
 use image::RgbaImage;
-use log::{debug, trace};
+use log::{debug, info, trace};
 use std::cell::Cell;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::rc::Rc;
 
+/// Parameters of a map layer, i.e. the tiles of a map provider.
+#[derive(Clone, Copy, Debug)]
+pub struct LayerParams {
+    /// Whether this layer is an overlay drawn on top of the base map, rather
+    /// than a base map.
+    pub overlay: bool,
+    /// Opacity at which to draw the tiles of this layer, between 0.0 and 1.0.
+    pub opacity: f32,
+}
+
+/// Parameters controlling how map tiles are loaded on the UI thread.
+#[derive(Clone, Debug)]
+pub struct TileParams {
+    /// Map layers, indexed like the map providers. The UI switches between the
+    /// base maps, and draws all the overlays on top of the current one.
+    pub layers: Vec<LayerParams>,
+    /// Whether to speculatively load tiles based on the mouse movements.
+    pub speculative_tile_load: bool,
+    /// Maximum number of pixels that a tile is allowed to be zoomed to.
+    pub max_pixels_per_tile: usize,
+    /// Maximum zoom level to load tiles at.
+    pub max_tile_level: i32,
+}
+
+/// Kind of placeholder drawn instead of a tile that isn't available.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placeholder {
+    /// The background thread failed to fetch the tile.
+    Failed,
+    /// The tile isn't available in offline mode, or the server reported it as
+    /// missing.
+    Missing,
+}
+
+impl Placeholder {
+    /// Returns the RGBA colors to fill this placeholder with and to draw its
+    /// hatch pattern with.
+    pub fn colors(self) -> ([f32; 4], [f32; 4]) {
+        match self {
+            Placeholder::Failed => ([0.5, 0.5, 0.5, 0.3], [0.4, 0.4, 0.4, 0.8]),
+            Placeholder::Missing => ([0.3, 0.5, 0.8, 0.2], [0.2, 0.4, 0.7, 0.6]),
+        }
+    }
+}
+
+/// A tile to draw on the UI.
+pub struct TileToDraw<'a, Image> {
+    /// Position of this tile on the world map.
+    pub index: TileIndex,
+    /// Loaded tile.
+    pub tile: &'a Tile<Image>,
+    /// Opacity of the tile's layer, between 0.0 and 1.0.
+    pub opacity: f32,
+}
+
 /// State on the UI thread to manage map tiles.
 pub struct TileState<Image> {
     /// Channel to request tiles from the background thread.
     tiles_tx: TileRequestSender,
-    /// LRU cache of tiles loaded in memory and ready to use on the UI thread.
-    tiles: Lru<TileIndex, Tile<Image>>,
+    /// Parameters of each map layer.
+    layers: Vec<LayerParams>,
+    /// LRU caches of tiles loaded in memory and ready to use on the UI thread,
+    /// one for each map layer.
+    tiles: Vec<Lru<TileIndex, Tile<Image>>>,
+    /// Tiles that the background thread failed to fetch or that aren't
+    /// available offline, for each map layer. These are forgotten once they
+    /// aren't visible anymore or are requested again.
+    unavailable: Vec<HashMap<TileIndex, Placeholder>>,
+    /// Index of the base map layer currently displayed.
+    base_layer: usize,
     /// Box of tiles currently visible in the UI window.
     tile_box: TileBox,
     /// Whether to speculatively load tiles based on the mouse movements.
@@ -36,7 +100,7 @@ pub struct TileState<Image> {
 }
 
 impl<Image> TileState<Image> {
-    /// Capacity of the in-memory LRU cache of tiles.
+    /// Capacity of the in-memory LRU cache of tiles, for each map layer.
     const LRU_CAPACITY: usize = 200;
 
     /// Creates a new tile state based on the given parameters.
@@ -44,25 +108,53 @@ impl<Image> TileState<Image> {
     /// This doesn't trigger any request, see the [`Self::start()`] function.
     pub fn new(
         tiles_tx: TileRequestSender,
-        speculative_tile_load: bool,
-        max_pixels_per_tile: usize,
-        max_tile_level: i32,
+        params: TileParams,
         iteration: Rc<Cell<usize>>,
     ) -> Self {
+        let base_layer = params
+            .layers
+            .iter()
+            .position(|layer| !layer.overlay)
+            .unwrap_or_default();
         Self {
             tiles_tx,
-            tiles: Lru::with_capacity(Self::LRU_CAPACITY),
+            tiles: params
+                .layers
+                .iter()
+                .map(|_| Lru::with_capacity(Self::LRU_CAPACITY))
+                .collect(),
+            unavailable: params.layers.iter().map(|_| HashMap::new()).collect(),
+            layers: params.layers,
+            base_layer,
             tile_box: TileBox::root(),
-            speculative_tile_load,
-            max_pixels_per_tile,
-            max_tile_level,
+            speculative_tile_load: params.speculative_tile_load,
+            max_pixels_per_tile: params.max_pixels_per_tile,
+            max_tile_level: params.max_tile_level,
             iteration,
         }
     }
 
     /// Starts requesting tiles, i.e. request the root tile.
-    pub fn start(&self) {
-        self.request_tiles(Some(self.get_request_tiles()), Box::new([]));
+    pub fn start(&mut self) {
+        self.request_visible_tiles(self.get_request_tiles(), Box::new([]));
+    }
+
+    /// Switches to the next base map, cycling back to the first one after the
+    /// last one, and requests the tiles that are not loaded yet for it.
+    pub fn next_base_layer(&mut self) {
+        let count = self.layers.len();
+        if let Some(next) = (1..=count)
+            .map(|i| (self.base_layer + i) % count)
+            .find(|&layer| !self.layers[layer].overlay)
+        {
+            self.base_layer = next;
+        }
+        info!(
+            "[{i}] Switching to base map layer #{layer}",
+            i = self.iteration.get(),
+            layer = self.base_layer,
+        );
+        self.request_visible_tiles(self.get_request_tiles(), Box::new([]));
     }
 
     /// Stops the processing, closing the tile request channel to the background
@@ -83,7 +175,7 @@ impl<Image> TileState<Image> {
         let new_tile_box = camera.refresh(self.max_pixels_per_tile, self.max_tile_level);
 
         // Request tiles.
-        let tiles = if self.tile_box != new_tile_box {
+        let visible = if self.tile_box != new_tile_box {
             debug!(
                 "[{i}] New tile box: {new_tile_box:?}",
                 i = self.iteration.get(),
@@ -100,11 +192,15 @@ impl<Image> TileState<Image> {
             Box::new([])
         };
 
-        self.request_tiles(tiles, speculative);
+        match visible {
+            Some(visible) => self.request_visible_tiles(visible, speculative),
+            None => self.request_tiles(None, speculative),
+        }
     }
 
     /// Processes the given tile sent by the background thread, returning `true`
-    /// if the tile was successfully inserted in the LRU cache.
+    /// if the tile was successfully inserted (or replaced) in the LRU cache of a
+    /// map layer currently displayed.
     ///
     /// Insertion can fail in either of the following cases.
     /// * The tile couldn't be loaded as a UI texture.
@@ -112,18 +208,31 @@ impl<Image> TileState<Image> {
     ///   tile in the cache.
     pub fn process_tile(
         &mut self,
+        layer: usize,
         index: TileIndex,
-        png_image: &[u8],
+        raw_image: &[u8],
         rgba_image: RgbaImage,
         create_image: impl FnOnce(RgbaImage) -> Option<Image>,
     ) -> bool {
         debug!(
-            "[{i}] Received tile {index:?} = {bytes} bytes",
+            "[{i}] Received tile {index:?} of layer #{layer} = {bytes} bytes",
             i = self.iteration.get(),
-            bytes = png_image.len()
+            bytes = raw_image.len()
         );
 
-        let (inserted, evicted) = self.tiles.or_insert_with(
+        self.unavailable[layer].remove(&index);
+
+        // A tile can be received again after the backend revalidated a stale
+        // cached tile, in which case the updated image replaces the current one.
+        if self.tiles[layer].contains_key(&index) {
+            let replaced = match create_image(rgba_image) {
+                Some(image) => self.tiles[layer].replace(&index, Tile { image }),
+                None => false,
+            };
+            return replaced && self.active_layers().contains(&layer);
+        }
+
+        let (inserted, evicted) = self.tiles[layer].or_insert_with(
             index,
             |idx| {
                 // Priority order of tiles for LRU cache.
@@ -148,57 +257,169 @@ impl<Image> TileState<Image> {
         );
 
         if let Some(evicted) = evicted {
-            self.evict_tile(evicted);
+            self.evict_tile(layer, evicted);
         }
 
-        inserted
+        // Tiles of hidden base maps are kept for later, but don't need a refresh.
+        inserted && self.active_layers().contains(&layer)
     }
 
-    /// Returns the current set of tiles to draw, based on the camera position
-    /// and available tiles.
+    /// Processes the failure to fetch the given tile reported by the background
+    /// thread, returning `true` if the tile is currently visible.
+    pub fn process_tile_error(&mut self, layer: usize, index: TileIndex) -> bool {
+        debug!(
+            "[{i}] Failed to fetch tile {index:?} of layer #{layer}",
+            i = self.iteration.get()
+        );
+        self.mark_unavailable(layer, index, Placeholder::Failed)
+    }
+
+    /// Processes the given tile reported as missing (in offline mode or by the
+    /// server) by the background thread, returning `true` if the tile is
+    /// currently visible.
+    pub fn process_tile_missing(&mut self, layer: usize, index: TileIndex) -> bool {
+        debug!(
+            "[{i}] Tile {index:?} of layer #{layer} is missing",
+            i = self.iteration.get()
+        );
+        self.mark_unavailable(layer, index, Placeholder::Missing)
+    }
+
+    /// Marks the given tile as unavailable with the given kind of placeholder,
+    /// returning `true` if the tile is currently visible.
+    fn mark_unavailable(
+        &mut self,
+        layer: usize,
+        index: TileIndex,
+        placeholder: Placeholder,
+    ) -> bool {
+        self.unavailable[layer].insert(index, placeholder);
+        self.tile_box.contains(&index) && self.active_layers().contains(&layer)
+    }
+
+    /// Returns the number of visible tiles that are unavailable with the given
+    /// kind of placeholder, in the current map layers.
+    pub fn unavailable_count(&self, placeholder: Placeholder) -> usize {
+        self.active_layers()
+            .into_iter()
+            .flat_map(|layer| self.unavailable[layer].iter())
+            .filter(|&(index, &p)| p == placeholder && self.tile_box.contains(index))
+            .count()
+    }
+
+    /// Forgets the unavailable tiles that aren't visible in the current map
+    /// layers anymore, or that are requested again in the given visible tiles.
+    fn forget_unavailable(&mut self, visible: &VisibleTiles) {
+        let layers = self.active_layers();
+        let tile_box = &self.tile_box;
+        for (layer, unavailable) in self.unavailable.iter_mut().enumerate() {
+            if layers.contains(&layer) {
+                unavailable
+                    .retain(|index, _| tile_box.contains(index) && !visible.tiles.contains(index));
+            } else {
+                unavailable.clear();
+            }
+        }
+    }
+
+    /// Returns the visible tiles that aren't available in any of the current
+    /// map layers, with the kind of placeholder to draw instead.
+    pub fn placeholders_to_draw(&self) -> Vec<(TileIndex, Placeholder)> {
+        let layers = self.active_layers();
+        self.tile_box
+            .tile_indices()
+            .into_iter()
+            .filter_map(|index| {
+                let placeholder = layers
+                    .iter()
+                    .find_map(|&layer| self.unavailable[layer].get(&index))?;
+                Some((index, *placeholder))
+            })
+            .collect()
+    }
+
+    /// Returns the current set of tiles to draw, based on the camera position,
+    /// the current map layers and available tiles.
     ///
-    /// If a tile is not available at the current zoom level, one of its
-    /// ancestors is returned from the cache instead.
+    /// Layers are returned in drawing order, i.e. the base map followed by the
+    /// overlays. For the base map, if a tile is not available at the current
+    /// zoom level, one of its ancestors is returned from the cache instead.
+    /// Overlays don't fall back to ancestors, as these would show through the
+    /// transparent parts of the tiles drawn on top of them.
     ///
-    /// The returned tiles are sorted from small to large zoom level (so that
-    /// tiles with a larger zoom level will appear on top if tiles are
-    /// rendered in order).
-    pub fn tiles_to_draw(&self) -> Vec<(TileIndex, &Tile<Image>)> {
-        let mut tiles_to_draw: HashMap<TileIndex, &Tile<Image>> = HashMap::new();
-        for mut index in self.tile_box.tile_indices() {
-            loop {
-                if let Some(tile) = self.tiles.get(&index) {
-                    tiles_to_draw.insert(index, tile);
-                    break;
-                }
-                match index.parent() {
-                    Some(p) => index = p,
-                    None => break,
+    /// Within each layer, the returned tiles are sorted from small to large
+    /// zoom level (so that tiles with a larger zoom level will appear on top if
+    /// tiles are rendered in order).
+    pub fn tiles_to_draw(&self) -> Vec<TileToDraw<'_, Image>> {
+        let mut result = Vec::new();
+        for layer in self.active_layers() {
+            let tiles = &self.tiles[layer];
+            let params = &self.layers[layer];
+            let mut tiles_to_draw: HashMap<TileIndex, &Tile<Image>> = HashMap::new();
+            for mut index in self.tile_box.tile_indices() {
+                loop {
+                    if let Some(tile) = tiles.get(&index) {
+                        tiles_to_draw.insert(index, tile);
+                        break;
+                    }
+                    if params.overlay {
+                        break;
+                    }
+                    match index.parent() {
+                        Some(p) => index = p,
+                        None => break,
+                    }
                 }
             }
+
+            let mut tiles_to_draw: Vec<(TileIndex, &Tile<Image>)> = tiles_to_draw.drain().collect();
+            #[allow(clippy::unnecessary_sort_by)]
+            tiles_to_draw.sort_by(|a, b| a.0.cmp(&b.0));
+
+            result.extend(tiles_to_draw.into_iter().map(|(index, tile)| TileToDraw {
+                index,
+                tile,
+                opacity: params.opacity,
+            }));
         }
 
-        let mut tiles_to_draw: Vec<(TileIndex, &Tile<Image>)> = tiles_to_draw.drain().collect();
-        tiles_to_draw.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
 
-        tiles_to_draw
+    /// Returns the map layers currently displayed, i.e. the current base map
+    /// followed by all the overlays.
+    fn active_layers(&self) -> Vec<usize> {
+        let overlays = (0..self.layers.len()).filter(|&layer| self.layers[layer].overlay);
+        std::iter::once(self.base_layer).chain(overlays).collect()
     }
 
     /// Filters out tiles from the input list that are already contained in the
-    /// LRU cache.
+    /// LRU caches of all the current map layers.
     fn filter_new_tiles(&self, mut tiles: Vec<TileIndex>) -> Box<[TileIndex]> {
-        tiles.retain(|index| !self.tiles.contains_key(index));
+        let layers = self.active_layers();
+        tiles.retain(|index| {
+            layers
+                .iter()
+                .any(|&layer| !self.tiles[layer].contains_key(index))
+        });
         tiles.into_boxed_slice()
     }
 
-    /// Returns the list of tiles in the current window box that are not yet
-    /// contained in the LRU cache.
-    fn get_request_tiles(&self) -> Box<[TileIndex]> {
-        self.filter_new_tiles(self.tile_box.tile_indices())
+    /// Returns the tiles in the current window box that are not yet contained
+    /// in the LRU caches, as well as their ancestors missing from the LRU cache
+    /// of the base map.
+    fn get_request_tiles(&self) -> VisibleTiles {
+        let mut ancestors = self.tile_box.ancestor_indices();
+        ancestors.retain(|index| !self.tiles[self.base_layer].contains_key(index));
+        VisibleTiles {
+            center: self.tile_box.center(),
+            tiles: self.filter_new_tiles(self.tile_box.tile_indices()),
+            ancestors: ancestors.into_boxed_slice(),
+        }
     }
 
     /// Returns the list of tiles speculated based on the given mouse direction,
-    /// filtering out those that are already contained in the LRU cache.
+    /// filtering out those that are already contained in the LRU caches.
     fn get_speculate_tiles(
         &self,
         x_dir: Ordering,
@@ -255,24 +476,46 @@ impl<Image> TileState<Image> {
         Self::warn_on_tile_error(self.tiles_tx.close());
     }
 
+    /// Sends a request to the background thread to fetch the given visible
+    /// and speculated tiles, forgetting the unavailable tiles that are
+    /// requested again.
+    fn request_visible_tiles(&mut self, visible: VisibleTiles, speculative: Box<[TileIndex]>) {
+        self.forget_unavailable(&visible);
+        self.request_tiles(Some(visible), speculative);
+    }
+
     /// Sends a request to the background thread to fetch the given list of
     /// tiles.
-    fn request_tiles(&self, tiles: Option<Box<[TileIndex]>>, speculative: Box<[TileIndex]>) {
-        if let Some(ref tiles) = tiles {
-            for tile in tiles.iter() {
+    fn request_tiles(&self, visible: Option<VisibleTiles>, speculative: Box<[TileIndex]>) {
+        if let Some(ref visible) = visible {
+            for tile in visible.tiles.iter() {
                 debug!("[{i}] Request tile {tile:?}", i = self.iteration.get());
             }
+            for tile in visible.ancestors.iter() {
+                debug!(
+                    "[{i}] Request ancestor tile {tile:?}",
+                    i = self.iteration.get()
+                );
+            }
         }
         for tile in speculative.iter() {
             debug!("[{i}] Speculate tile {tile:?}", i = self.iteration.get());
         }
-        Self::warn_on_tile_error(self.tiles_tx.request_tiles(tiles, speculative));
+        Self::warn_on_tile_error(self.tiles_tx.request_tiles(
+            &self.active_layers(),
+            visible,
+            speculative,
+        ));
     }
 
-    /// Indicates to the background thread that the given tile was evicted.
-    fn evict_tile(&self, tile: TileIndex) {
-        debug!("[{i}] Evicted tile {tile:?}", i = self.iteration.get());
-        Self::warn_on_tile_error(self.tiles_tx.evict_tile(tile));
+    /// Indicates to the background thread that the given tile of the given map
+    /// layer was evicted.
+    fn evict_tile(&self, layer: usize, tile: TileIndex) {
+        debug!(
+            "[{i}] Evicted tile {tile:?} of layer #{layer}",
+            i = self.iteration.get()
+        );
+        Self::warn_on_tile_error(self.tiles_tx.evict_tile(layer, tile));
     }
 
     /// Prints a warning message based on the error if the given result is not
@@ -3,10 +3,11 @@
 use super::camera::Camera;
 use super::util::Color;
 use crate::tracks::polyline::Point;
-use crate::tracks::schema::ActivityType;
-use log::debug;
+use crate::tracks::schema::SportType;
+use crate::tracks::{TrackAttribute, TrackAttributes, TrackLayer};
+use log::{debug, info};
 use std::cell::Cell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 /// Polyline with an associated color.
@@ -15,8 +16,12 @@ struct ColoredPolyline {
     points: Vec<Point<f64>>,
     /// Color attributed to this polyline.
     color: Rc<Cell<Color>>,
-    /// Strava activity type associated with this polyline.
-    r#type: ActivityType,
+    /// Layer of the map on which this polyline is displayed.
+    layer: TrackLayer,
+    /// Strava sport type associated with this polyline.
+    sport_type: SportType,
+    /// Attributes recorded at each point of this polyline.
+    attributes: TrackAttributes,
 }
 
 /// A polyline scaled to the current zoom level.
@@ -27,8 +32,10 @@ struct ZoomedPolyline {
     bbox: Option<BBox>,
     /// Color attributed to this polyline.
     color: Rc<Cell<Color>>,
-    /// Color of the activity type associated with this polyline.
+    /// Color of the sport type associated with this polyline.
     type_color: Rc<Cell<Color>>,
+    /// Index in the original polyline of each point, if it has attributes.
+    indices: Vec<usize>,
 }
 
 impl ZoomedPolyline {
@@ -37,17 +44,22 @@ impl ZoomedPolyline {
     fn new(
         poly: &ColoredPolyline,
         zoom: f64,
-        type_colors: &mut HashMap<ActivityType, Rc<Cell<Color>>>,
+        type_colors: &mut HashMap<SportType, Rc<Cell<Color>>>,
     ) -> Self {
-        let mut points: Vec<Point<i32>> = poly
-            .points
-            .iter()
-            .map(|p| Point {
+        let mut points: Vec<Point<i32>> = Vec::new();
+        let mut indices = Vec::new();
+        for (i, p) in poly.points.iter().enumerate() {
+            let point = Point {
                 x: (p.x * zoom) as i32,
                 y: (p.y * zoom) as i32,
-            })
-            .collect();
-        points.dedup();
+            };
+            if points.last() != Some(&point) {
+                points.push(point);
+                if !poly.attributes.is_empty() {
+                    indices.push(i);
+                }
+            }
+        }
 
         let mut bbox = None;
         for &p in &points {
@@ -58,7 +70,7 @@ impl ZoomedPolyline {
         }
 
         let type_color = type_colors
-            .entry(poly.r#type)
+            .entry(poly.sport_type.clone())
             .or_insert_with(|| Rc::new(Cell::new(Color::new_random())));
 
         ZoomedPolyline {
@@ -66,6 +78,7 @@ impl ZoomedPolyline {
             bbox,
             color: poly.color.clone(),
             type_color: type_color.clone(),
+            indices,
         }
     }
 
@@ -85,13 +98,58 @@ pub struct VisiblePolyline<'a> {
     points: &'a [Point<i32>],
     /// Color attributed to this polyline.
     pub color: Color,
+    /// Colors of the segments based on an attribute, if any.
+    attribute_colors: Option<AttributeColors<'a>>,
     /// Window size.
     iwsize: Point<i32>,
     /// Camera offset.
     ioffset: Point<i32>,
 }
 
+/// Colors of the segments of a polyline based on the values of an attribute.
+struct AttributeColors<'a> {
+    /// Values of the attribute at each point of the original polyline, if the
+    /// polyline has this attribute.
+    values: Option<&'a [Option<f64>]>,
+    /// Index in the original polyline of each point of the zoomed polyline.
+    indices: &'a [usize],
+    /// Range of the attribute over all the polylines.
+    range: (f64, f64),
+}
+
 impl VisiblePolyline<'_> {
+    /// Color of the segments without a value of the selected attribute.
+    const MISSING_ATTRIBUTE_COLOR: Color = Color([0.5, 0.5, 0.5, 1.0]);
+
+    /// Checks whether the segments of this polyline have their own colors,
+    /// based on an attribute.
+    pub fn has_segment_colors(&self) -> bool {
+        self.attribute_colors.is_some()
+    }
+
+    /// Returns the color of the segment starting at the given point index.
+    pub fn segment_color(&self, index: usize) -> Color {
+        let Some(colors) = &self.attribute_colors else {
+            return self.color;
+        };
+        let value = colors
+            .values
+            .zip(colors.indices.get(index))
+            .and_then(|(values, &i)| values.get(i).copied().flatten());
+        match value {
+            Some(value) => {
+                let (min, max) = colors.range;
+                let t = if max > min {
+                    (value - min) / (max - min)
+                } else {
+                    0.5
+                };
+                Color::new_gradient(t as f32)
+            }
+            None => Self::MISSING_ATTRIBUTE_COLOR,
+        }
+    }
+
     /// Returns the first point of the polyline, if it is not empty.
     pub fn first_point(&self) -> Option<Point<i32>> {
         self.points.first().map(|p| self.convert(p))
@@ -178,16 +236,25 @@ impl BBox {
 pub struct TrackState {
     /// Colored polylines loaded on the UI thread.
     polylines: Vec<ColoredPolyline>,
-    /// Mapping from activity types to colors.
-    type_colors: HashMap<ActivityType, Rc<Cell<Color>>>,
+    /// Mapping from sport types to colors.
+    type_colors: HashMap<SportType, Rc<Cell<Color>>>,
     /// Polylines scaled to the current zoom level.
     zoomed_polylines: Vec<ZoomedPolyline>,
-    /// Whether to choose the color based on the activity type.
+    /// Whether to choose the color based on the sport type.
     color_by_type: bool,
+    /// Range of the values of each attribute over all the polylines.
+    attribute_ranges: HashMap<TrackAttribute, (f64, f64)>,
+    /// Attribute to choose the color of each segment by, if any.
+    color_attribute: Option<TrackAttribute>,
 }
 
 #[allow(clippy::new_without_default)]
 impl TrackState {
+    /// Color of the planned routes.
+    const ROUTE_COLOR: Color = Color([0.1, 0.3, 0.9, 0.8]);
+    /// Color of the segments.
+    const SEGMENT_COLOR: Color = Color([1.0, 0.4, 0.0, 1.0]);
+
     /// Creates a new empty state.
     pub fn new() -> Self {
         Self {
@@ -195,6 +262,8 @@ impl TrackState {
             type_colors: HashMap::new(),
             zoomed_polylines: Vec::new(),
             color_by_type: false,
+            attribute_ranges: HashMap::new(),
+            color_attribute: None,
         }
     }
 
@@ -204,12 +273,62 @@ impl TrackState {
     }
 
     /// Toggles whether tracks should be displayed based on their own color or
-    /// activity type.
+    /// sport type.
     pub fn toggle_color_by_type(&mut self) {
         self.color_by_type = !self.color_by_type;
     }
 
-    /// Re-generate random colors of either the tracks or activity types, based
+    /// Cycles the attribute to color the tracks by, among the attributes
+    /// recorded in the loaded tracks, and then back to the track or sport type
+    /// colors.
+    pub fn cycle_color_attribute(&mut self) {
+        let mut available = TrackAttribute::ALL
+            .into_iter()
+            .filter(|a| self.attribute_ranges.contains_key(a));
+        self.color_attribute = match self.color_attribute {
+            None => available.next(),
+            Some(current) => available.skip_while(|&a| a != current).nth(1),
+        };
+        match self.color_attribute {
+            Some(attribute) => info!("Coloring tracks by {}", attribute.name()),
+            None => info!("Coloring tracks by track or sport type"),
+        }
+    }
+
+    /// Returns the legend of the sport type colors of the activities, sorted
+    /// by name, when the tracks are colored by sport type, followed by the
+    /// colors of the other layers.
+    pub fn type_legend(&self) -> Vec<(String, Color)> {
+        if !self.color_by_type || self.color_attribute.is_some() {
+            return Vec::new();
+        }
+        let sport_types: HashSet<&SportType> = self
+            .polylines
+            .iter()
+            .filter(|poly| poly.layer == TrackLayer::Activity)
+            .map(|poly| &poly.sport_type)
+            .collect();
+        let mut legend: Vec<(String, Color)> = sport_types
+            .into_iter()
+            .filter_map(|sport_type| {
+                let color = self.type_colors.get(sport_type)?;
+                Some((sport_type.to_string(), color.get()))
+            })
+            .collect();
+        legend.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (layer, name, color) in [
+            (TrackLayer::Route, "Routes", Self::ROUTE_COLOR),
+            (TrackLayer::Segment, "Segments", Self::SEGMENT_COLOR),
+        ] {
+            if self.polylines.iter().any(|poly| poly.layer == layer) {
+                legend.push((name.to_owned(), color));
+            }
+        }
+        legend
+    }
+
+    /// Re-generate random colors of either the tracks or sport types, based
     /// on the `color_by_type` state.
     pub fn randomize_colors(&mut self) {
         if self.color_by_type {
@@ -235,14 +354,30 @@ impl TrackState {
     /// Processes the given activity sent by the background thread.
     pub fn process_activity(
         &mut self,
-        r#type: ActivityType,
+        layer: TrackLayer,
+        sport_type: SportType,
         points: Vec<Point<f64>>,
+        attributes: TrackAttributes,
         camera: &Camera,
     ) {
+        for (&attribute, values) in &attributes {
+            for &value in values.iter().flatten() {
+                self.attribute_ranges
+                    .entry(attribute)
+                    .and_modify(|(min, max)| {
+                        *min = min.min(value);
+                        *max = max.max(value);
+                    })
+                    .or_insert((value, value));
+            }
+        }
+
         let poly = ColoredPolyline {
             points,
-            r#type,
+            layer,
+            sport_type,
             color: Rc::new(Cell::new(Color::new_random())),
+            attributes,
         };
         self.zoomed_polylines.push(ZoomedPolyline::new(
             &poly,
@@ -254,21 +389,42 @@ impl TrackState {
 
     /// Returns an iterator over the visible polylines, based on the given
     /// camera position.
+    ///
+    /// The polylines are ordered by layer, so that routes and segments are
+    /// drawn on top of the activities.
     pub fn visible_polylines(&self, camera: &Camera) -> impl Iterator<Item = VisiblePolyline<'_>> {
         let iwsize = camera.iwsize();
         let ioffset = camera.ioffset();
-        self.zoomed_polylines
-            .iter()
-            .filter(move |poly| poly.visible(ioffset, iwsize))
-            .map(move |poly| {
-                let color = if self.color_by_type {
-                    poly.type_color.get()
-                } else {
-                    poly.color.get()
+        TrackLayer::ALL
+            .into_iter()
+            .flat_map(move |layer| {
+                self.zoomed_polylines
+                    .iter()
+                    .zip(&self.polylines)
+                    .filter(move |(_, original)| original.layer == layer)
+            })
+            .filter(move |(poly, _)| poly.visible(ioffset, iwsize))
+            .map(move |(poly, original)| {
+                let color = match original.layer {
+                    TrackLayer::Activity if self.color_by_type => poly.type_color.get(),
+                    TrackLayer::Activity => poly.color.get(),
+                    TrackLayer::Route => Self::ROUTE_COLOR,
+                    TrackLayer::Segment => Self::SEGMENT_COLOR,
                 };
+                let attribute_colors = self.color_attribute.and_then(|attribute| {
+                    if original.layer != TrackLayer::Activity {
+                        return None;
+                    }
+                    Some(AttributeColors {
+                        values: original.attributes.get(&attribute).map(Vec::as_slice),
+                        indices: &poly.indices,
+                        range: *self.attribute_ranges.get(&attribute)?,
+                    })
+                });
                 VisiblePolyline {
                     points: poly.points.as_slice(),
                     color,
+                    attribute_colors,
                     iwsize,
                     ioffset,
                 }
@@ -1,7 +1,10 @@
 //! Module containing various UI utilities.
 
+use crate::config::TileFormat;
+use crate::map::tiles::TileIndex;
 use crate::ui::tracks::TrackStats;
-use image::{ImageError, ImageFormat, RgbaImage};
+use image::imageops::{self, FilterType};
+use image::{ImageError, RgbaImage};
 use log::warn;
 use rand::distr::Open01;
 use rand::{rng, Rng};
@@ -19,6 +22,22 @@ impl Color {
         let b = rng.sample(Open01);
         Self([r, g, b, 1.0])
     }
+
+    /// Creates a color on a gradient from blue to red (via cyan, green and
+    /// yellow), for the given position between 0 and 1.
+    pub fn new_gradient(t: f32) -> Self {
+        // Hue from blue (240 degrees) to red (0 degrees), in sectors of 60
+        // degrees.
+        let h = (1.0 - t.clamp(0.0, 1.0)) * 4.0;
+        let x = 1.0 - (h % 2.0 - 1.0).abs();
+        let [r, g, b] = match h as u32 {
+            0 => [1.0, x, 0.0],
+            1 => [x, 1.0, 0.0],
+            2 => [0.0, 1.0, x],
+            _ => [0.0, x, 1.0],
+        };
+        Self([r, g, b, 1.0])
+    }
 }
 
 /// A loaded map tile.
@@ -27,13 +46,54 @@ pub struct Tile<Image> {
     pub image: Image,
 }
 
-/// Decode an image in RGBA format from PNG data.
-pub fn decode_png(bytes: &[u8]) -> Result<RgbaImage, ImageError> {
+/// Decode an image in RGBA format from data in the given tile format.
+pub fn decode_image(bytes: &[u8], format: TileFormat) -> Result<RgbaImage, ImageError> {
     let dynamic_image =
-        image::ImageReader::with_format(std::io::Cursor::new(bytes), ImageFormat::Png).decode()?;
+        image::ImageReader::with_format(std::io::Cursor::new(bytes), format.image_format())
+            .decode()?;
     Ok(dynamic_image.to_rgba8())
 }
 
+/// Returns the part of the given image of an ancestor tile covering the given
+/// tile, scaled up to the size of the ancestor's image.
+pub fn upscale_tile(image: &RgbaImage, ancestor: &TileIndex, index: &TileIndex) -> RgbaImage {
+    let shift = index.z - ancestor.z;
+    let (width, height) = image.dimensions();
+    // Position of the tile within its ancestor, in pixels.
+    let x = ((index.x - (ancestor.x << shift)) as u64 * width as u64) >> shift;
+    let y = ((index.y - (ancestor.y << shift)) as u64 * height as u64) >> shift;
+    let cropped = imageops::crop_imm(
+        image,
+        x as u32,
+        y as u32,
+        (width >> shift).max(1),
+        (height >> shift).max(1),
+    )
+    .to_image();
+    imageops::resize(&cropped, width, height, FilterType::Triangle)
+}
+
+/// Returns the diagonal segments of a hatch pattern filling the given (left,
+/// top, width, height) rectangle, with the given spacing between lines.
+///
+/// This pattern is drawn as a placeholder for tiles that failed to load.
+pub fn hatch_pattern(rect: [f64; 4], spacing: f64) -> Vec<[f64; 4]> {
+    let [left, top, width, height] = rect;
+    let count = ((width + height) / spacing).ceil() as usize;
+    (1..count)
+        .map(|i| {
+            // Segment of the line x + y = d within the rectangle.
+            let d = i as f64 * spacing;
+            [
+                left + d.min(width),
+                top + (d - width).max(0.0),
+                left + (d - height).max(0.0),
+                top + d.min(height),
+            ]
+        })
+        .collect()
+}
+
 /// Prints a warning message based on the error if the given result is not OK.
 pub fn warn_on_error<E: std::fmt::Debug>(x: Result<(), E>, msg: &str) {
     match x {
@@ -53,3 +113,42 @@ pub struct RenderStats {
     /// Number of segments drawn.
     pub drawn_segment_count: usize,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn new_gradient_stops() {
+        assert_eq!(Color::new_gradient(0.0).0, [0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(Color::new_gradient(0.25).0, [0.0, 1.0, 1.0, 1.0]);
+        assert_eq!(Color::new_gradient(0.5).0, [0.0, 1.0, 0.0, 1.0]);
+        assert_eq!(Color::new_gradient(0.75).0, [1.0, 1.0, 0.0, 1.0]);
+        assert_eq!(Color::new_gradient(1.0).0, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(Color::new_gradient(2.0).0, [1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn upscale_tile_quadrant() {
+        // Each quadrant of the ancestor has a different color.
+        let image =
+            RgbaImage::from_fn(8, 8, |x, y| image::Rgba([x as u8 / 4, y as u8 / 4, 0, 255]));
+        let ancestor = TileIndex { z: 3, x: 2, y: 5 };
+        let index = TileIndex { z: 4, x: 5, y: 10 };
+        let upscaled = upscale_tile(&image, &ancestor, &index);
+        assert_eq!(upscaled.dimensions(), (8, 8));
+        assert!(upscaled.pixels().all(|p| p.0 == [1, 0, 0, 255]));
+    }
+
+    #[test]
+    fn hatch_pattern_segments() {
+        assert_eq!(
+            hatch_pattern([10.0, 20.0, 4.0, 4.0], 2.0),
+            [
+                [12.0, 20.0, 10.0, 22.0],
+                [14.0, 20.0, 10.0, 24.0],
+                [14.0, 22.0, 12.0, 24.0],
+            ]
+        );
+    }
+}
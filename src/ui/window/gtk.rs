@@ -4,9 +4,10 @@ use crate::config::FONT_PATH;
 use crate::map::tile_channel::TileRequestSender;
 use crate::tracks::polyline::Point;
 use crate::ui::camera::Camera;
-use crate::ui::tiles::TileState;
+use crate::ui::status::StatusState;
+use crate::ui::tiles::{Placeholder, TileParams, TileState};
 use crate::ui::tracks::TrackState;
-use crate::ui::util::{warn_on_error, RenderStats};
+use crate::ui::util::{hatch_pattern, warn_on_error, RenderStats};
 use crate::ui::UiMessage;
 use anyhow::Context as AnyhowContext;
 use futures::channel::oneshot;
@@ -43,6 +44,7 @@ pub struct Window {
     camera: Camera,
     tile_state: TileState<(Pixbuf, u32)>,
     track_state: TrackState,
+    status_state: StatusState,
     thick: Thickness,
     click: bool,
     last_pos: Option<Point<f64>>,
@@ -64,6 +66,8 @@ impl Window {
     const THICKNESSES: [f64; 5] = [1.0, 2.0, 4.0, 6.0, 8.0];
     /// Font size.
     const FONT_SIZE: f64 = 20.0;
+    /// Spacing between the lines of the placeholder pattern of failed tiles.
+    const HATCH_SPACING: f64 = 16.0;
     /// How often to fetch messages from the background thread.
     const REFRESH_RATE: Duration = Duration::from_millis(50);
 
@@ -73,9 +77,7 @@ impl Window {
         cancel_tx: oneshot::Sender<()>,
         tiles_tx: TileRequestSender,
         _lazy_ui_refresh: bool,
-        speculative_tile_load: bool,
-        max_pixels_per_tile: usize,
-        max_tile_level: i32,
+        tile_params: TileParams,
     ) -> anyhow::Result<()> {
         let freetype =
             freetype::Library::init().context("Failed to initialize FreeType library")?;
@@ -86,13 +88,7 @@ impl Window {
 
         let app = Application::builder().application_id(Self::APP_ID).build();
 
-        let window = Rc::new(RefCell::new(Window::new(
-            tiles_tx,
-            speculative_tile_load,
-            max_pixels_per_tile,
-            max_tile_level,
-            font_face,
-        )));
+        let window = Rc::new(RefCell::new(Window::new(tiles_tx, tile_params, font_face)));
         window.borrow_mut().tile_state.start();
 
         let window_init = window.clone();
@@ -118,24 +114,13 @@ impl Window {
     }
 
     /// Creates a new window state.
-    fn new(
-        tiles_tx: TileRequestSender,
-        speculative_tile_load: bool,
-        max_pixels_per_tile: usize,
-        max_tile_level: i32,
-        font_face: FontFace,
-    ) -> Self {
+    fn new(tiles_tx: TileRequestSender, tile_params: TileParams, font_face: FontFace) -> Self {
         let iteration = Rc::new(Cell::new(0));
         Self {
             camera: Camera::new(Self::INITIAL_WIDTH, Self::INITIAL_HEIGHT),
-            tile_state: TileState::new(
-                tiles_tx,
-                speculative_tile_load,
-                max_pixels_per_tile,
-                max_tile_level,
-                iteration.clone(),
-            ),
+            tile_state: TileState::new(tiles_tx, tile_params, iteration.clone()),
             track_state: TrackState::new(),
+            status_state: StatusState::new(),
             thick: Thickness(0),
             click: false,
             last_pos: None,
@@ -153,19 +138,31 @@ impl Window {
     /// Processes the given message from the background thread.
     fn process_update(&mut self, msg: UiMessage) {
         let need_refresh = match msg {
-            UiMessage::Activity { id, r#type, points } => {
-                debug!("[{i}] Received activity #{id}", i = self.iteration.get());
-                self.track_state
-                    .process_activity(r#type, points, &self.camera);
+            UiMessage::Activity {
+                id,
+                layer,
+                sport_type,
+                points,
+                attributes,
+            } => {
+                debug!("[{i}] Received {layer:?} #{id}", i = self.iteration.get());
+                self.track_state.process_activity(
+                    layer,
+                    sport_type,
+                    points,
+                    attributes,
+                    &self.camera,
+                );
                 true
             }
             UiMessage::Tile {
+                layer,
                 index,
-                png_image,
+                raw_image,
                 rgba_image,
             } => {
                 self.tile_state
-                    .process_tile(index, &png_image, rgba_image, |rgba_image| {
+                    .process_tile(layer, index, &raw_image, rgba_image, |rgba_image| {
                         let width = rgba_image.width();
                         let height = rgba_image.height();
                         let stride = width * 4;
@@ -182,6 +179,34 @@ impl Window {
                         Some((pixbuf, width))
                     })
             }
+            UiMessage::TileError {
+                layer,
+                index,
+                error,
+            } => {
+                self.status_state.process_error(error);
+                self.tile_state.process_tile_error(layer, index)
+            }
+            UiMessage::TileMissing { layer, index } => {
+                self.tile_state.process_tile_missing(layer, index)
+            }
+            UiMessage::TileProgress { pending } => {
+                self.status_state.process_tile_progress(pending);
+                true
+            }
+            UiMessage::TrackProgress {
+                loaded,
+                failed,
+                total,
+            } => {
+                self.status_state
+                    .process_track_progress(loaded, failed, total);
+                true
+            }
+            UiMessage::TrackError { error } => {
+                self.status_state.process_error(error);
+                true
+            }
         };
 
         if need_refresh {
@@ -297,6 +322,14 @@ impl Window {
                 self.track_state.randomize_colors();
                 true
             }
+            Key::a => {
+                self.track_state.cycle_color_attribute();
+                true
+            }
+            Key::m => {
+                self.tile_state.next_base_layer();
+                true
+            }
             _ => false,
         };
         if accepted {
@@ -388,13 +421,13 @@ impl Window {
         let zoom = self.camera.zoom();
 
         let tiles_to_draw = self.tile_state.tiles_to_draw();
-        for (i, (tile_index, tile)) in tiles_to_draw.iter().enumerate() {
+        for (i, tile_to_draw) in tiles_to_draw.iter().enumerate() {
             trace!("Drawing tile {i}/{}", tiles_to_draw.len());
 
-            let pixbuf: &Pixbuf = &tile.image.0;
-            let pixbuf_width = tile.image.1 - 1;
+            let pixbuf: &Pixbuf = &tile_to_draw.tile.image.0;
+            let pixbuf_width = tile_to_draw.tile.image.1 - 1;
 
-            let rect = tile_index.rect();
+            let rect = tile_to_draw.index.rect();
             let target_width: f64 = zoom * rect[2];
 
             let scale_factor: f64 = target_width / (pixbuf_width as f64);
@@ -407,11 +440,38 @@ impl Window {
             context.scale(scale_factor, scale_factor);
 
             context.set_source_pixbuf(pixbuf, 0.0, 0.0);
-            context.paint().context("Failed to draw tile")?;
+            context
+                .paint_with_alpha(tile_to_draw.opacity.into())
+                .context("Failed to draw tile")?;
             context.identity_matrix();
         }
         debug!("Drawn tiles");
 
+        let placeholders = self.tile_state.placeholders_to_draw();
+        context.set_line_width(1.0);
+        for (index, placeholder) in &placeholders {
+            trace!("Drawing {placeholder:?} placeholder of tile {index:?}");
+            let rect = index.rect();
+            let rect = [
+                ioffset.x as f64 + zoom * rect[0],
+                ioffset.y as f64 + zoom * rect[1],
+                zoom * rect[2],
+                zoom * rect[3],
+            ];
+            let ([r, g, b, a], [hr, hg, hb, ha]) = placeholder.colors();
+            context.set_source_rgba(r.into(), g.into(), b.into(), a.into());
+            context.rectangle(rect[0], rect[1], rect[2], rect[3]);
+            context.fill().context("Failed to draw placeholder")?;
+
+            context.set_source_rgba(hr.into(), hg.into(), hb.into(), ha.into());
+            for [x1, y1, x2, y2] in hatch_pattern(rect, Self::HATCH_SPACING) {
+                context.move_to(x1, y1);
+                context.line_to(x2, y2);
+            }
+            context.stroke().context("Failed to draw placeholder")?;
+        }
+        debug!("Drawn {} placeholders", placeholders.len());
+
         context.set_line_join(LineJoin::Bevel);
 
         let mut segment_count = 0;
@@ -424,6 +484,19 @@ impl Window {
             context.set_line_width(line_width);
 
             segment_count += poly.segments_count();
+            if poly.has_segment_colors() {
+                // Each segment has its own color, so it's stroked separately.
+                for (index, p1, p2) in poly.segments() {
+                    drawn_segment_count += 1;
+                    let color = poly.segment_color(index).0;
+                    context.set_source_rgb(color[0].into(), color[1].into(), color[2].into());
+                    context.move_to(p1.x as f64, p1.y as f64);
+                    context.line_to(p2.x as f64, p2.y as f64);
+                    context.stroke().context("Failed to draw polyline")?;
+                }
+                continue;
+            }
+
             let mut last_index = None;
             for (index, p1, p2) in poly.segments() {
                 drawn_segment_count += 1;
@@ -483,14 +556,34 @@ impl Window {
         Ok(())
     }
 
-    /// Renders the debugging statistics at the bottom of the UI.
+    /// Renders the status and debugging statistics at the bottom of the UI.
     fn render_text(&self, context: &Context, render_stats: RenderStats) -> anyhow::Result<()> {
+        let track_stats = &render_stats.track_stats;
+        let mut lines = self.status_state.status_lines(
+            self.tile_state.unavailable_count(Placeholder::Failed),
+            self.tile_state.unavailable_count(Placeholder::Missing),
+        );
+        lines.extend([
+            format!("Drawn {} tiles", render_stats.drawn_tiles_count),
+            format!(
+                "Deduped {} / {} / {} points",
+                track_stats.visible_points, track_stats.deduped_points, track_stats.total_points
+            ),
+            format!(
+                "Drawn {} / {} segments",
+                render_stats.drawn_segment_count, render_stats.segment_count
+            ),
+        ]);
+
+        let legend = self.track_state.type_legend();
+
+        let height = ((lines.len() + legend.len()) as f64 + 0.5) * Self::FONT_SIZE;
         context.set_source_rgba(1.0, 1.0, 1.0, 0.5);
         context.rectangle(
             0.0,
-            self.camera.height() - 3.5 * Self::FONT_SIZE,
+            self.camera.height() - height,
             self.camera.width(),
-            3.5 * Self::FONT_SIZE,
+            height,
         );
         context.fill().context("Failed to draw rectangle")?;
 
@@ -498,27 +591,23 @@ impl Window {
         context.set_font_size(Self::FONT_SIZE);
         context.set_source_rgb(0.0, 0.0, 0.0);
 
-        context.move_to(0.0, self.camera.height() - 2.5 * Self::FONT_SIZE);
-        context
-            .show_text(&format!("Drawn {} tiles", render_stats.drawn_tiles_count))
-            .context("Failed to draw text")?;
-
-        let track_stats = &render_stats.track_stats;
-        context.move_to(0.0, self.camera.height() - 1.5 * Self::FONT_SIZE);
-        context
-            .show_text(&format!(
-                "Deduped {} / {} / {} points",
-                track_stats.visible_points, track_stats.deduped_points, track_stats.total_points
-            ))
-            .context("Failed to draw text")?;
+        for (i, line) in lines.iter().enumerate() {
+            context.move_to(
+                0.0,
+                self.camera.height() - height + (i as f64 + 1.0) * Self::FONT_SIZE,
+            );
+            context.show_text(line).context("Failed to draw text")?;
+        }
 
-        context.move_to(0.0, self.camera.height() - 0.5 * Self::FONT_SIZE);
-        context
-            .show_text(&format!(
-                "Drawn {} / {} segments",
-                render_stats.drawn_segment_count, render_stats.segment_count
-            ))
-            .context("Failed to draw text")?;
+        for (i, (name, color)) in legend.iter().enumerate() {
+            let [r, g, b, a] = color.0;
+            context.set_source_rgba(r as f64, g as f64, b as f64, a as f64);
+            context.move_to(
+                0.0,
+                self.camera.height() - height + ((lines.len() + i) as f64 + 1.0) * Self::FONT_SIZE,
+            );
+            context.show_text(name).context("Failed to draw text")?;
+        }
 
         Ok(())
     }
@@ -3,9 +3,10 @@
 use crate::config::FONT_PATH;
 use crate::map::tile_channel::TileRequestSender;
 use crate::ui::camera::Camera;
-use crate::ui::tiles::TileState;
+use crate::ui::status::StatusState;
+use crate::ui::tiles::{Placeholder, TileParams, TileState};
 use crate::ui::tracks::TrackState;
-use crate::ui::util::{warn_on_error, RenderStats};
+use crate::ui::util::{hatch_pattern, warn_on_error, Color, RenderStats};
 use crate::ui::UiMessage;
 use anyhow::bail;
 use anyhow::Context as AnyhowContext;
@@ -44,6 +45,7 @@ pub struct Window {
     camera: Camera,
     tile_state: TileState<(Image, G2dTexture)>,
     track_state: TrackState,
+    status_state: StatusState,
     lazy_ui_refresh: bool,
     thick: Thickness,
     click: bool,
@@ -62,6 +64,8 @@ impl Window {
     const THICKNESSES: [f64; 5] = [1.0, 2.0, 4.0, 6.0, 8.0];
     /// Font size.
     const FONT_SIZE: FontSize = 12;
+    /// Spacing between the lines of the placeholder pattern of failed tiles.
+    const HATCH_SPACING: f64 = 16.0;
 
     /// Runs the UI loop, in the UI thread.
     pub fn ui_loop(
@@ -69,9 +73,7 @@ impl Window {
         cancel_tx: oneshot::Sender<()>,
         tiles_tx: TileRequestSender,
         lazy_ui_refresh: bool,
-        speculative_tile_load: bool,
-        max_pixels_per_tile: usize,
-        max_tile_level: i32,
+        tile_params: TileParams,
     ) -> anyhow::Result<()> {
         let mut piston_window =
             match WindowSettings::new("Ridemap", (Self::INITIAL_WIDTH, Self::INITIAL_HEIGHT))
@@ -82,15 +84,7 @@ impl Window {
                 Err(e) => bail!("Failed to build PistonWindow: {e:?}"),
             };
 
-        let window = Window::new(
-            ui_rx,
-            cancel_tx,
-            tiles_tx,
-            lazy_ui_refresh,
-            speculative_tile_load,
-            max_pixels_per_tile,
-            max_tile_level,
-        );
+        let window = Window::new(ui_rx, cancel_tx, tiles_tx, lazy_ui_refresh, tile_params);
         window.do_loop(&mut piston_window)
     }
 
@@ -144,23 +138,16 @@ impl Window {
         cancel_tx: oneshot::Sender<()>,
         tiles_tx: TileRequestSender,
         lazy_ui_refresh: bool,
-        speculative_tile_load: bool,
-        max_pixels_per_tile: usize,
-        max_tile_level: i32,
+        tile_params: TileParams,
     ) -> Self {
         let iteration = Rc::new(Cell::new(0));
         Self {
             ui_rx,
             cancel_tx,
             camera: Camera::new(Self::INITIAL_WIDTH, Self::INITIAL_HEIGHT),
-            tile_state: TileState::new(
-                tiles_tx,
-                speculative_tile_load,
-                max_pixels_per_tile,
-                max_tile_level,
-                iteration.clone(),
-            ),
+            tile_state: TileState::new(tiles_tx, tile_params, iteration.clone()),
             track_state: TrackState::new(),
+            status_state: StatusState::new(),
             lazy_ui_refresh,
             thick: Thickness(0),
             click: false,
@@ -223,6 +210,14 @@ impl Window {
                     self.track_state.randomize_colors();
                     true
                 }
+                Key::A => {
+                    self.track_state.cycle_color_attribute();
+                    true
+                }
+                Key::M => {
+                    self.tile_state.next_base_layer();
+                    true
+                }
                 _ => false,
             },
             Input::Move(Motion::MouseScroll(scroll)) => {
@@ -263,33 +258,75 @@ impl Window {
 
         for msg in self.ui_rx.try_iter() {
             match msg {
-                UiMessage::Activity { id, r#type, points } => {
-                    debug!("[{i}] Received activity #{id}", i = self.iteration.get());
-                    self.track_state
-                        .process_activity(r#type, points, &self.camera);
+                UiMessage::Activity {
+                    id,
+                    layer,
+                    sport_type,
+                    points,
+                    attributes,
+                } => {
+                    debug!("[{i}] Received {layer:?} #{id}", i = self.iteration.get());
+                    self.track_state.process_activity(
+                        layer,
+                        sport_type,
+                        points,
+                        attributes,
+                        &self.camera,
+                    );
 
                     self.need_refresh = true;
                 }
                 UiMessage::Tile {
+                    layer,
                     index,
-                    png_image,
+                    raw_image,
                     rgba_image,
                 } => {
-                    self.need_refresh |=
-                        self.tile_state
-                            .process_tile(index, &png_image, rgba_image, |rgba_image| {
-                                match Texture::from_image(
-                                    &mut piston_window.create_texture_context(),
-                                    &rgba_image,
-                                    &TextureSettings::new(),
-                                ) {
-                                    Ok(texture) => Some((Image::new().rect(index.rect()), texture)),
-                                    Err(e) => {
-                                        error!("Error creating texture: {e}");
-                                        None
-                                    }
-                                }
-                            });
+                    self.need_refresh |= self.tile_state.process_tile(
+                        layer,
+                        index,
+                        &raw_image,
+                        rgba_image,
+                        |rgba_image| match Texture::from_image(
+                            &mut piston_window.create_texture_context(),
+                            &rgba_image,
+                            &TextureSettings::new(),
+                        ) {
+                            Ok(texture) => Some((Image::new().rect(index.rect()), texture)),
+                            Err(e) => {
+                                error!("Error creating texture: {e}");
+                                None
+                            }
+                        },
+                    );
+                }
+                UiMessage::TileError {
+                    layer,
+                    index,
+                    error,
+                } => {
+                    self.need_refresh |= self.tile_state.process_tile_error(layer, index);
+                    self.status_state.process_error(error);
+                }
+                UiMessage::TileMissing { layer, index } => {
+                    self.need_refresh |= self.tile_state.process_tile_missing(layer, index);
+                }
+                UiMessage::TileProgress { pending } => {
+                    self.status_state.process_tile_progress(pending);
+                    self.need_refresh = true;
+                }
+                UiMessage::TrackProgress {
+                    loaded,
+                    failed,
+                    total,
+                } => {
+                    self.status_state
+                        .process_track_progress(loaded, failed, total);
+                    self.need_refresh = true;
+                }
+                UiMessage::TrackError { error } => {
+                    self.status_state.process_error(error);
+                    self.need_refresh = true;
                 }
             }
         }
@@ -339,32 +376,67 @@ impl Window {
             .scale(zoom, zoom);
 
         let tiles_to_draw = self.tile_state.tiles_to_draw();
-        for (i, (_, tile)) in tiles_to_draw.iter().enumerate() {
-            trace!("Drawing tile {i}/{}", tiles_to_draw.len());
-            let image: &Image = &tile.image.0;
-            let texture: &G2dTexture = &tile.image.1;
-            image.draw(texture, &context.draw_state, tile_transform, graphics);
+        for (i, tile_to_draw) in tiles_to_draw.iter().enumerate() {
+            trace!(
+                "Drawing tile {i}/{} = {:?}",
+                tiles_to_draw.len(),
+                tile_to_draw.index
+            );
+            let image: Image = tile_to_draw.tile.image.0;
+            let texture: &G2dTexture = &tile_to_draw.tile.image.1;
+            image.color([1.0, 1.0, 1.0, tile_to_draw.opacity]).draw(
+                texture,
+                &context.draw_state,
+                tile_transform,
+                graphics,
+            );
         }
         debug!("Drawn tiles");
 
+        let placeholders = self.tile_state.placeholders_to_draw();
+        for (index, placeholder) in &placeholders {
+            trace!("Drawing {placeholder:?} placeholder of tile {index:?}");
+            let rect = index.rect();
+            let rect = [
+                ioffset.x as f64 + zoom * rect[0],
+                ioffset.y as f64 + zoom * rect[1],
+                zoom * rect[2],
+                zoom * rect[3],
+            ];
+            let (fill_color, hatch_color) = placeholder.colors();
+            graphics::rectangle(fill_color, rect, context.transform, graphics);
+            let line = Line::new(hatch_color, 1.0);
+            for segment in hatch_pattern(rect, Self::HATCH_SPACING) {
+                line.draw(segment, &context.draw_state, context.transform, graphics);
+            }
+        }
+        debug!("Drawn {} placeholders", placeholders.len());
+
         let mut segment_count = 0;
         let mut drawn_segment_count = 0;
         for (i, poly) in self.track_state.visible_polylines(&self.camera).enumerate() {
             trace!("Drawing polyline {i}");
-            let color = poly.color.0;
             let line_width = Self::THICKNESSES[self.thick.0];
-            let line = if line_width == 1.0 {
-                Line::new(color, 1.0)
-            } else {
-                Line::new(color, line_width)
-                    .width(line_width)
-                    .shape(Shape::Bevel)
+            let line = |color: Color| {
+                if line_width == 1.0 {
+                    Line::new(color.0, 1.0)
+                } else {
+                    Line::new(color.0, line_width)
+                        .width(line_width)
+                        .shape(Shape::Bevel)
+                }
             };
+            let uniform_line = line(poly.color);
 
             segment_count += poly.segments_count();
-            for (_index, p1, p2) in poly.segments() {
+            for (index, p1, p2) in poly.segments() {
                 drawn_segment_count += 1;
-                line.draw(
+                let segment_line = if poly.has_segment_colors() {
+                    line(poly.segment_color(index))
+                } else {
+                    uniform_line
+                };
+                segment_line.draw(
                     [p1.x as f64, p1.y as f64, p2.x as f64, p2.y as f64],
                     &context.draw_state,
                     context.transform,
@@ -407,7 +479,7 @@ impl Window {
         }
     }
 
-    /// Renders the debugging statistics at the bottom of the UI.
+    /// Renders the status and debugging statistics at the bottom of the UI.
     fn render_text<C, G>(
         &self,
         context: Context,
@@ -422,66 +494,61 @@ impl Window {
     {
         let font_size = Self::FONT_SIZE as f64;
 
+        let track_stats = &render_stats.track_stats;
+        let mut lines = self.status_state.status_lines(
+            self.tile_state.unavailable_count(Placeholder::Failed),
+            self.tile_state.unavailable_count(Placeholder::Missing),
+        );
+        lines.extend([
+            format!("Drawn {} tiles", render_stats.drawn_tiles_count),
+            format!(
+                "Deduped {} / {} / {} points",
+                track_stats.visible_points, track_stats.deduped_points, track_stats.total_points
+            ),
+            format!(
+                "Drawn {} / {} segments",
+                render_stats.drawn_segment_count, render_stats.segment_count
+            ),
+        ]);
+
+        let legend = self.track_state.type_legend();
+
+        let height = ((lines.len() + legend.len()) as f64 + 0.5) * font_size;
         graphics::rectangle(
             [1.0, 1.0, 1.0, 0.5],
             [
                 0.0,
-                self.camera.height() - 3.5 * font_size,
+                self.camera.height() - height,
                 self.camera.width(),
-                3.5 * font_size,
+                height,
             ],
             context.transform,
             graphics,
         );
 
-        // Render at twice the font size but with 0.5 zoom for Retina displays. See https://github.com/PistonDevelopers/piston/issues/1240#issuecomment-569318143.
-        if let Err(e) = graphics::text(
-            [0.0, 0.0, 0.0, 1.0],
-            Self::FONT_SIZE * 2,
-            &format!("Drawn {} tiles", render_stats.drawn_tiles_count),
-            character_cache,
-            context
-                .transform
-                .trans(0.0, self.camera.height() - 2.5 * font_size)
-                .zoom(0.5),
-            graphics,
-        ) {
-            bail!("Failed to draw text: {e:?}");
-        }
-
-        let track_stats = &render_stats.track_stats;
-        if let Err(e) = graphics::text(
-            [0.0, 0.0, 0.0, 1.0],
-            Self::FONT_SIZE * 2,
-            &format!(
-                "Deduped {} / {} / {} points",
-                track_stats.visible_points, track_stats.deduped_points, track_stats.total_points
-            ),
-            character_cache,
-            context
-                .transform
-                .trans(0.0, self.camera.height() - 1.5 * font_size)
-                .zoom(0.5),
-            graphics,
-        ) {
-            bail!("Failed to draw text: {e:?}");
-        }
-
-        if let Err(e) = graphics::text(
-            [0.0, 0.0, 0.0, 1.0],
-            Self::FONT_SIZE * 2,
-            &format!(
-                "Drawn {} / {} segments",
-                render_stats.drawn_segment_count, render_stats.segment_count
-            ),
-            character_cache,
-            context
-                .transform
-                .trans(0.0, self.camera.height() - 0.5 * font_size)
-                .zoom(0.5),
-            graphics,
-        ) {
-            bail!("Failed to draw text: {e:?}");
+        let black = Color([0.0, 0.0, 0.0, 1.0]);
+        let colored_lines = lines
+            .iter()
+            .map(|line| (line.as_str(), black))
+            .chain(legend.iter().map(|(name, color)| (name.as_str(), *color)));
+        for (i, (line, color)) in colored_lines.enumerate() {
+            // Render at twice the font size but with 0.5 zoom for Retina displays. See https://github.com/PistonDevelopers/piston/issues/1240#issuecomment-569318143.
+            if let Err(e) = graphics::text(
+                color.0,
+                Self::FONT_SIZE * 2,
+                line,
+                character_cache,
+                context
+                    .transform
+                    .trans(
+                        0.0,
+                        self.camera.height() - height + (i as f64 + 1.0) * font_size,
+                    )
+                    .zoom(0.5),
+                graphics,
+            ) {
+                bail!("Failed to draw text: {e:?}");
+            }
         }
 
         Ok(())